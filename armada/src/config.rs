@@ -4,9 +4,10 @@ use std::fs::read_to_string;
 use toml::value::Value;
 
 pub fn get_toml_config(toml_path: String) -> Vec<String> {
-    let toml_contents = read_to_string(&toml_path).expect(&format!("failed to read toml file at {}", &toml_path));
+    let toml_contents =
+        read_to_string(&toml_path).unwrap_or_else(|_| panic!("failed to read toml file at {}", &toml_path));
     let parsed: HashMap<String, Value> =
-        toml::from_str(&toml_contents).expect(&format!("failed to parse toml file at {}", &toml_path));
+        toml::from_str(&toml_contents).unwrap_or_else(|_| panic!("failed to parse toml file at {}", &toml_path));
     let mut clap_args: Vec<String> = vec!["armada".to_string()];
     for (key, val) in &parsed {
         clap_args.append(&mut get_flag(key, val));
@@ -27,7 +28,7 @@ fn get_flag(key: &String, val: &Value) -> Vec<String> {
     arg
 }
 
-fn unpack_array_args(arr: &Vec<Value>) -> String {
+fn unpack_array_args(arr: &[Value]) -> String {
     let mut arg = "".to_string();
     for (i, v) in arr.iter().enumerate() {
         arg.push_str(&match v {
@@ -36,7 +37,7 @@ fn unpack_array_args(arr: &Vec<Value>) -> String {
             _ => panic!("Incorrect type found in TOML, array values must be Strings or Integers"),
         });
         if i != arr.len() - 1 {
-            arg.push_str(",")
+            arg.push(',')
         }
     }
     arg
@@ -0,0 +1,52 @@
+use armada_lib::utils::{get_default_ipv4_interface, get_default_ipv6_interface, get_interface_mtu, get_interfaces};
+
+/// Prints every interface armada can see with its flags, MTU, MAC and addresses, and where the default routes lead
+pub(crate) fn print_interfaces() {
+    let ipv4_default_route = get_default_ipv4_interface().unwrap_or_else(|e| {
+        eprintln!("Unable to find the IPv4 default route ({:#}).", e);
+        None
+    });
+    let ipv6_default_route = get_default_ipv6_interface().unwrap_or_else(|e| {
+        eprintln!("Unable to find the IPv6 default route ({:#}).", e);
+        None
+    });
+
+    for interface in get_interfaces() {
+        let flags: Vec<_> = [
+            (interface.is_up(), "UP"),
+            (interface.is_loopback(), "LOOPBACK"),
+            (interface.is_broadcast(), "BROADCAST"),
+            (interface.is_point_to_point(), "POINTOPOINT"),
+            (interface.is_multicast(), "MULTICAST"),
+        ]
+            .into_iter()
+            .filter_map(|(is_set, flag)| is_set.then_some(flag))
+            .collect();
+
+        let mut summary = format!("{} (index {}) <{}>", interface.name, interface.index, flags.join(","));
+
+        if let Some(mtu) = get_interface_mtu(interface.index) {
+            summary.push_str(&format!(" mtu {}", mtu));
+        }
+
+        if let Some(mac) = interface.mac {
+            summary.push_str(&format!(" mac {}", mac));
+        }
+
+        println!("{}", summary);
+
+        for network in &interface.ips {
+            println!("    {}", network);
+        }
+
+        for (family, default_route) in [("IPv4", &ipv4_default_route), ("IPv6", &ipv6_default_route)] {
+            match default_route {
+                Some(default_route) if default_route.index == interface.index => match default_route.gateway {
+                    Some(gateway) => println!("    {} default route via {}", family, gateway),
+                    None => println!("    {} default route", family),
+                },
+                _ => {}
+            }
+        }
+    }
+}
@@ -1,4 +1,5 @@
 mod args;
+mod interfaces;
 mod ranges;
 mod run_variants;
 mod config;
@@ -7,58 +8,211 @@ use std::net::{
     IpAddr,
     Ipv4Addr,
     Ipv6Addr,
+    SocketAddr,
 };
 
-use armada_lib::Armada;
+use std::collections::HashMap;
+use std::sync::OnceLock;
 
-use crate::args::ArmadaConfig;
+use armada_lib::{Armada, HostIterator, IpHeader, PortIterator, PortState, ScanOptions, ScanType, SourcePorts};
+
+use crate::args::{ArmadaCommand, ArmadaConfig, Backend};
 
 #[tokio::main]
 async fn main() {
+    let armada_config = match args::get_armada_command() {
+        ArmadaCommand::Scan(armada_config) => armada_config,
+        ArmadaCommand::ListInterfaces => {
+            interfaces::print_interfaces();
+
+            return;
+        }
+    };
+
     let ArmadaConfig {
         targets,
         ports,
+        scan_options,
         quiet_mode,
-        rate_limit,
-        listening_port,
-        retries,
-        timeout,
+        source_ports,
+        backend,
+        interface,
+        receive_buffer_size,
+        send_buffer_size,
+        benchmark,
+        connect_concurrency,
         source_ips,
         stream_results
-    } = args::get_armada_config();
+    } = *armada_config;
+
+    // layer-2 frames always leave through a single interface, so they're sent from its addresses
+    let route_per_target =
+        cfg!(target_os = "linux") && !(matches!(backend, Backend::Layer2 | Backend::Mmap) || benchmark);
+    let (source_ipv4, source_ipv6) =
+        split_and_enforce_source_ips(source_ips, interface.as_deref(), route_per_target).await;
+    let scan_options = scan_options.with_source_ipv4_addrs(source_ipv4).with_source_ipv6_addrs(source_ipv6);
 
-    let armada = Armada::new(listening_port);
+    let socket_options = SocketOptions { source_ports, interface, receive_buffer_size, send_buffer_size };
 
-    let (source_ipv4, source_ipv6) = split_and_enforce_source_ips(source_ips).await;
+    if benchmark {
+        run_benchmarks(&socket_options, targets, ports, scan_options).await;
 
-    let mut syn_scan_results = if quiet_mode {
+        return;
+    }
+
+    let armada = create_armada(backend, &socket_options, &scan_options, connect_concurrency, quiet_mode);
+
+    let mut scan_results = if quiet_mode {
         use run_variants::QuietArmada;
 
-        armada
-            .run_quiet(targets, ports, source_ipv4, source_ipv6, retries, timeout, rate_limit, stream_results)
-            .await
+        armada.run_quiet(targets, ports, scan_options, stream_results).await
     } else {
         use run_variants::ProgressArmada;
 
-        armada
-            .run_with_stats(targets, ports, source_ipv4, source_ipv6, retries, timeout, rate_limit, stream_results)
-            .await
+        armada.run_with_stats(targets, ports, scan_options, stream_results).await
     };
 
     if !stream_results {
-        syn_scan_results.sort();
+        scan_results.sort();
 
-        syn_scan_results.into_iter().for_each(|remote| {
-            println!("{}:{}", remote.ip(), remote.port());
+        scan_results.iter().for_each(|(remote, port_state)| {
+            println!("{}", format_port_result(remote, port_state));
         });
     }
 }
 
-async fn split_and_enforce_source_ips(source_ips: Option<Vec<IpAddr>>) -> (Vec<Ipv4Addr>, Vec<Ipv6Addr>) {
-    // we need to try to
-    let source_ips = match source_ips {
-        Some(source_ips) => source_ips,
-        _ => armada_lib::utils::get_default_ips()
+/// Open ports are printed as `ip:port` while every other state is annotated, e.g. `ip:port filtered`.
+/// Link-local IPv6 addresses keep their zone and are bracketed so the port can be told apart, e.g. `[fe80::1%eth0]:22`
+pub(crate) fn format_port_result(remote: &SocketAddr, port_state: &PortState) -> String {
+    let host = match remote {
+        SocketAddr::V6(remote) if remote.scope_id() != 0 => format!("[{}%{}]", remote.ip(), zone_name(remote.scope_id())),
+        remote => remote.ip().to_string(),
+    };
+
+    match port_state {
+        PortState::Open => format!("{}:{}", host, remote.port()),
+        port_state => format!("{}:{} {}", host, remote.port(), port_state),
+    }
+}
+
+/// The name of the interface a scope id stands for, or the id itself if there's no such interface.
+/// Interfaces are only looked up once, the first time a zoned result is printed
+fn zone_name(scope_id: u32) -> String {
+    static ZONE_NAMES: OnceLock<HashMap<u32, String>> = OnceLock::new();
+
+    let zone_names = ZONE_NAMES.get_or_init(|| {
+        armada_lib::utils::get_interfaces()
+            .into_iter()
+            .map(|interface| (interface.index, interface.name))
+            .collect()
+    });
+
+    zone_names.get(&scope_id).cloned().unwrap_or_else(|| scope_id.to_string())
+}
+
+/// How the sockets of raw socket, layer-2 and PACKET_MMAP scanners are set up
+struct SocketOptions {
+    source_ports: SourcePorts,
+    interface: Option<String>,
+    receive_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+}
+
+/// Creates a scanner for the chosen backend. If raw sockets are unavailable (e.g. no CAP_NET_RAW), SYN scans of
+/// armada's own SYNs fall back to a connect scan. Layer-2, layer-3 and PACKET_MMAP scans were asked for explicitly and
+/// so never fall back.
+fn create_armada(
+    backend: Backend,
+    socket_options: &SocketOptions,
+    scan_options: &ScanOptions,
+    connect_concurrency: usize,
+    quiet_mode: bool,
+) -> Armada {
+    let SocketOptions { source_ports, interface, receive_buffer_size, send_buffer_size } = socket_options;
+    let (source_ports, interface) = (*source_ports, interface.as_deref());
+
+    match backend {
+        Backend::Connect(proxies) => Armada::new_connect(connect_concurrency, proxies)
+            .unwrap_or_else(|e| panic!("Unable to start the connect scanner ({:#}).", e)),
+        Backend::Layer2 => Armada::new_l2(source_ports, interface)
+            .unwrap_or_else(|e| panic!("Unable to open a layer-2 channel ({:#}).", e)),
+        Backend::Layer3 => Armada::new_l3(source_ports, interface, *receive_buffer_size, *send_buffer_size)
+            .unwrap_or_else(|e| panic!("Unable to open header-including raw sockets ({:#}).", e)),
+        Backend::Mmap => Armada::new_mmap(source_ports, interface)
+            .unwrap_or_else(|e| panic!("Unable to set up the PACKET_MMAP rings ({:#}).", e)),
+        Backend::Raw => match Armada::new(source_ports, interface, *receive_buffer_size, *send_buffer_size) {
+            Ok(armada) => armada,
+            Err(e) if scan_options.scan_type() == ScanType::Syn && scan_options.syn_profile().is_default() => {
+                if !quiet_mode {
+                    eprintln!("Unable to open raw sockets ({:#}). Falling back to a TCP connect scan.", e);
+                }
+
+                Armada::new_connect(connect_concurrency, vec![])
+                    .unwrap_or_else(|e| panic!("Unable to start the connect scanner ({:#}).", e))
+            }
+            Err(e) => panic!("Unable to open raw sockets ({:#}). Only SYN scans without a SYN profile can fall back to a TCP connect scan.", e),
+        },
+    }
+}
+
+/// Runs the same scan over the raw socket and PACKET_MMAP backends, one after the other, and prints how fast each sent
+async fn run_benchmarks(
+    socket_options: &SocketOptions,
+    targets: HostIterator,
+    ports: PortIterator,
+    scan_options: ScanOptions,
+) {
+    use run_variants::BenchmarkArmada;
+
+    let SocketOptions { source_ports, interface, receive_buffer_size, send_buffer_size } = socket_options;
+
+    // each backend is only set up for its own run so the other can't steal its replies
+    let create_raw = || {
+        Armada::new(*source_ports, interface.as_deref(), *receive_buffer_size, *send_buffer_size)
+            .unwrap_or_else(|e| panic!("Unable to open raw sockets ({:#}).", e))
+    };
+    let create_mmap = || {
+        Armada::new_mmap(*source_ports, interface.as_deref())
+            .unwrap_or_else(|e| panic!("Unable to set up the PACKET_MMAP rings ({:#}).", e))
+    };
+    let backends: [(&str, &dyn Fn() -> Armada); 2] = [
+        ("raw sockets", &create_raw),
+        ("PACKET_MMAP", &create_mmap),
+    ];
+
+    for (backend_name, create_backend) in backends {
+        let armada = create_backend();
+
+        let (packets_sent, sending_time) = armada
+            .run_benchmark(targets.clone(), ports.clone(), scan_options.clone().with_ip_header(IpHeader::new()))
+            .await;
+
+        let packets_per_second = packets_sent as f64 / sending_time.as_secs_f64().max(f64::EPSILON);
+
+        println!(
+            "{}: {} packets in {:.2}s ({:.0} pps)",
+            backend_name,
+            packets_sent,
+            sending_time.as_secs_f64(),
+            packets_per_second
+        );
+    }
+}
+
+/// Without any source ips supplied, uses the addresses of the interface we were told to scan out of. Failing that, leaves
+/// them empty when `route_per_target` so each target is scanned from the address its route calls for, and otherwise
+/// falls back to every address of the default route's interface
+async fn split_and_enforce_source_ips(
+    source_ips: Option<Vec<IpAddr>>,
+    interface: Option<&str>,
+    route_per_target: bool,
+) -> (Vec<Ipv4Addr>, Vec<Ipv6Addr>) {
+    let source_ips = match (source_ips, interface) {
+        (Some(source_ips), _) => source_ips,
+        (None, Some(interface)) => armada_lib::utils::get_interface_ips(interface)
+            .unwrap_or_else(|e| panic!("Unable to identify the source ip addresses of interface '{}' ({:#}).", interface, e)),
+        (None, None) if route_per_target => Vec::new(),
+        (None, None) => armada_lib::utils::get_default_ips()
             .await
             .expect("Unable to identify source ip addresses automatically. Please supply them via --source-ip."),
     };
@@ -83,3 +237,20 @@ async fn split_and_enforce_source_ips(source_ips: Option<Vec<IpAddr>>) -> (Vec<I
 
     (source_ipv4_addrs, source_ipv6_addrs)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddrV6;
+
+    #[test]
+    fn formats_port_results() {
+        let ipv4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 443);
+        // no interface has this index, so the zone is printed as the id itself
+        let link_local = SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 22, 0, 4_000_000_000));
+
+        assert_eq!(format_port_result(&ipv4, &PortState::Open), "192.0.2.1:443");
+        assert_eq!(format_port_result(&ipv4, &PortState::Filtered), "192.0.2.1:443 filtered");
+        assert_eq!(format_port_result(&link_local, &PortState::Open), "[fe80::1%4000000000]:22");
+    }
+}
@@ -1,16 +1,15 @@
+mod run_benchmark;
 mod run_quiet;
 mod run_with_stats;
 
-use std::net::{
-    Ipv4Addr,
-    Ipv6Addr,
-    SocketAddr,
-};
+use std::net::SocketAddr;
 use std::time::Duration;
 
 use armada_lib::{
     HostIterator,
     PortIterator,
+    PortState,
+    ScanOptions,
 };
 use async_trait::async_trait;
 
@@ -20,13 +19,9 @@ pub(crate) trait QuietArmada {
         &self,
         targets: HostIterator,
         ports: PortIterator,
-        source_ipv4_addrs: Vec<Ipv4Addr>,
-        source_ipv6_addrs: Vec<Ipv6Addr>,
-        retries: u8,
-        timeout: Duration,
-        rate_limit: Option<usize>,
+        scan_options: ScanOptions,
         stream_results: bool
-    ) -> Vec<SocketAddr>;
+    ) -> Vec<(SocketAddr, PortState)>;
 }
 
 #[async_trait]
@@ -35,11 +30,18 @@ pub(crate) trait ProgressArmada {
         &self,
         targets: HostIterator,
         ports: PortIterator,
-        source_ipv4_addrs: Vec<Ipv4Addr>,
-        source_ipv6_addrs: Vec<Ipv6Addr>,
-        retries: u8,
-        timeout: Duration,
-        rate_limit: Option<usize>,
+        scan_options: ScanOptions,
         stream_results: bool
-    ) -> Vec<SocketAddr>;
+    ) -> Vec<(SocketAddr, PortState)>;
+}
+
+#[async_trait]
+pub(crate) trait BenchmarkArmada {
+    /// Runs the scan without collecting results and returns how many probes were sent and how long sending them took
+    async fn run_benchmark(
+        &self,
+        targets: HostIterator,
+        ports: PortIterator,
+        scan_options: ScanOptions,
+    ) -> (u128, Duration);
 }
@@ -0,0 +1,34 @@
+use std::time::{Duration, Instant};
+
+use armada_lib::{Armada, ArmadaWorkMessage, HostIterator, PortIterator, ScanOptions};
+use async_trait::async_trait;
+
+use crate::run_variants::BenchmarkArmada;
+
+#[async_trait]
+impl BenchmarkArmada for Armada {
+    async fn run_benchmark(
+        &self,
+        targets: HostIterator,
+        ports: PortIterator,
+        scan_options: ScanOptions,
+    ) -> (u128, Duration) {
+        let mut reporting_handle = self.scan_with_handle(targets, ports, scan_options).unwrap();
+
+        let start = Instant::now();
+        let mut total_sent = 0;
+        // the scan keeps waiting on replies long after the last probe is out, so only time the sending
+        let mut last_send_seen = start;
+
+        while let Some(message) = reporting_handle.recv().await {
+            if let ArmadaWorkMessage::Stats { total_packets_sent, .. } = message {
+                if total_packets_sent > total_sent {
+                    total_sent = total_packets_sent;
+                    last_send_seen = Instant::now();
+                }
+            }
+        }
+
+        (total_sent, last_send_seen.duration_since(start))
+    }
+}
@@ -1,13 +1,9 @@
-use std::net::{
-    Ipv4Addr,
-    Ipv6Addr,
-    SocketAddr,
-};
-use std::time::Duration;
+use std::net::SocketAddr;
 
-use armada_lib::{Armada, HostIterator, PortIterator, ArmadaWorkMessage};
+use armada_lib::{Armada, HostIterator, PortIterator, ArmadaWorkMessage, PortState, ScanOptions};
 use async_trait::async_trait;
 
+use crate::format_port_result;
 use crate::run_variants::QuietArmada;
 
 #[async_trait]
@@ -16,46 +12,23 @@ impl QuietArmada for Armada {
         &self,
         targets: HostIterator,
         ports: PortIterator,
-        source_ipv4_addrs: Vec<Ipv4Addr>,
-        source_ipv6_addrs: Vec<Ipv6Addr>,
-        retries: u8,
-        timeout: Duration,
-        rate_limit: Option<usize>,
+        scan_options: ScanOptions,
         stream_results: bool
-    ) -> Vec<SocketAddr> {
+    ) -> Vec<(SocketAddr, PortState)> {
         if stream_results {
-            let mut reporting_handle = self.scan_with_handle(
-                targets,
-                ports,
-                source_ipv4_addrs,
-                source_ipv6_addrs,
-                retries,
-                timeout,
-                rate_limit,
-            ).unwrap();
+            let mut reporting_handle = self.scan_with_handle(targets, ports, scan_options).unwrap();
 
             while let Some(message) = reporting_handle.recv().await {
-                match message {
-                    ArmadaWorkMessage::Results(results) => {
-                        results.iter().for_each(|remote| {
-                            println!("{}:{}", remote.ip(), remote.port());
-                        });
-                    }
-                    _ => {}
+                if let ArmadaWorkMessage::Results(results) = message {
+                    results.iter().for_each(|(remote, port_state)| {
+                        println!("{}", format_port_result(remote, port_state));
+                    });
                 }
             }
 
             vec![]
         } else {
-            self.scan_collect(
-                targets,
-                ports,
-                source_ipv4_addrs,
-                source_ipv6_addrs,
-                retries,
-                timeout,
-                rate_limit,
-            )
+            self.scan_collect(targets, ports, scan_options)
                 .await
                 .unwrap()
         }
@@ -1,8 +1,4 @@
-use std::net::{
-    Ipv4Addr,
-    Ipv6Addr,
-    SocketAddr,
-};
+use std::net::SocketAddr;
 use std::time::Duration;
 
 use armada_lib::{
@@ -10,6 +6,9 @@ use armada_lib::{
     ArmadaWorkMessage,
     HostIterator,
     PortIterator,
+    PortState,
+    RateLimit,
+    ScanOptions,
 };
 use async_trait::async_trait;
 use indicatif::{
@@ -18,6 +17,7 @@ use indicatif::{
     ProgressStyle,
 };
 
+use crate::format_port_result;
 use crate::run_variants::ProgressArmada;
 
 const UPDATE_INTERVAL: Duration = Duration::from_millis(50);
@@ -28,13 +28,10 @@ impl ProgressArmada for Armada {
         &self,
         targets: HostIterator,
         ports: PortIterator,
-        source_ipv4_addrs: Vec<Ipv4Addr>,
-        source_ipv6_addrs: Vec<Ipv6Addr>,
-        retries: u8,
-        timeout: Duration,
-        rate_limit: Option<usize>,
+        scan_options: ScanOptions,
         stream_output: bool
-    ) -> Vec<SocketAddr> {
+    ) -> Vec<(SocketAddr, PortState)> {
+        let retries = scan_options.retries();
         let mut total_open_ports = Vec::new();
         let total_ports: u128 = targets.size() * ports.size() as u128;
         let _total_packets = total_ports * (1 + retries) as u128;
@@ -55,26 +52,18 @@ impl ProgressArmada for Armada {
 
         let total_scan_progress_bar = multi_pb.add(ProgressBar::new(total_ports as u64));
         total_scan_progress_bar.set_message("0");
-        total_scan_progress_bar.set_style(ProgressStyle::default_bar().template(get_progress_stylization(&rate_limit, retries)).expect("invalid template"));
+        total_scan_progress_bar.set_style(ProgressStyle::default_bar().template(get_progress_stylization(&scan_options.rate_limit(), retries)).expect("invalid template"));
         total_scan_progress_bar.enable_steady_tick(UPDATE_INTERVAL);
 
 
-        let mut reporting_handle = self.scan_with_handle(
-            targets,
-            ports,
-            source_ipv4_addrs,
-            source_ipv6_addrs,
-            retries,
-            timeout,
-            rate_limit,
-        ).unwrap();
+        let mut reporting_handle = self.scan_with_handle(targets, ports, scan_options).unwrap();
 
         while let Some(message) = reporting_handle.recv().await {
             match message {
                 ArmadaWorkMessage::Results(results) => {
                     if stream_output {
-                        results.iter().for_each(|remote| {
-                            println!("{}:{}", remote.ip(), remote.port());
+                        results.iter().for_each(|(remote, port_state)| {
+                            println!("{}", format_port_result(remote, port_state));
                         });
                     }
 
@@ -86,8 +75,24 @@ impl ProgressArmada for Armada {
                     total_processed_ports: _,
                     current_inflight_packets,
                     total_packets_sent,
+                    total_packets_dropped,
+                    total_packets_malformed,
+                    current_packets_per_second,
                 } => {
-                    inflight_progress_bar.set_message(format!("{}", current_inflight_packets));
+                    let mut message = format!("{} ({} pps", current_inflight_packets, current_packets_per_second);
+
+                    // drops mean replies were lost and malformed packets that something is mangling them, so make
+                    // either visible when they happen
+                    if total_packets_dropped > 0 {
+                        message.push_str(&format!(", replies dropped: {}", total_packets_dropped));
+                    }
+
+                    if total_packets_malformed > 0 {
+                        message.push_str(&format!(", malformed: {}", total_packets_malformed));
+                    }
+
+                    message.push(')');
+                    inflight_progress_bar.set_message(message);
                     total_scan_progress_bar.set_position((total_packets_sent / (1 + retries) as u128) as u64);
                 }
             }
@@ -101,7 +106,7 @@ impl ProgressArmada for Armada {
     }
 }
 
-fn get_progress_stylization(rate_limit: &Option<usize>, retries: u8) -> &'static str {
+fn get_progress_stylization(rate_limit: &Option<RateLimit>, retries: u8) -> &'static str {
     match (rate_limit, retries) {
         (None, 0) => "[ETA: {eta_precise:.dim}] {wide_bar:.blue} {pos:.dim}/{len:.dim} ports (Elapsed: {elapsed_precise:.dim})",
         _ => "[ETA: {eta_precise:.dim}] {wide_bar:.red} {pos:.dim}/{len:.dim} ports (Elapsed: {elapsed_precise:.dim})"
@@ -1,14 +1,20 @@
+use crate::utils::{get_interfaces, is_ipv6_link_local};
+use anyhow::{anyhow, bail};
 use cidr_utils::cidr::{IpCidr, IpCidrIpAddrIterator, Ipv4Cidr, Ipv6Cidr};
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use std::str::FromStr;
+use std::sync::Arc;
 
 const IPV4_BITMASK: u8 = 32;
 const IPV6_BITMASK: u8 = 128;
 
 #[derive(Debug)]
 pub struct HostIterator {
-    inner: Vec<IpCidr>,
+    // each range along with the scope id (interface index) of its zone, 0 for ranges without one. Shared between clones,
+    // as every port of a scan sweeps the hosts with a clone of its own
+    inner: Arc<Vec<(IpCidr, u32)>>,
     current_cidr_idx: Option<usize>,
-    current_cidr_iterator: Option<IpCidrIpAddrIterator>,
+    current_cidr_iterator: Option<(IpCidrIpAddrIterator, u32)>,
 }
 
 // we implement a slightly incorrect clone here intentionally
@@ -22,10 +28,14 @@ impl Clone for HostIterator {
     }
 }
 
+impl Default for HostIterator {
+    fn default() -> Self { Self::new() }
+}
+
 impl HostIterator {
     pub fn new() -> Self {
         Self {
-            inner: Vec::new(),
+            inner: Arc::new(Vec::new()),
             current_cidr_idx: None,
             current_cidr_iterator: None,
         }
@@ -35,22 +45,11 @@ impl HostIterator {
         use num_traits::ToPrimitive;
 
         self.inner.iter()
-            .fold(0, |acc, cidr| acc + cidr.size().to_u128().expect("Cidr range is too large to report back a size. Crashing here is in your best interest."))
+            .fold(0, |acc, (cidr, _)| acc + cidr.size().to_u128().expect("Cidr range is too large to report back a size. Crashing here is in your best interest."))
     }
 
     pub fn add_ip(mut self, addr: IpAddr) -> Self {
-        let ip_cidr = match addr {
-            IpAddr::V4(ipv4_addr) => IpCidr::V4(
-                Ipv4Cidr::from_prefix_and_bits(ipv4_addr, IPV4_BITMASK)
-                    .expect("Failed to convert to IPv4 CIDR"),
-            ),
-            IpAddr::V6(ipv6_addr) => IpCidr::V6(
-                Ipv6Cidr::from_prefix_and_bits(ipv6_addr, IPV6_BITMASK)
-                    .expect("Failed to convert to IPv6 CIDR"),
-            ),
-        };
-
-        self.inner.push(ip_cidr);
+        Arc::make_mut(&mut self.inner).push((single_host_cidr(addr), 0));
 
         self
     }
@@ -64,10 +63,43 @@ impl HostIterator {
     }
 
     pub fn add_cidr(mut self, range: IpCidr) -> Self {
-        self.inner.push(range);
+        Arc::make_mut(&mut self.inner).push((range, 0));
         self
     }
 
+    /// Adds a target given as an address or a CIDR range, e.g. `10.0.0.0/24`. Link-local IPv6 targets may name the
+    /// interface they're reached over with a zone identifier, either the interface's name or index, e.g. `fe80::1%eth0`
+    /// or `fe80::/120%2`. Without one, the kernel has no way to tell which link they're on.
+    pub fn add_target(mut self, target: &str) -> anyhow::Result<Self> {
+        let (range, zone) = match target.split_once('%') {
+            Some((range, zone)) => (range, Some(zone)),
+            None => (target, None),
+        };
+
+        let ip_cidr = match IpAddr::from_str(range) {
+            Ok(addr) => single_host_cidr(addr),
+            Err(_) => IpCidr::from_str(range)?,
+        };
+
+        let scope_id = match zone {
+            Some(zone) => {
+                if !is_ipv6_link_local(&ip_cidr.first_as_ip_addr()) || !is_ipv6_link_local(&ip_cidr.last_as_ip_addr()) {
+                    bail!("Only link-local IPv6 targets (fe80::/10) can have a zone identifier.");
+                }
+
+                scope_id_of(zone)?
+            }
+            None => 0,
+        };
+
+        Arc::make_mut(&mut self.inner).push((ip_cidr, scope_id));
+
+        Ok(self)
+    }
+
+    /// Whether any of the targets were given a zone identifier
+    pub fn has_zones(&self) -> bool { self.inner.iter().any(|(_, scope_id)| *scope_id != 0) }
+
     pub fn reset(&mut self) {
         self.current_cidr_idx = None;
         self.current_cidr_iterator = None;
@@ -84,15 +116,16 @@ impl HostIterator {
         self.current_cidr_iterator = None;
 
         if let Some(idx) = self.current_cidr_idx {
-            if let Some(ip_cidr) = self.inner.get(idx) {
-                self.current_cidr_iterator = Some(ip_cidr.iter());
+            if let Some((ip_cidr, scope_id)) = self.inner.get(idx) {
+                self.current_cidr_iterator = Some((ip_cidr.iter(), *scope_id));
             }
         }
     }
 }
 
 impl Iterator for HostIterator {
-    type Item = IpAddr;
+    /// Each target address along with the scope id of its zone, 0 if it has none
+    type Item = (IpAddr, u32);
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
@@ -101,10 +134,43 @@ impl Iterator for HostIterator {
                 _ => {}
             }
 
-            match self.current_cidr_iterator.as_mut().map(|iter| iter.next()) {
-                Some(Some(next_ip)) => break Some(next_ip),
-                Some(None) | None => self.rotate_iterator(),
+            match self.current_cidr_iterator.as_mut().map(|(iter, scope_id)| (iter.next(), *scope_id)) {
+                Some((Some(next_ip), scope_id)) => break Some((next_ip, scope_id)),
+                Some((None, _)) | None => self.rotate_iterator(),
             }
         }
     }
 }
+
+/// The range holding nothing but `addr`
+fn single_host_cidr(addr: IpAddr) -> IpCidr {
+    match addr {
+        IpAddr::V4(ipv4_addr) => IpCidr::V4(
+            Ipv4Cidr::from_prefix_and_bits(ipv4_addr, IPV4_BITMASK)
+                .expect("Failed to convert to IPv4 CIDR"),
+        ),
+        IpAddr::V6(ipv6_addr) => IpCidr::V6(
+            Ipv6Cidr::from_prefix_and_bits(ipv6_addr, IPV6_BITMASK)
+                .expect("Failed to convert to IPv6 CIDR"),
+        ),
+    }
+}
+
+/// The address of a target's port, keeping the zone of link-local IPv6 targets
+pub(crate) fn remote_addr(ip: IpAddr, scope_id: u32, port: u16) -> SocketAddr {
+    match ip {
+        IpAddr::V6(ipv6_addr) if scope_id != 0 => SocketAddr::V6(SocketAddrV6::new(ipv6_addr, port, 0, scope_id)),
+        ip => SocketAddr::new(ip, port),
+    }
+}
+
+/// The scope id a zone identifier stands for, which is the index of the interface it names by name or index
+fn scope_id_of(zone: &str) -> anyhow::Result<u32> {
+    let index = u32::from_str(zone).ok();
+
+    get_interfaces()
+        .into_iter()
+        .find(|interface| interface.name == zone || Some(interface.index) == index)
+        .map(|interface| interface.index)
+        .ok_or_else(|| anyhow!("Unable to find interface '{}' for the zone identifier.", zone))
+}
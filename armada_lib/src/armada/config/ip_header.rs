@@ -0,0 +1,58 @@
+/// The IP header fields of a scan's probes. Only transports that build the IP header themselves can set them: layer-3
+/// (see `Armada::new_l3`), layer-2 and PACKET_MMAP scanners. Whatever isn't set is left as those transports would have
+/// it: a TTL and hop limit of 64, a TOS of 0, IDs counting up from a random start and the don't fragment bit set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IpHeader {
+    ttl: Option<u8>,
+    hop_limit: Option<u8>,
+    tos: Option<u8>,
+    identification: Option<u16>,
+    dont_fragment: Option<bool>,
+}
+
+impl IpHeader {
+    pub fn new() -> Self { Self::default() }
+
+    /// Sets the TTL of IPv4 probes
+    pub fn with_ttl(mut self, ttl: u8) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the hop limit of IPv6 probes
+    pub fn with_hop_limit(mut self, hop_limit: u8) -> Self {
+        self.hop_limit = Some(hop_limit);
+        self
+    }
+
+    /// Sets the type of service byte (DSCP and ECN) of IPv4 probes, and the traffic class of IPv6 ones
+    pub fn with_tos(mut self, tos: u8) -> Self {
+        self.tos = Some(tos);
+        self
+    }
+
+    /// Sends every IPv4 probe with the same identification
+    pub fn with_identification(mut self, identification: u16) -> Self {
+        self.identification = Some(identification);
+        self
+    }
+
+    /// Sets or clears the don't fragment bit of IPv4 probes
+    pub fn with_dont_fragment(mut self, dont_fragment: bool) -> Self {
+        self.dont_fragment = Some(dont_fragment);
+        self
+    }
+
+    pub fn ttl(&self) -> Option<u8> { self.ttl }
+
+    pub fn hop_limit(&self) -> Option<u8> { self.hop_limit }
+
+    pub fn tos(&self) -> Option<u8> { self.tos }
+
+    pub fn identification(&self) -> Option<u16> { self.identification }
+
+    pub fn dont_fragment(&self) -> Option<bool> { self.dont_fragment }
+
+    /// Whether any field was set, i.e. the probes can't just go out with whatever header the kernel gives them
+    pub fn is_set(&self) -> bool { *self != Self::default() }
+}
@@ -1,2 +1,9 @@
 pub mod host;
+pub mod ip_header;
+pub mod network_limits;
 pub mod port;
+pub mod rate_limit;
+pub mod scan_options;
+pub mod scan_type;
+pub mod source_ports;
+pub mod syn_profile;
@@ -0,0 +1,104 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// unless told otherwise, hosts share their network's limits with the rest of their /24 or /48
+const DEFAULT_IPV4_PREFIX_LEN: u8 = 24;
+const DEFAULT_IPV6_PREFIX_LEN: u8 = 48;
+
+/// Caps on how hard a scan hits any single host, and any single network of hosts, on top of its overall rate limit.
+/// Networks are IPv4 /24s and IPv6 /48s unless configured otherwise. Nothing is capped by default.
+#[derive(Clone, Copy, Debug)]
+pub struct NetworkLimits {
+    host_packets_per_second: Option<usize>,
+    host_inflight: Option<usize>,
+    network_packets_per_second: Option<usize>,
+    network_inflight: Option<usize>,
+    ipv4_prefix_len: u8,
+    ipv6_prefix_len: u8,
+}
+
+impl Default for NetworkLimits {
+    fn default() -> Self {
+        Self {
+            host_packets_per_second: None,
+            host_inflight: None,
+            network_packets_per_second: None,
+            network_inflight: None,
+            ipv4_prefix_len: DEFAULT_IPV4_PREFIX_LEN,
+            ipv6_prefix_len: DEFAULT_IPV6_PREFIX_LEN,
+        }
+    }
+}
+
+impl NetworkLimits {
+    pub fn new() -> Self { Self::default() }
+
+    /// Sends no more than `packets_per_second` to any one host
+    pub fn with_host_rate(mut self, packets_per_second: usize) -> Self {
+        self.host_packets_per_second = Some(packets_per_second.max(1));
+        self
+    }
+
+    /// Has no more than `probes` in flight to any one host at a time
+    pub fn with_host_inflight(mut self, probes: usize) -> Self {
+        self.host_inflight = Some(probes.max(1));
+        self
+    }
+
+    /// Sends no more than `packets_per_second` to any one network
+    pub fn with_network_rate(mut self, packets_per_second: usize) -> Self {
+        self.network_packets_per_second = Some(packets_per_second.max(1));
+        self
+    }
+
+    /// Has no more than `probes` in flight to any one network at a time
+    pub fn with_network_inflight(mut self, probes: usize) -> Self {
+        self.network_inflight = Some(probes.max(1));
+        self
+    }
+
+    /// Sets the prefix lengths that IPv4 and IPv6 hosts are grouped into networks by
+    pub fn with_prefix_lengths(mut self, ipv4_prefix_len: u8, ipv6_prefix_len: u8) -> Self {
+        self.ipv4_prefix_len = ipv4_prefix_len.min(32);
+        self.ipv6_prefix_len = ipv6_prefix_len.min(128);
+        self
+    }
+
+    pub fn host_packets_per_second(&self) -> Option<usize> { self.host_packets_per_second }
+
+    pub fn host_inflight(&self) -> Option<usize> { self.host_inflight }
+
+    pub fn network_packets_per_second(&self) -> Option<usize> { self.network_packets_per_second }
+
+    pub fn network_inflight(&self) -> Option<usize> { self.network_inflight }
+
+    /// Whether any host or network is capped at all
+    pub fn is_unlimited(&self) -> bool { !self.limits_hosts() && !self.limits_networks() }
+
+    pub(crate) fn limits_hosts(&self) -> bool { self.host_packets_per_second.is_some() || self.host_inflight.is_some() }
+
+    pub(crate) fn limits_networks(&self) -> bool {
+        self.network_packets_per_second.is_some() || self.network_inflight.is_some()
+    }
+
+    /// The network `ip` belongs to, i.e. its prefix
+    pub(crate) fn network_of(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(_) => prefix_of(ip, self.ipv4_prefix_len),
+            IpAddr::V6(_) => prefix_of(ip, self.ipv6_prefix_len),
+        }
+    }
+}
+
+/// The first `prefix_len` bits of `ip`, with the rest zeroed
+pub(crate) fn prefix_of(ip: IpAddr, prefix_len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ipv4) => {
+            let mask = u32::MAX.checked_shl(32u32.saturating_sub(prefix_len as u32)).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ipv4) & mask))
+        }
+        IpAddr::V6(ipv6) => {
+            let mask = u128::MAX.checked_shl(128u32.saturating_sub(prefix_len as u32)).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ipv6) & mask))
+        }
+    }
+}
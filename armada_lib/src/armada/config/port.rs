@@ -4,6 +4,10 @@ pub struct PortIterator {
     idx: Option<usize>,
 }
 
+impl Default for PortIterator {
+    fn default() -> Self { Self::new() }
+}
+
 impl PortIterator {
     pub fn new() -> Self {
         Self {
@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+// unless told otherwise, a scan may send this far ahead of its rate after a lull
+const DEFAULT_BURST_DURATION: Duration = Duration::from_micros(200);
+// but always at least this many packets, so slow scans can still batch their sends
+const MIN_DEFAULT_BURST: usize = 32;
+
+/// How fast a scan sends. Packets are paced evenly rather than let out in bursts, apart from the `burst` that can build
+/// up while there's nothing to send. An adaptive rate limit is only a ceiling, below which the scan finds out for itself
+/// how fast it can send without losing replies.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    packets_per_second: usize,
+    burst: Option<usize>,
+    adaptive: bool,
+}
+
+impl RateLimit {
+    pub fn new(packets_per_second: usize) -> Self {
+        Self {
+            packets_per_second: packets_per_second.max(1),
+            burst: None,
+            adaptive: false,
+        }
+    }
+
+    /// Lets up to `burst` packets out back to back. Defaults to 200µs worth of packets, and at least 32.
+    pub fn with_burst(mut self, burst: usize) -> Self {
+        self.burst = Some(burst.max(1));
+        self
+    }
+
+    /// Starts the scan out slowly and speeds it up towards the rate limit for as long as replies aren't being lost,
+    /// backing off when they are
+    pub fn adaptive(mut self) -> Self {
+        self.adaptive = true;
+        self
+    }
+
+    pub fn packets_per_second(&self) -> usize { self.packets_per_second }
+
+    pub fn burst(&self) -> usize { self.burst_at(self.packets_per_second) }
+
+    pub fn is_adaptive(&self) -> bool { self.adaptive }
+
+    /// The burst when sending at `packets_per_second`, which an adaptive rate limit may be anywhere below its ceiling
+    pub(crate) fn burst_at(&self, packets_per_second: usize) -> usize {
+        self.burst.unwrap_or_else(|| {
+            let burst_packets = packets_per_second as f64 * DEFAULT_BURST_DURATION.as_secs_f64();
+
+            (burst_packets as usize).max(MIN_DEFAULT_BURST)
+        })
+    }
+}
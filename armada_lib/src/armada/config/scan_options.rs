@@ -0,0 +1,113 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use crate::armada::config::ip_header::IpHeader;
+use crate::armada::config::network_limits::NetworkLimits;
+use crate::armada::config::rate_limit::RateLimit;
+use crate::armada::config::scan_type::ScanType;
+use crate::armada::config::syn_profile::SynProfile;
+
+const DEFAULT_PORT_RETRIES: u8 = 2;
+const DEFAULT_PORT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How a scan goes about probing its targets. By default that's a SYN scan of armada's own SYNs, each port retried
+/// twice after a second without a reply, as fast as the scanner can send and from the addresses routing picks.
+#[derive(Clone, Debug)]
+pub struct ScanOptions {
+    scan_type: ScanType,
+    source_ipv4_addrs: Vec<Ipv4Addr>,
+    source_ipv6_addrs: Vec<Ipv6Addr>,
+    port_retries: u8,
+    port_timeout: Duration,
+    rate_limit: Option<RateLimit>,
+    network_limits: NetworkLimits,
+    ip_header: IpHeader,
+    syn_profile: SynProfile,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            scan_type: ScanType::default(),
+            source_ipv4_addrs: Vec::new(),
+            source_ipv6_addrs: Vec::new(),
+            port_retries: DEFAULT_PORT_RETRIES,
+            port_timeout: DEFAULT_PORT_TIMEOUT,
+            rate_limit: None,
+            network_limits: NetworkLimits::default(),
+            ip_header: IpHeader::default(),
+            syn_profile: SynProfile::default(),
+        }
+    }
+}
+
+impl ScanOptions {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn with_scan_type(mut self, scan_type: ScanType) -> Self {
+        self.scan_type = scan_type;
+        self
+    }
+
+    /// Scans IPv4 targets from these addresses. Without any, each one is scanned from the address its route calls for
+    pub fn with_source_ipv4_addrs(mut self, source_ipv4_addrs: Vec<Ipv4Addr>) -> Self {
+        self.source_ipv4_addrs = source_ipv4_addrs;
+        self
+    }
+
+    /// Scans IPv6 targets from these addresses. Without any, each one is scanned from the address its route calls for
+    pub fn with_source_ipv6_addrs(mut self, source_ipv6_addrs: Vec<Ipv6Addr>) -> Self {
+        self.source_ipv6_addrs = source_ipv6_addrs;
+        self
+    }
+
+    /// How many more times a port that didn't answer is probed before it's given up on
+    pub fn with_retries(mut self, port_retries: u8) -> Self {
+        self.port_retries = port_retries;
+        self
+    }
+
+    /// How long to wait on a reply before the first ones come in and the timeout adapts to the target's network
+    pub fn with_timeout(mut self, port_timeout: Duration) -> Self {
+        self.port_timeout = port_timeout;
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    pub fn with_network_limits(mut self, network_limits: NetworkLimits) -> Self {
+        self.network_limits = network_limits;
+        self
+    }
+
+    pub fn with_ip_header(mut self, ip_header: IpHeader) -> Self {
+        self.ip_header = ip_header;
+        self
+    }
+
+    pub fn with_syn_profile(mut self, syn_profile: SynProfile) -> Self {
+        self.syn_profile = syn_profile;
+        self
+    }
+
+    pub fn scan_type(&self) -> ScanType { self.scan_type }
+
+    pub fn source_ipv4_addrs(&self) -> &[Ipv4Addr] { &self.source_ipv4_addrs }
+
+    pub fn source_ipv6_addrs(&self) -> &[Ipv6Addr] { &self.source_ipv6_addrs }
+
+    pub fn retries(&self) -> u8 { self.port_retries }
+
+    pub fn timeout(&self) -> Duration { self.port_timeout }
+
+    pub fn rate_limit(&self) -> Option<RateLimit> { self.rate_limit }
+
+    pub fn network_limits(&self) -> NetworkLimits { self.network_limits }
+
+    pub fn ip_header(&self) -> IpHeader { self.ip_header }
+
+    pub fn syn_profile(&self) -> &SynProfile { &self.syn_profile }
+}
@@ -0,0 +1,197 @@
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::tcp::TcpFlags::{ACK, FIN, PSH, RST, SYN, URG};
+use pnet::packet::tcp::TcpPacket;
+
+use crate::armada::packet::{parse_sctp_packet, SCTP_CHUNK_ABORT, SCTP_CHUNK_INIT_ACK};
+use crate::armada::work::PortState;
+
+/// The kind of probe armada sends and how the replies to it are interpreted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ScanType {
+    /// Half-open scan. SYN-ACK means open, RST means closed.
+    #[default]
+    Syn,
+    /// Bare ACK probes. Any RST means the port is unfiltered, silence means filtered.
+    Ack,
+    /// FIN probes. RST means closed, silence means open or filtered.
+    Fin,
+    /// Probes with no flags set. RST means closed, silence means open or filtered.
+    Null,
+    /// FIN, PSH and URG probes. RST means closed, silence means open or filtered.
+    Xmas,
+    /// ACK probes where the window of the returned RST tells open (non-zero) from closed (zero).
+    Window,
+    /// SCTP INIT probes. INIT-ACK means open, ABORT means closed.
+    SctpInit,
+}
+
+impl ScanType {
+    /// The transport protocol the probes of this scan type are sent over.
+    pub(crate) fn transport_protocol(&self) -> IpNextHeaderProtocol {
+        match self {
+            ScanType::SctpInit => IpNextHeaderProtocols::Sctp,
+            _ => IpNextHeaderProtocols::Tcp,
+        }
+    }
+
+    /// The TCP flags set on every probe sent for this scan type.
+    pub(crate) fn probe_flags(&self) -> u16 {
+        match self {
+            ScanType::Syn => SYN,
+            ScanType::Ack | ScanType::Window => ACK,
+            ScanType::Fin => FIN,
+            ScanType::Null | ScanType::SctpInit => 0,
+            ScanType::Xmas => FIN | PSH | URG,
+        }
+    }
+
+    /// Determines the state of a port from a transport layer segment sent in reply to one of our probes.
+    /// Returns `(source port, destination port, state)` or `None` if the reply doesn't tell us anything for this scan type.
+    pub(crate) fn interpret_response(&self, segment: &[u8]) -> Option<(u16, u16, PortState)> {
+        match self {
+            ScanType::SctpInit => {
+                let (source_port, destination_port, chunk_type) = parse_sctp_packet(segment)?;
+
+                let port_state = match chunk_type {
+                    SCTP_CHUNK_INIT_ACK => PortState::Open,
+                    SCTP_CHUNK_ABORT => PortState::Closed,
+                    _ => return None,
+                };
+
+                Some((source_port, destination_port, port_state))
+            }
+            _ => {
+                let packet = TcpPacket::new(segment)?;
+                let port_state = self.interpret_tcp_response(&packet)?;
+
+                Some((packet.get_source(), packet.get_destination(), port_state))
+            }
+        }
+    }
+
+    fn interpret_tcp_response(&self, packet: &TcpPacket) -> Option<PortState> {
+        let flags = packet.get_flags();
+        let rst_flag = flags & RST != 0;
+
+        match self {
+            ScanType::Syn if rst_flag => Some(PortState::Closed),
+            ScanType::Syn if flags & (SYN | ACK) == SYN | ACK => Some(PortState::Open),
+            ScanType::Ack if rst_flag => Some(PortState::Unfiltered),
+            ScanType::Fin | ScanType::Null | ScanType::Xmas if rst_flag => Some(PortState::Closed),
+            ScanType::Window if rst_flag && packet.get_window() > 0 => Some(PortState::Open),
+            ScanType::Window if rst_flag => Some(PortState::Closed),
+            _ => None,
+        }
+    }
+
+    /// The state we assume for a port that never answered any of our attempts.
+    pub(crate) fn timeout_state(&self) -> PortState {
+        match self {
+            ScanType::Fin | ScanType::Null | ScanType::Xmas => PortState::OpenFiltered,
+            ScanType::Syn | ScanType::Ack | ScanType::Window | ScanType::SctpInit => PortState::Filtered,
+        }
+    }
+
+    /// Whether ports in this state should be reported back as results.
+    /// SYN and SCTP INIT scans only care about open ports while the firewall auditing scans report everything that isn't closed.
+    pub(crate) fn reports(&self, state: PortState) -> bool {
+        match self {
+            ScanType::Syn | ScanType::SctpInit => state == PortState::Open,
+            _ => state != PortState::Closed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::tcp::MutableTcpPacket;
+
+    const SCAN_TYPES: [ScanType; 7] = [
+        ScanType::Syn,
+        ScanType::Ack,
+        ScanType::Fin,
+        ScanType::Null,
+        ScanType::Xmas,
+        ScanType::Window,
+        ScanType::SctpInit,
+    ];
+
+    fn tcp_reply(flags: u16, window: u16) -> Vec<u8> {
+        let mut buffer = vec![0u8; 20];
+        let mut packet = MutableTcpPacket::new(&mut buffer).unwrap();
+        packet.set_source(443);
+        packet.set_destination(50000);
+        packet.set_data_offset(5);
+        packet.set_flags(flags);
+        packet.set_window(window);
+
+        buffer
+    }
+
+    fn sctp_reply(chunk_type: u8) -> Vec<u8> {
+        let mut buffer = vec![0u8; 16];
+        buffer[.. 2].copy_from_slice(&443u16.to_be_bytes());
+        buffer[2 .. 4].copy_from_slice(&50000u16.to_be_bytes());
+        buffer[12] = chunk_type;
+
+        buffer
+    }
+
+    #[test]
+    fn probe_flags_match_the_scan_type() {
+        let flags = SCAN_TYPES.map(|scan_type| scan_type.probe_flags());
+
+        assert_eq!(flags, [SYN, ACK, FIN, 0, FIN | PSH | URG, ACK, 0]);
+    }
+
+    #[test]
+    fn replies_map_to_port_states() {
+        use PortState::*;
+
+        // (reply, state for each scan type in the order of SCAN_TYPES). Neither kind of reply makes sense to the other
+        let table: [(Vec<u8>, [Option<PortState>; 7]); 6] = [
+            (tcp_reply(SYN | ACK, 64240), [Some(Open), None, None, None, None, None, None]),
+            (
+                tcp_reply(RST, 0),
+                [Some(Closed), Some(Unfiltered), Some(Closed), Some(Closed), Some(Closed), Some(Closed), None],
+            ),
+            (
+                tcp_reply(RST | ACK, 512),
+                [Some(Closed), Some(Unfiltered), Some(Closed), Some(Closed), Some(Closed), Some(Open), None],
+            ),
+            (tcp_reply(ACK, 1024), [None; 7]),
+            (sctp_reply(SCTP_CHUNK_INIT_ACK), [None, None, None, None, None, None, Some(Open)]),
+            (sctp_reply(SCTP_CHUNK_ABORT), [None, None, None, None, None, None, Some(Closed)]),
+        ];
+
+        for (reply, states) in table {
+            for (scan_type, state) in SCAN_TYPES.iter().zip(states) {
+                let interpreted = scan_type.interpret_response(&reply);
+
+                assert_eq!(interpreted.map(|(_, _, state)| state), state, "{:?} reading {:?}", scan_type, reply);
+                assert!(interpreted.is_none_or(|(source, destination, _)| (source, destination) == (443, 50000)));
+            }
+        }
+    }
+
+    #[test]
+    fn silence_and_reporting_follow_the_scan_type() {
+        use PortState::*;
+
+        let timeout_states = SCAN_TYPES.map(|scan_type| scan_type.timeout_state());
+        assert_eq!(timeout_states, [Filtered, Filtered, OpenFiltered, OpenFiltered, OpenFiltered, Filtered, Filtered]);
+
+        for scan_type in SCAN_TYPES {
+            let reported: Vec<_> = [Open, Closed, Filtered, Unfiltered, OpenFiltered]
+                .into_iter()
+                .filter(|state| scan_type.reports(*state))
+                .collect();
+
+            match scan_type {
+                ScanType::Syn | ScanType::SctpInit => assert_eq!(reported, [Open]),
+                _ => assert_eq!(reported, [Open, Filtered, Unfiltered, OpenFiltered]),
+            }
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use crate::armada::local_sockets;
+use rand::Rng;
+
+// random pools are drawn from here, clear of the well known ports
+const RANDOM_POOL_RANGE: std::ops::Range<u16> = 50_000 .. 60_000;
+// how many random pools we look at for one no local socket is bound within before settling for the last
+const RANDOM_POOL_ATTEMPTS: usize = 16;
+
+/// The local ports probes are sent from, an inclusive range. Every probe leaves from a port picked at random out of the
+/// pool, so a scan can't be filtered by a single source port, and anything arriving on one of them is treated as a
+/// possible reply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourcePorts {
+    first: u16,
+    last: u16,
+}
+
+impl SourcePorts {
+    /// A pool of the ports from `first` through `last`
+    pub fn new(first: u16, last: u16) -> Self { Self { first: first.min(last), last: first.max(last) } }
+
+    /// A pool of just the one port, which every probe is sent from
+    pub fn single(port: u16) -> Self { Self::new(port, port) }
+
+    /// A pool of `count` consecutive ports starting somewhere in 50000-60000. Where we can tell, the pool is picked so
+    /// that no local socket is bound within it.
+    pub fn random(count: u16) -> Self {
+        let count = count.clamp(1, RANDOM_POOL_RANGE.len() as u16);
+        let mut rng = rand::thread_rng();
+        let mut pool = Self::single(RANDOM_POOL_RANGE.start);
+
+        for _ in 0 .. RANDOM_POOL_ATTEMPTS {
+            let first = rng.gen_range(RANDOM_POOL_RANGE.start ..= RANDOM_POOL_RANGE.end - count);
+            pool = Self::new(first, first + count - 1);
+
+            // if we can't tell which ports are bound, any pool is as good as the next
+            if local_sockets::bound_ports(&pool).map_or(true, |bound_ports| bound_ports.is_empty()) {
+                break;
+            }
+        }
+
+        pool
+    }
+
+    pub fn first(&self) -> u16 { self.first }
+
+    pub fn last(&self) -> u16 { self.last }
+
+    /// The number of ports in the pool
+    pub fn count(&self) -> usize { (self.last - self.first) as usize + 1 }
+
+    pub fn contains(&self, port: u16) -> bool { (self.first ..= self.last).contains(&port) }
+
+    /// Picks the port the next probe leaves from
+    pub(crate) fn pick(&self, rng: &mut impl Rng) -> u16 {
+        match self.first == self.last {
+            true => self.first,
+            false => rng.gen_range(self.first ..= self.last),
+        }
+    }
+}
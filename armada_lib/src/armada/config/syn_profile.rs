@@ -0,0 +1,146 @@
+use anyhow::bail;
+
+// a tcp header has room for 40 bytes of options
+const MAX_TCP_OPTIONS_LEN: usize = 40;
+
+/// A TCP option carried by SYN probes, in the order it's given
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SynOption {
+    /// End of option list (kind 0). Some stacks pad their options out with these
+    Eol,
+    /// No-operation (kind 1), used to align the options that follow
+    Nop,
+    /// Maximum segment size (kind 2)
+    Mss(u16),
+    /// Window scale (kind 3), as a shift count
+    WindowScale(u8),
+    /// SACK permitted (kind 4)
+    SackPermitted,
+    /// Timestamps (kind 8). The value is picked at random once per scan and the echo reply is 0, as in a fresh SYN
+    Timestamps,
+}
+
+impl SynOption {
+    /// How many bytes the option takes up in the header
+    pub fn size(&self) -> usize {
+        match self {
+            SynOption::Eol | SynOption::Nop => 1,
+            SynOption::SackPermitted => 2,
+            SynOption::WindowScale(_) => 3,
+            SynOption::Mss(_) => 4,
+            SynOption::Timestamps => 10,
+        }
+    }
+}
+
+/// The window and TCP options of a SYN scan's probes. Middleboxes and hosts can treat SYNs that don't look like
+/// they came from a real client differently, so besides armada's own (a window of 1024 and just an MSS of 1460), there
+/// are presets copying the SYNs of current Linux, Windows and macOS, and any of them can be adjusted from there.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SynProfile {
+    window: u16,
+    options: Vec<SynOption>,
+}
+
+impl Default for SynProfile {
+    fn default() -> Self { Self::armada() }
+}
+
+impl SynProfile {
+    pub fn new() -> Self { Self::default() }
+
+    /// The SYNs armada has always sent: a window of 1024 and an MSS of 1460, padded out to a 32 byte header
+    pub fn armada() -> Self {
+        let mut options = vec![SynOption::Mss(1460)];
+        options.resize(9, SynOption::Eol);
+
+        Self { window: 1024, options }
+    }
+
+    /// Linux's SYNs over Ethernet
+    pub fn linux() -> Self {
+        Self {
+            window: 64240,
+            options: vec![
+                SynOption::Mss(1460),
+                SynOption::SackPermitted,
+                SynOption::Timestamps,
+                SynOption::Nop,
+                SynOption::WindowScale(7),
+            ],
+        }
+    }
+
+    /// Windows 10 and 11's SYNs over Ethernet
+    pub fn windows() -> Self {
+        Self {
+            window: 64240,
+            options: vec![
+                SynOption::Mss(1460),
+                SynOption::Nop,
+                SynOption::WindowScale(8),
+                SynOption::Nop,
+                SynOption::Nop,
+                SynOption::SackPermitted,
+            ],
+        }
+    }
+
+    /// macOS's SYNs over Ethernet
+    pub fn macos() -> Self {
+        Self {
+            window: 65535,
+            options: vec![
+                SynOption::Mss(1460),
+                SynOption::Nop,
+                SynOption::WindowScale(6),
+                SynOption::Nop,
+                SynOption::Nop,
+                SynOption::Timestamps,
+                SynOption::SackPermitted,
+                SynOption::Eol,
+                SynOption::Eol,
+            ],
+        }
+    }
+
+    pub fn with_window(mut self, window: u16) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Replaces the options, which are sent in the given order and have to fit in the 40 bytes a TCP header has room
+    /// for. They're padded out to a multiple of 4 bytes with EOLs.
+    pub fn with_options(mut self, options: Vec<SynOption>) -> anyhow::Result<Self> {
+        let options_len: usize = options.iter().map(SynOption::size).sum();
+
+        if options_len > MAX_TCP_OPTIONS_LEN {
+            bail!("SYN options take up {} bytes, but a TCP header only has room for {}.", options_len, MAX_TCP_OPTIONS_LEN);
+        }
+
+        self.options = options;
+        Ok(self)
+    }
+
+    /// Sets the MSS option, in place of the one already there or else ahead of the other options
+    pub fn with_mss(mut self, mss: u16) -> anyhow::Result<Self> {
+        match self.options.iter_mut().find(|option| matches!(option, SynOption::Mss(_))) {
+            Some(option) => *option = SynOption::Mss(mss),
+            None => {
+                let mut options = vec![SynOption::Mss(mss)];
+                options.append(&mut self.options);
+
+                return self.with_options(options);
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Whether these are armada's own SYNs, the only ones scanners that leave the SYNs to the kernel can send
+    pub fn is_default(&self) -> bool { *self == Self::default() }
+
+    pub fn window(&self) -> u16 { self.window }
+
+    pub fn options(&self) -> &[SynOption] { &self.options }
+}
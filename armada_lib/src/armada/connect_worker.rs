@@ -0,0 +1,442 @@
+use crate::armada::config::host::remote_addr;
+use crate::armada::config::rate_limit::RateLimit;
+use crate::armada::network_scheduler::NetworkScheduler;
+use crate::armada::rate_controller::RateController;
+use crate::armada::rtt_estimator::RttEstimator;
+use crate::armada::socks5::{reply_port_state, Socks5Proxy};
+use crate::armada::work::{ArmadaWork, ArmadaWorkMessage, PortState};
+use crate::armada::token_bucket::TokenBucket;
+use crate::armada::worker::STATS_UPDATE_INTERVAL;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{bail, Context};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::Instant;
+use tracing::{error, warn};
+
+/// A worker that scans with the operating system's `connect()` instead of raw sockets, optionally through SOCKS5 proxies.
+/// It needs no privileges but can only tell open ports from closed or filtered ones.
+pub(crate) struct ConnectWorker {
+    work_queue: UnboundedReceiver<ArmadaWork>,
+    concurrency: usize,
+    proxies: Vec<Arc<Socks5Proxy>>,
+}
+
+impl ConnectWorker {
+    pub(crate) fn new(work_queue: UnboundedReceiver<ArmadaWork>, concurrency: usize, proxies: Vec<Socks5Proxy>) -> Self {
+        Self {
+            work_queue,
+            concurrency: concurrency.max(1),
+            proxies: proxies.into_iter().map(Arc::new).collect(),
+        }
+    }
+
+    /// Runs the connect worker on its own single threaded runtime until the work queue closes
+    pub(crate) fn run(mut self) -> anyhow::Result<()> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("Failed to create connect worker runtime.")?;
+
+        runtime.block_on(async move {
+            while let Some(work) = self.work_queue.recv().await {
+                if let Err(e) = self.process_work(work).await {
+                    error!(err = ?e, "scan failed");
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn process_work(&self, work_unit: ArmadaWork) -> anyhow::Result<()> {
+        let ArmadaWork { mut remote_addrs, options, reporting_channel } = work_unit;
+
+        // connects are made from whatever address the kernel picks, with the kernel's own SYNs
+        let scan_type = options.scan_type();
+        let port_retries = options.retries();
+        let port_timeout = options.timeout();
+        let rate_limit = options.rate_limit();
+        let network_limits = options.network_limits();
+
+        // (remote, number of attempts already made)
+        let mut requeued_addrs: Vec<(SocketAddr, u8)> = Vec::new();
+        let mut inflight_probes = FuturesUnordered::new();
+        // rotate through the proxies that still work, one connection attempt at a time
+        let mut live_proxies = self.proxies.clone();
+        let mut next_proxy = 0;
+        // holds back connection attempts to hosts and networks that are at their limits
+        let mut network_scheduler = (!network_limits.is_unlimited()).then(|| NetworkScheduler::new(network_limits));
+
+        // how long to wait on connections to each network, starting out at the port timeout
+        let mut rtt_estimator = RttEstimator::new(port_timeout, network_limits);
+
+        // results list
+        let mut port_results = Vec::new();
+
+        // adaptive rate limits are only a ceiling, below which the controller decides how fast we connect
+        let mut rate_controller = rate_limit
+            .filter(RateLimit::is_adaptive)
+            .map(|rate_limit| RateController::new(&rate_limit, port_timeout));
+        let mut rate_limiter = rate_limit.map(|rate_limit| {
+            let connects_per_second = rate_controller
+                .as_ref()
+                .map_or(rate_limit.packets_per_second(), RateController::rate);
+
+            TokenBucket::new(connects_per_second, rate_limit.burst_at(connects_per_second))
+        });
+
+        let mut last_stats_update = Instant::now();
+        let mut next_stats_update = last_stats_update + STATS_UPDATE_INTERVAL;
+
+        let mut total_processed_ports = 0u128;
+        let mut total_connects_attempted = 0u128;
+        let mut total_connects_attempted_at_last_update = 0u128;
+        let mut current_connects_per_second = 0u128;
+        let mut remote_addrs_exhausted = false;
+
+        loop {
+            let now = std::time::Instant::now();
+
+            if let (Some(rate_controller), Some(rate_limiter), Some(rate_limit)) =
+                (&mut rate_controller, &mut rate_limiter, &rate_limit)
+            {
+                // the kernel handles the replies to our connects, so there's nothing for us to drop
+                if let Some(connects_per_second) = rate_controller.adjust(now, 0) {
+                    rate_limiter.set_rate(now, connects_per_second, rate_limit.burst_at(connects_per_second));
+                }
+            }
+
+            // attempts held back by the network limits are requeued as tokens come in
+            if let Some(network_scheduler) = &mut network_scheduler {
+                network_scheduler.wake(now, &mut requeued_addrs);
+            }
+
+            // set when the rate limit keeps us from starting a connection attempt we otherwise could
+            let mut next_token = None;
+
+            // start as many connection attempts as the concurrency and rate limits allow
+            while inflight_probes.len() < self.concurrency {
+                let now = std::time::Instant::now();
+
+                if let Some(rate_limiter) = &mut rate_limiter {
+                    if rate_limiter.available(now) == 0 {
+                        next_token = Some(rate_limiter.next_available());
+                        break;
+                    }
+                }
+
+                let (remote, attempt) = match requeued_addrs.pop() {
+                    Some(requeued) => requeued,
+                    None if remote_addrs_exhausted => break,
+                    // with enough attempts held back by the network limits, there's no use in pulling in more
+                    None if network_scheduler.as_ref().is_some_and(NetworkScheduler::is_full) => break,
+                    None => match remote_addrs.next() {
+                        Some((port, (addr, scope_id))) => (remote_addr(addr, scope_id, port), 0),
+                        None => {
+                            remote_addrs_exhausted = true;
+                            break;
+                        }
+                    },
+                };
+
+                if let Some(network_scheduler) = &mut network_scheduler {
+                    if !network_scheduler.admit((remote, attempt), remote.ip(), now) {
+                        continue;
+                    }
+                }
+
+                let proxy = (!live_proxies.is_empty()).then(|| {
+                    next_proxy = (next_proxy + 1) % live_proxies.len();
+                    live_proxies[next_proxy].clone()
+                });
+
+                let timeout = rtt_estimator.timeout(remote.ip());
+                inflight_probes.push(probe(remote, attempt, timeout, proxy));
+
+                if let Some(rate_limiter) = &mut rate_limiter {
+                    rate_limiter.consume(1);
+                }
+
+                if let Some(rate_controller) = &mut rate_controller {
+                    rate_controller.record_sent(1);
+                }
+
+                total_connects_attempted += 1;
+            }
+
+            // if the iterator is empty AND we have no more addrs to process we're done
+            if inflight_probes.is_empty()
+                && requeued_addrs.is_empty()
+                && remote_addrs_exhausted
+                && network_scheduler.as_ref().is_none_or(NetworkScheduler::is_empty)
+            {
+                break;
+            }
+
+            // when we might next be able to start more attempts, if the rate or network limits are holding us back
+            let next_start = [next_token, network_scheduler.as_ref().and_then(NetworkScheduler::next_wake_up)]
+                .into_iter()
+                .flatten()
+                .min()
+                .map(Instant::from_std);
+
+            tokio::select! {
+                Some((remote, attempt, probe_result, elapsed)) = inflight_probes.next() => {
+                    if let Some(network_scheduler) = &mut network_scheduler {
+                        network_scheduler.release(remote.ip(), std::time::Instant::now(), &mut requeued_addrs);
+                    }
+
+                    let probe_result = match probe_result {
+                        Ok(probe_result) => probe_result,
+                        // whatever went wrong on our end would go wrong for every attempt after it too
+                        Err(ProbeError::Local(e)) => return Err(e).context("Unable to make a connection attempt."),
+                        // the attempt never made it past the proxy, so it doesn't count against the remote
+                        Err(ProbeError::Proxy(proxy, e)) => {
+                            if let Some(position) = live_proxies.iter().position(|live| Arc::ptr_eq(live, &proxy)) {
+                                warn!(err = ?e, ?proxy, "Unable to use SOCKS5 proxy, leaving it out of this scan");
+                                live_proxies.remove(position);
+                            }
+
+                            if live_proxies.is_empty() {
+                                bail!("Every SOCKS5 proxy failed, so the scan can't go on.");
+                            }
+
+                            requeued_addrs.push((remote, attempt));
+                            continue;
+                        }
+                    };
+
+                    if let Some(rate_controller) = &mut rate_controller {
+                        match probe_result {
+                            Some(_) => rate_controller.record_answer(attempt),
+                            None => rate_controller.record_timeout(attempt),
+                        }
+                    }
+
+                    // connections through a proxy take as long as the proxy does too, and a retried attempt may have
+                    // lost out to congestion, so only first direct attempts say how far away the network is
+                    if probe_result.is_some() && attempt == 0 && self.proxies.is_empty() {
+                        rtt_estimator.record(remote.ip(), elapsed);
+                    }
+
+                    let port_state = match probe_result {
+                        Some(port_state) => port_state,
+                        // no answer, so give it another go if we have retries left
+                        None if attempt < port_retries => {
+                            requeued_addrs.push((remote, attempt + 1));
+                            continue;
+                        }
+                        None => scan_type.timeout_state(),
+                    };
+
+                    total_processed_ports += 1;
+
+                    // a proxy also tells refused ports apart from hosts it couldn't reach, which is worth passing on
+                    let reported = match port_state {
+                        PortState::Open | PortState::Unreachable => true,
+                        PortState::Closed => !self.proxies.is_empty(),
+                        _ => false,
+                    };
+
+                    if reported {
+                        port_results.push((remote, port_state));
+                    }
+                }
+                // time to try starting more connection attempts
+                _ = tokio::time::sleep_until(next_start.unwrap_or(next_stats_update)), if next_start.is_some() => {}
+                _ = tokio::time::sleep_until(next_stats_update) => {
+                    let now = Instant::now();
+                    let connects_since_update = total_connects_attempted - total_connects_attempted_at_last_update;
+                    current_connects_per_second =
+                        (connects_since_update as f64 / now.duration_since(last_stats_update).as_secs_f64()) as u128;
+
+                    last_stats_update = now;
+                    next_stats_update = now + STATS_UPDATE_INTERVAL;
+                    total_connects_attempted_at_last_update = total_connects_attempted;
+
+                    reporting_channel
+                        .send(ArmadaWorkMessage::stats(
+                            total_processed_ports,
+                            inflight_probes.len() as u128,
+                            total_connects_attempted,
+                            0,
+                            0,
+                            current_connects_per_second
+                        ))
+                        .context("Failed to send stats update over reporting channel.")?;
+
+                    if !port_results.is_empty() {
+                        reporting_channel.send(
+                            ArmadaWorkMessage::results(std::mem::take(&mut port_results))
+                        ).context("Failed to send results message to reporting channel.")?;
+                    }
+                }
+            }
+        }
+
+        // send the final stats and results before closing up shop
+        reporting_channel
+            .send(ArmadaWorkMessage::stats(
+                total_processed_ports,
+                inflight_probes.len() as u128,
+                total_connects_attempted,
+                0,
+                0,
+                current_connects_per_second
+            ))
+            .context("Failed to send final stats message over reporting channel.")?;
+
+        reporting_channel
+            .send(ArmadaWorkMessage::results(port_results))
+            .context("Failed to send final results message over reporting channel.")?;
+
+        Ok(())
+    }
+}
+
+/// Attempts a full TCP connection to the remote, either directly or through the given proxy.
+/// Returns `None` if we never heard back, in which case the probe may be retried, along with how long the attempt took.
+/// Errors are for attempts that failed before they got to the remote (see `ProbeError`).
+async fn probe(
+    remote: SocketAddr,
+    attempt: u8,
+    port_timeout: Duration,
+    proxy: Option<Arc<Socks5Proxy>>,
+) -> (SocketAddr, u8, Result<Option<PortState>, ProbeError>, Duration) {
+    let started = std::time::Instant::now();
+    let port_state = match proxy {
+        // a proxy that takes too long may only be slow to reach the remote, which is as good as filtered
+        Some(proxy) => match tokio::time::timeout(port_timeout, proxy.connect(remote)).await {
+            Ok(Ok(reply_code)) => Ok(reply_port_state(reply_code)),
+            Ok(Err(e)) => Err(ProbeError::Proxy(proxy, e)),
+            Err(_) => Ok(None),
+        },
+        None => match tokio::time::timeout(port_timeout, TcpStream::connect(remote)).await {
+            Ok(Ok(_)) => Ok(Some(PortState::Open)),
+            Ok(Err(e)) => connect_error_state(e).map_err(ProbeError::Local),
+            Err(_) => Ok(None),
+        },
+    };
+
+    (remote, attempt, port_state, started.elapsed())
+}
+
+/// Why a connection attempt never got to the remote.
+enum ProbeError {
+    /// the attempt failed on our end, e.g. for lack of file descriptors or local ports
+    Local(std::io::Error),
+    /// the proxy the attempt went through couldn't be connected to, logged into or made sense of
+    Proxy(Arc<Socks5Proxy>, std::io::Error),
+}
+
+/// What a failed direct connection attempt says about the remote port. Errors that say nothing about it because the
+/// attempt failed on our end are passed back.
+fn connect_error_state(error: std::io::Error) -> std::io::Result<Option<PortState>> {
+    match error.kind() {
+        ErrorKind::ConnectionRefused => Ok(Some(PortState::Closed)),
+        // unreachable hosts look like a filtered port, same as timeouts
+        ErrorKind::TimedOut | ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable | ErrorKind::NetworkDown => {
+            Ok(None)
+        }
+        _ => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::armada::config::host::HostIterator;
+    use crate::armada::config::port::PortIterator;
+    use crate::armada::config::scan_options::ScanOptions;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    /// A SOCKS5 proxy without authentication that says every IPv4 remote it's asked for is open.
+    async fn serve_open_ports() -> Socks5Proxy {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut greeting = [0u8; 3];
+                stream.read_exact(&mut greeting).await.unwrap();
+                stream.write_all(&[5, 0]).await.unwrap();
+
+                let mut connect_request = [0u8; 10];
+                stream.read_exact(&mut connect_request).await.unwrap();
+                stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            }
+        });
+
+        Socks5Proxy::new(address)
+    }
+
+    /// A proxy address that nothing listens on.
+    async fn dead_proxy() -> Socks5Proxy {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        Socks5Proxy::new(listener.local_addr().unwrap().to_string())
+    }
+
+    /// Connect scans ports 1 through 8 of 192.0.2.1 through the given proxies without retries, returning whatever
+    /// results were reported along with how the scan ended.
+    async fn scan(proxies: Vec<Socks5Proxy>) -> (Vec<(SocketAddr, PortState)>, anyhow::Result<()>) {
+        let (_, work_queue) = unbounded_channel();
+        let (reporting_channel, mut reports) = unbounded_channel();
+
+        let work = ArmadaWork::new(
+            HostIterator::new().add_ip("192.0.2.1".parse().unwrap()),
+            PortIterator::new().add_range(1, 8),
+            ScanOptions::new().with_retries(0).with_timeout(Duration::from_secs(1)),
+            reporting_channel,
+        );
+        let scan_result = ConnectWorker::new(work_queue, 4, proxies).process_work(work).await;
+
+        let mut results = Vec::new();
+        while let Ok(report) = reports.try_recv() {
+            if let ArmadaWorkMessage::Results(reported) = report {
+                results.extend(reported);
+            }
+        }
+
+        (results, scan_result)
+    }
+
+    #[tokio::test]
+    async fn attempts_through_failed_proxies_go_through_the_others() {
+        let (results, scan_result) = scan(vec![dead_proxy().await, serve_open_ports().await]).await;
+
+        assert!(scan_result.is_ok());
+        assert_eq!(results.len(), 8);
+        assert!(results.iter().all(|(_, port_state)| *port_state == PortState::Open));
+    }
+
+    #[tokio::test]
+    async fn scans_fail_once_every_proxy_has() {
+        let (results, scan_result) = scan(vec![dead_proxy().await, dead_proxy().await]).await;
+
+        assert!(scan_result.is_err());
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn only_remote_failures_say_something_about_the_port() {
+        let state = |raw_error| connect_error_state(std::io::Error::from_raw_os_error(raw_error)).ok();
+
+        assert_eq!(state(libc::ECONNREFUSED), Some(Some(PortState::Closed)));
+        assert_eq!(state(libc::ETIMEDOUT), Some(None));
+        assert_eq!(state(libc::EHOSTUNREACH), Some(None));
+        assert_eq!(state(libc::ENETUNREACH), Some(None));
+
+        // running out of descriptors or local ports is our problem, not the remote's
+        assert_eq!(state(libc::EMFILE), None);
+        assert_eq!(state(libc::ENFILE), None);
+        assert_eq!(state(libc::EADDRNOTAVAIL), None);
+    }
+}
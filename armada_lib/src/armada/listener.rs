@@ -0,0 +1,206 @@
+use crate::armada::config::scan_type::ScanType;
+use crate::armada::config::source_ports::SourcePorts;
+use crate::armada::transport::ReplyReceiver;
+use crate::armada::work::PortState;
+use crate::armada::worker::STATS_UPDATE_INTERVAL;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender, TryRecvError};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::warn;
+
+// the most replies received before checking for new commands from the worker
+const BATCH_RECV_SIZE: usize = 256;
+
+/// Tells the listener which scan, if any, the replies it receives belong to
+pub(crate) enum ListenerCommand {
+    /// Interpret replies according to `scan_type` and forward the state of every port that answered over `replies`
+    Listen {
+        scan_type: ScanType,
+        replies: SyncSender<(SocketAddr, PortState)>,
+    },
+    /// The scan is over, stop listening until the next one
+    Idle,
+}
+
+/// State shared between the worker and the listener, outside of the replies themselves
+pub(crate) struct ListenerStatus {
+    // how far the worker may get ahead of the listener before replies risk being dropped
+    reply_capacity: u64,
+    // replies dropped by the kernel since the current scan started
+    dropped_packets: AtomicU64,
+    // packets skipped as malformed since the current scan started
+    malformed_packets: AtomicU64,
+    // probes the worker has sent so far
+    probes_sent: AtomicU64,
+    // how many probes had been sent the last time the listener found nothing left to receive
+    probes_sent_when_drained: AtomicU64,
+    // set while the listener sleeps on empty sockets
+    waiting: AtomicBool,
+}
+
+impl ListenerStatus {
+    pub(crate) fn new(reply_capacity: u64) -> Self {
+        Self {
+            reply_capacity,
+            dropped_packets: AtomicU64::new(0),
+            malformed_packets: AtomicU64::new(0),
+            probes_sent: AtomicU64::new(0),
+            probes_sent_when_drained: AtomicU64::new(0),
+            waiting: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn dropped_packets(&self) -> u64 { self.dropped_packets.load(Ordering::Relaxed) }
+
+    pub(crate) fn malformed_packets(&self) -> u64 { self.malformed_packets.load(Ordering::Relaxed) }
+
+    pub(crate) fn record_probes_sent(&self, count: usize) { self.probes_sent.fetch_add(count as u64, Ordering::Relaxed); }
+
+    /// Whether so many probes were sent since the listener last caught up that their replies could overflow the
+    /// receiver's buffers
+    pub(crate) fn is_behind(&self) -> bool {
+        let probes_sent_when_drained = self.probes_sent_when_drained.load(Ordering::Relaxed);
+        let probes_sent_since_drained = self.probes_sent.load(Ordering::Relaxed).saturating_sub(probes_sent_when_drained);
+
+        probes_sent_since_drained >= self.reply_capacity
+    }
+
+    /// Tells the listener it's caught up if it's still asleep, which after the worker has given it time to wake up means
+    /// nothing arrived for it to receive. Otherwise the worker would stall whenever its probes go unanswered.
+    pub(crate) fn catch_up_if_waiting(&self) {
+        if self.waiting.load(Ordering::Relaxed) {
+            let probes_sent = self.probes_sent.load(Ordering::Relaxed);
+            self.probes_sent_when_drained.fetch_max(probes_sent, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Receives replies on its own thread so that sending probes never keeps us from draining the socket buffers
+pub(crate) struct ReplyListener {
+    receiver: Box<dyn ReplyReceiver>,
+    commands: Receiver<ListenerCommand>,
+    status: Arc<ListenerStatus>,
+}
+
+impl ReplyListener {
+    pub(crate) fn new(
+        receiver: Box<dyn ReplyReceiver>,
+        commands: Receiver<ListenerCommand>,
+        status: Arc<ListenerStatus>,
+    ) -> Self {
+        Self { receiver, commands, status }
+    }
+
+    /// Runs the listener, only forwarding replies sent to one of the source ports. Returns once the worker hangs up.
+    pub(crate) fn run(mut self, source_ports: SourcePorts) {
+        let mut scan = None;
+        let mut dropped_packets_at_start = 0;
+        let mut malformed_packets_at_start = 0;
+        let mut next_drop_check = Instant::now();
+        let mut last_probes_sent = 0;
+        let mut receive_failed = false;
+
+        loop {
+            // while idle there's nothing to do but wait for the next scan
+            let command = match scan {
+                Some(_) => match self.commands.try_recv() {
+                    Ok(command) => Some(command),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return,
+                },
+                None => match self.commands.recv() {
+                    Ok(command) => Some(command),
+                    Err(_) => return,
+                },
+            };
+
+            match command {
+                Some(ListenerCommand::Listen { scan_type, replies }) => {
+                    // drops and malformed packets are counted from the start of each scan
+                    dropped_packets_at_start = self.receiver.dropped_packets();
+                    malformed_packets_at_start = self.receiver.malformed_packets();
+                    self.status.dropped_packets.store(0, Ordering::Relaxed);
+                    self.status.malformed_packets.store(0, Ordering::Relaxed);
+                    next_drop_check = Instant::now();
+                    receive_failed = false;
+                    scan = Some((scan_type, replies));
+                }
+                Some(ListenerCommand::Idle) => scan = None,
+                None => {}
+            }
+
+            let (scan_type, replies) = match &scan {
+                Some(scan) => scan,
+                None => continue,
+            };
+
+            let protocol = scan_type.transport_protocol();
+
+            // anything sent before this point has had its chance to be answered once we run out of replies
+            let probes_sent = self.status.probes_sent.load(Ordering::Relaxed);
+            let mut drained = false;
+
+            for _ in 0 .. BATCH_RECV_SIZE {
+                let (segment, mut remote) = match self.receiver.try_next(protocol) {
+                    Ok(Some(reply)) => reply,
+                    result => {
+                        // a broken socket fails every time, so only say so once a scan
+                        if let (Err(e), false) = (result, receive_failed) {
+                            warn!(err = ?e, "Unable to receive replies.");
+                            receive_failed = true;
+                        }
+
+                        self.status.probes_sent_when_drained.fetch_max(probes_sent, Ordering::Relaxed);
+                        drained = true;
+                        break;
+                    }
+                };
+
+                let (source_port, destination_port, port_state) = match scan_type.interpret_response(segment) {
+                    Some(response) => response,
+                    None => continue,
+                };
+
+                // the worker may have already finished up, in which case the reply is of no use to anyone
+                if source_ports.contains(destination_port) {
+                    // the zone the reply arrived from is kept, so it matches the probe sent to a link-local target
+                    remote.set_port(source_port);
+                    let _ = replies.send((remote, port_state));
+                }
+            }
+
+            if next_drop_check.le(&Instant::now()) {
+                let dropped_packets = self.receiver.dropped_packets().saturating_sub(dropped_packets_at_start);
+                self.status.dropped_packets.store(dropped_packets, Ordering::Relaxed);
+
+                let malformed_packets = self.receiver.malformed_packets().saturating_sub(malformed_packets_at_start);
+                self.status.malformed_packets.store(malformed_packets, Ordering::Relaxed);
+
+                next_drop_check = Instant::now().checked_add(STATS_UPDATE_INTERVAL).unwrap();
+            }
+
+            // while probes are going out, replies come in faster than we could be woken up for them, so we keep checking
+            // (but let the worker have the core if it needs it). Once the worker goes quiet, e.g. between rate limit
+            // buckets, we sleep until there's something to receive or the drops are due a check. New commands are picked
+            // up once we wake; until then any replies to a new scan simply wait in the socket
+            if drained {
+                match probes_sent == last_probes_sent {
+                    true => {
+                        self.status.waiting.store(true, Ordering::Relaxed);
+
+                        if let Err(e) = self.receiver.wait(next_drop_check.saturating_duration_since(Instant::now())) {
+                            warn!(err = ?e, "Unable to wait for replies.");
+                        }
+
+                        self.status.waiting.store(false, Ordering::Relaxed);
+                    }
+                    false => std::thread::yield_now(),
+                }
+            }
+
+            last_probes_sent = probes_sent;
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use crate::armada::config::source_ports::SourcePorts;
+
+/// The ports within `source_ports` that a local TCP or SCTP socket is bound to. The kernel would answer replies to our
+/// probes on those itself, or hand them to whoever owns the socket, so they can't be scanned from. Only Linux can
+/// tell, everywhere else this finds nothing.
+pub(crate) fn bound_ports(source_ports: &SourcePorts) -> anyhow::Result<Vec<u16>> {
+    #[cfg(target_os = "linux")]
+    return linux::bound_ports(source_ports);
+
+    #[cfg(not(target_os = "linux"))]
+    return Ok(Vec::new());
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use crate::armada::config::source_ports::SourcePorts;
+    use anyhow::Context;
+
+    // every socket is listed with its local address and port in the second column, e.g. `0100007F:1F90`
+    const TCP_SOCKET_TABLES: [&str; 2] = ["/proc/net/tcp", "/proc/net/tcp6"];
+    // SCTP endpoints list their local port in decimal in the sixth column. The table only exists once SCTP is loaded
+    const SCTP_ENDPOINT_TABLE: &str = "/proc/net/sctp/eps";
+
+    pub(super) fn bound_ports(source_ports: &SourcePorts) -> anyhow::Result<Vec<u16>> {
+        let mut bound_ports = Vec::new();
+
+        for table in TCP_SOCKET_TABLES {
+            let sockets = std::fs::read_to_string(table).with_context(|| format!("Unable to read '{}'.", table))?;
+
+            bound_ports.extend(
+                sockets
+                    .lines()
+                    .skip(1)
+                    .filter_map(|socket| socket.split_whitespace().nth(1)?.rsplit_once(':'))
+                    .filter_map(|(_, port)| u16::from_str_radix(port, 16).ok()),
+            );
+        }
+
+        if let Ok(endpoints) = std::fs::read_to_string(SCTP_ENDPOINT_TABLE) {
+            bound_ports.extend(
+                endpoints
+                    .lines()
+                    .skip(1)
+                    .filter_map(|endpoint| endpoint.split_whitespace().nth(5)?.parse::<u16>().ok()),
+            );
+        }
+
+        bound_ports.retain(|port| source_ports.contains(*port));
+        bound_ports.sort_unstable();
+        bound_ports.dedup();
+
+        Ok(bound_ports)
+    }
+}
@@ -1,6 +1,20 @@
 pub mod config;
+mod connect_worker;
+mod listener;
+mod local_sockets;
+#[cfg(target_os = "linux")]
+pub(crate) mod netlink;
+mod network_scheduler;
 mod packet;
+mod probes;
+mod rate_controller;
+mod routing;
+mod rtt_estimator;
+pub mod socks5;
 mod tcp_ext;
+mod timing_wheel;
+mod token_bucket;
+mod transport;
 pub mod work;
 mod worker;
 
@@ -12,25 +26,104 @@ use tokio::sync::mpsc::{
 
 use crate::armada::config::host::HostIterator;
 use crate::armada::config::port::PortIterator;
-use crate::armada::work::{ArmadaWork, ArmadaWorkMessage};
+use crate::armada::config::scan_options::ScanOptions;
+use crate::armada::config::scan_type::ScanType;
+use crate::armada::config::source_ports::SourcePorts;
+use crate::armada::work::{ArmadaWork, ArmadaWorkMessage, PortState};
+use crate::armada::connect_worker::ConnectWorker;
+use crate::armada::socks5::Socks5Proxy;
+use crate::armada::transport::Transport;
 use crate::armada::worker::ArmadaWorker;
 use futures::stream::StreamExt;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::time::Duration;
-use anyhow::Context;
+use std::net::SocketAddr;
+use anyhow::{bail, Context};
 use tokio_stream::wrappers::UnboundedReceiverStream;
-use tracing::error;
+use tracing::{error, warn};
 
 /// High performance port scanner
 #[derive(Clone)]
 pub struct Armada {
-    // raw socket
     work_sender: UnboundedSender<ArmadaWork>,
+    // what the scanner sends its probes through, which decides the scans it can take on
+    kind: ScannerKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ScannerKind {
+    // raw sockets, the kernel writing the IP headers
+    Raw,
+    // raw sockets with IP_HDRINCL
+    HeaderIncluded,
+    // whole frames, through a datalink channel or PACKET_MMAP rings
+    Datalink,
+    // full TCP connects, possibly through SOCKS5 proxies
+    Connect,
 }
 
 impl Armada {
     // todo: add options
-    pub fn new(listening_port: u16) -> Self {
+    /// Creates a raw socket based scanner, sending probes from `source_ports`. With an interface named, probes only leave
+    /// (and replies only arrive) through it, which is only supported on Linux. The socket buffer sizes are in bytes and
+    /// left up to the kernel if not given, bigger receive buffers letting more replies wait out bursts. This fails if the
+    /// raw sockets can't be opened, e.g. without CAP_NET_RAW, or if a local socket is bound to any of the source ports.
+    pub fn new(
+        source_ports: SourcePorts,
+        interface_name: Option<&str>,
+        receive_buffer_size: Option<usize>,
+        send_buffer_size: Option<usize>,
+    ) -> anyhow::Result<Self> {
+        // open the sockets up front so callers learn about missing privileges right away
+        let transport = transport::raw::open(source_ports, interface_name, receive_buffer_size, send_buffer_size, false)?;
+
+        Self::spawn_worker(source_ports, transport, ScannerKind::Raw)
+    }
+
+    /// Creates a scanner that, like `new`, sends through raw sockets, but builds each probe's IP header itself
+    /// (IP_HDRINCL) rather than leaving it to the kernel. The kernel still routes the probes, but their TTL, hop limit,
+    /// TOS, IPv4 identification and don't fragment bit can be set per scan (see `IpHeader`). Building IPv6 headers
+    /// needs Linux 4.5 or later.
+    pub fn new_l3(
+        source_ports: SourcePorts,
+        interface_name: Option<&str>,
+        receive_buffer_size: Option<usize>,
+        send_buffer_size: Option<usize>,
+    ) -> anyhow::Result<Self> {
+        let transport = transport::raw::open(source_ports, interface_name, receive_buffer_size, send_buffer_size, true)?;
+
+        Self::spawn_worker(source_ports, transport, ScannerKind::HeaderIncluded)
+    }
+
+    /// Creates a scanner that writes whole Ethernet frames to the named interface or, if none is given, the one holding
+    /// the default route, bypassing the kernel's routing and IP layers. Every probe is sent to the default gateway, so
+    /// on-link targets can't be scanned. Like `new`, this needs root or CAP_NET_RAW.
+    pub fn new_l2(source_ports: SourcePorts, interface_name: Option<&str>) -> anyhow::Result<Self> {
+        let transport = transport::datalink::open(interface_name)?;
+
+        Self::spawn_worker(source_ports, transport, ScannerKind::Datalink)
+    }
+
+    /// Creates a scanner that, like `new_l2`, sends whole Ethernet frames to the default gateway, but does so through
+    /// PACKET_MMAP rings shared with the kernel, which sustains far higher packet rates. Frames go out on the named
+    /// interface or, if none is given, the one holding the default route. This is only available on Linux and needs
+    /// root or CAP_NET_RAW.
+    pub fn new_mmap(source_ports: SourcePorts, interface_name: Option<&str>) -> anyhow::Result<Self> {
+        #[cfg(target_os = "linux")]
+        return Self::spawn_worker(source_ports, transport::mmap::open(interface_name)?, ScannerKind::Datalink);
+
+        #[cfg(not(target_os = "linux"))]
+        anyhow::bail!("PACKET_MMAP scanning (on {:?}) is only available on Linux.", interface_name);
+    }
+
+    fn spawn_worker(source_ports: SourcePorts, transport: Transport, kind: ScannerKind) -> anyhow::Result<Self> {
+        // replies to probes from a port that's already in use would go to whoever holds it, not to us
+        match local_sockets::bound_ports(&source_ports) {
+            Ok(bound_ports) if !bound_ports.is_empty() => {
+                bail!("Local sockets are bound to source ports {:?}. Pick ports that are free.", bound_ports)
+            }
+            Ok(_) => {}
+            Err(e) => warn!(err = ?e, "Unable to check whether the source ports are free."),
+        }
+
         let (work_sender, work_receiver) = unbounded_channel();
 
         let armada_worker = ArmadaWorker::new(work_receiver);
@@ -38,34 +131,45 @@ impl Armada {
         std::thread::Builder::new()
             .name("armada_worker".to_string())
             .spawn(move || {
-                if let Err(e) = armada_worker.run(listening_port) {
+                if let Err(e) = armada_worker.run(source_ports, transport) {
                     error!(err = ?e);
                 }
-            }).expect("Failed to create armada worker thread.");
+            }).context("Failed to create armada worker thread.")?;
 
-        Self { work_sender }
+        Ok(Self { work_sender, kind })
+    }
+
+    /// Creates an unprivileged scanner that performs full TCP connects, with at most `concurrency` connection attempts in flight.
+    /// Connect scans only support `ScanType::Syn` style results (open ports) and ignore the source addresses of a scan.
+    /// If any `proxies` are supplied, connections are made through them in round-robin order instead of directly.
+    pub fn new_connect(concurrency: usize, proxies: Vec<Socks5Proxy>) -> anyhow::Result<Self> {
+        let (work_sender, work_receiver) = unbounded_channel();
+
+        let connect_worker = ConnectWorker::new(work_receiver, concurrency, proxies);
+
+        std::thread::Builder::new()
+            .name("armada_connect_worker".to_string())
+            .spawn(move || {
+                if let Err(e) = connect_worker.run() {
+                    error!(err = ?e);
+                }
+            }).context("Failed to create armada connect worker thread.")?;
+
+        Ok(Self { work_sender, kind: ScannerKind::Connect })
     }
 
     /// Initiates a port scan and returns the final port scan results.
+    /// The scan is carried out as `options` describe (see `ScanOptions`).
+    /// Connect scanners only take SYN scans.
+    /// IP header fields can only be set on scanners that build the IP headers themselves, and only raw SYN scans can send
+    /// SYNs other than armada's own. Scans asking for anything else are turned down with an error.
     pub async fn scan_collect(
         &self,
         remote_hosts: HostIterator,
         ports: PortIterator,
-        source_ipv4_addrs: Vec<Ipv4Addr>,
-        source_ipv6_addrs: Vec<Ipv6Addr>,
-        port_retries: u8,
-        port_timeout: Duration,
-        packets_per_second: Option<usize>,
-    ) -> anyhow::Result<Vec<SocketAddr>> {
-        let armada_work_results_handle = self.scan_with_handle(
-            remote_hosts,
-            ports,
-            source_ipv4_addrs,
-            source_ipv6_addrs,
-            port_retries,
-            port_timeout,
-            packets_per_second
-        )?;
+        options: ScanOptions,
+    ) -> anyhow::Result<Vec<(SocketAddr, PortState)>> {
+        let armada_work_results_handle = self.scan_with_handle(remote_hosts, ports, options)?;
 
         // receive all of the reports, filter out non-result messages, and flatten the result list
         let results = UnboundedReceiverStream::new(armada_work_results_handle)
@@ -84,33 +188,79 @@ impl Armada {
     }
 
     /// Initiates a port scan and returns a stream handle that can be used to receive both results and statistics of the scan process.
+    /// The scan is carried out as `options` describe (see `ScanOptions`).
+    /// Connect scanners only take SYN scans.
+    /// IP header fields can only be set on scanners that build the IP headers themselves, and only raw SYN scans can send
+    /// SYNs other than armada's own. Scans asking for anything else are turned down with an error.
     pub fn scan_with_handle(
         &self,
         remote_hosts: HostIterator,
         ports: PortIterator,
-        source_ipv4_addrs: Vec<Ipv4Addr>,
-        source_ipv6_addrs: Vec<Ipv6Addr>,
-        port_retries: u8,
-        port_timeout: Duration,
-        packets_per_second: Option<usize>,
+        options: ScanOptions,
     ) -> anyhow::Result<UnboundedReceiver<ArmadaWorkMessage>> {
+        // the worker threads can only log why they gave up on a scan, so turn down what they can't do right here
+        let scan_type = options.scan_type();
+
+        if self.kind == ScannerKind::Connect && scan_type != ScanType::Syn {
+            bail!("Connect scans can only find open TCP ports. {:?} scans require raw sockets.", scan_type);
+        }
+
+        if options.ip_header().is_set() && matches!(self.kind, ScannerKind::Raw | ScannerKind::Connect) {
+            bail!("The kernel builds the IP headers of this scanner's probes, so their fields can't be set.");
+        }
+
+        if !options.syn_profile().is_default() {
+            if self.kind == ScannerKind::Connect {
+                bail!("The kernel builds the SYNs of connect scans, so their window and options can't be set.");
+            }
+
+            if scan_type != ScanType::Syn {
+                bail!("SYN profiles only shape the probes of SYN scans, not {:?} scans.", scan_type);
+            }
+        }
+
         let (reporting_channel, report_receiver) = unbounded_channel();
 
-        let work = ArmadaWork::new(
-            remote_hosts,
-            ports,
-            port_retries,
-            port_timeout,
-            packets_per_second,
-            source_ipv4_addrs,
-            source_ipv6_addrs,
-            reporting_channel,
-        );
-
-        let _ = self.work_sender
+        let work = ArmadaWork::new(remote_hosts, ports, options, reporting_channel);
+
+        self.work_sender
             .send(work)
             .context("Failed to send armada work over work sender channel.")?;
 
         Ok(report_receiver)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::armada::config::ip_header::IpHeader;
+    use crate::armada::config::syn_profile::SynProfile;
+
+    fn scan(armada: &Armada, options: ScanOptions) -> anyhow::Result<UnboundedReceiver<ArmadaWorkMessage>> {
+        armada.scan_with_handle(
+            HostIterator::new().add_ip("127.0.0.1".parse().unwrap()),
+            PortIterator::new().add_port(1),
+            options.with_retries(0).with_timeout(std::time::Duration::from_millis(10)),
+        )
+    }
+
+    #[test]
+    fn connect_scanners_turn_down_raw_scan_types() {
+        let armada = Armada::new_connect(1, vec![]).unwrap();
+        let scan_type = |scan_type| scan(&armada, ScanOptions::new().with_scan_type(scan_type));
+
+        assert!(scan_type(ScanType::Ack).is_err());
+        assert!(scan_type(ScanType::SctpInit).is_err());
+        assert!(scan_type(ScanType::Syn).is_ok());
+    }
+
+    #[test]
+    fn scanners_turn_down_probes_they_cant_build() {
+        let armada = Armada::new_connect(1, vec![]).unwrap();
+
+        assert!(scan(&armada, ScanOptions::new().with_ip_header(IpHeader::new().with_ttl(5))).is_err());
+        assert!(scan(&armada, ScanOptions::new().with_syn_profile(SynProfile::linux())).is_err());
+        assert!(scan(&armada, ScanOptions::new().with_syn_profile(SynProfile::armada())).is_ok());
+    }
+}
@@ -0,0 +1,301 @@
+use anyhow::{bail, Context};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// from linux/netlink.h and linux/rtnetlink.h. Not every libc release exposes them, so we define the ones we need
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_DUMP: u16 = 0x300;
+const RTM_NEWLINK: u16 = 16;
+const RTM_GETLINK: u16 = 18;
+const RTM_NEWADDR: u16 = 20;
+const RTM_GETADDR: u16 = 22;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_GETROUTE: u16 = 26;
+
+// route attributes
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+const RTA_PRIORITY: u16 = 6;
+const RTA_PREFSRC: u16 = 7;
+const RTA_TABLE: u16 = 15;
+
+// address attributes. IFA_LOCAL is the address itself, IFA_ADDRESS the peer's on point-to-point links
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+
+// link attributes
+const IFLA_ADDRESS: u16 = 1;
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MTU: u16 = 4;
+
+// header lengths, all of them already multiples of 4
+const NLMSG_HEADER_LEN: usize = 16;
+const RTMSG_LEN: usize = 12;
+const IFADDRMSG_LEN: usize = 8;
+const IFINFOMSG_LEN: usize = 16;
+const RTATTR_HEADER_LEN: usize = 4;
+
+// a dump comes in as many datagrams as it takes. The kernel never makes one bigger than this
+const RECV_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A route from the kernel's routing tables
+#[derive(Clone, Debug)]
+pub(crate) struct Route {
+    pub(crate) destination: IpAddr,
+    pub(crate) prefix_len: u8,
+    pub(crate) table: u32,
+    // RTN_UNICAST, RTN_LOCAL, RTN_UNREACHABLE and so on
+    pub(crate) route_type: u8,
+    pub(crate) interface_index: Option<u32>,
+    pub(crate) gateway: Option<IpAddr>,
+    pub(crate) preferred_source: Option<IpAddr>,
+    // the route's metric. Lower wins
+    pub(crate) priority: u32,
+}
+
+/// An address assigned to an interface
+#[derive(Clone, Debug)]
+pub(crate) struct InterfaceAddress {
+    pub(crate) interface_index: u32,
+    pub(crate) address: IpAddr,
+    pub(crate) prefix_len: u8,
+}
+
+/// A network interface
+#[derive(Clone, Debug)]
+pub(crate) struct Interface {
+    pub(crate) index: u32,
+    pub(crate) name: String,
+    // the hardware address, for interfaces that have one
+    pub(crate) mac: Option<[u8; 6]>,
+    pub(crate) mtu: Option<u32>,
+}
+
+/// Every route in every routing table, for both address families
+pub(crate) fn routes() -> anyhow::Result<Vec<Route>> {
+    let mut routes = Vec::new();
+
+    dump(RTM_GETROUTE, &[0u8; RTMSG_LEN], |message_type, message| {
+        if message_type != RTM_NEWROUTE || message.len() < RTMSG_LEN {
+            return;
+        }
+
+        let family = message[0];
+        let mut route = Route {
+            destination: unspecified(family),
+            prefix_len: message[1],
+            table: message[4] as u32,
+            route_type: message[7],
+            interface_index: None,
+            gateway: None,
+            preferred_source: None,
+            priority: 0,
+        };
+
+        for (attribute_type, data) in attributes(&message[RTMSG_LEN ..]) {
+            match attribute_type {
+                RTA_DST => route.destination = parse_ip(family, data).unwrap_or(route.destination),
+                RTA_OIF => route.interface_index = parse_u32(data),
+                RTA_GATEWAY => route.gateway = parse_ip(family, data),
+                RTA_PRIORITY => route.priority = parse_u32(data).unwrap_or(0),
+                RTA_PREFSRC => route.preferred_source = parse_ip(family, data),
+                // tables past 255 don't fit the header's field
+                RTA_TABLE => route.table = parse_u32(data).unwrap_or(route.table),
+                _ => {}
+            }
+        }
+
+        // anything other than IPv4 or IPv6 (e.g. multicast routing) is of no use to us
+        if family == libc::AF_INET as u8 || family == libc::AF_INET6 as u8 {
+            routes.push(route);
+        }
+    })?;
+
+    Ok(routes)
+}
+
+/// Every address assigned to any interface, for both address families
+pub(crate) fn addresses() -> anyhow::Result<Vec<InterfaceAddress>> {
+    let mut addresses = Vec::new();
+
+    dump(RTM_GETADDR, &[0u8; IFADDRMSG_LEN], |message_type, message| {
+        if message_type != RTM_NEWADDR || message.len() < IFADDRMSG_LEN {
+            return;
+        }
+
+        let family = message[0];
+        let prefix_len = message[1];
+        let interface_index = u32::from_ne_bytes([message[4], message[5], message[6], message[7]]);
+        let mut local = None;
+        let mut address = None;
+
+        for (attribute_type, data) in attributes(&message[IFADDRMSG_LEN ..]) {
+            match attribute_type {
+                IFA_LOCAL => local = parse_ip(family, data),
+                IFA_ADDRESS => address = parse_ip(family, data),
+                _ => {}
+            }
+        }
+
+        if let Some(address) = local.or(address) {
+            addresses.push(InterfaceAddress { interface_index, address, prefix_len });
+        }
+    })?;
+
+    Ok(addresses)
+}
+
+/// Every network interface
+pub(crate) fn interfaces() -> anyhow::Result<Vec<Interface>> {
+    let mut interfaces = Vec::new();
+
+    dump(RTM_GETLINK, &[0u8; IFINFOMSG_LEN], |message_type, message| {
+        if message_type != RTM_NEWLINK || message.len() < IFINFOMSG_LEN {
+            return;
+        }
+
+        let index = u32::from_ne_bytes([message[4], message[5], message[6], message[7]]);
+        let mut name = None;
+        let mut mac = None;
+        let mut mtu = None;
+
+        for (attribute_type, data) in attributes(&message[IFINFOMSG_LEN ..]) {
+            match attribute_type {
+                // NUL terminated
+                IFLA_IFNAME => name = std::str::from_utf8(data).ok().map(|name| name.trim_end_matches('\0').to_string()),
+                // only Ethernet-like addresses are of any use to us
+                IFLA_ADDRESS => mac = <[u8; 6]>::try_from(data).ok(),
+                IFLA_MTU => mtu = parse_u32(data),
+                _ => {}
+            }
+        }
+
+        if let Some(name) = name {
+            interfaces.push(Interface { index, name, mac, mtu });
+        }
+    })?;
+
+    Ok(interfaces)
+}
+
+/// A NETLINK_ROUTE socket, closed on drop
+struct NetlinkSocket {
+    fd: libc::c_int,
+}
+
+impl Drop for NetlinkSocket {
+    fn drop(&mut self) { unsafe { libc::close(self.fd); } }
+}
+
+/// Asks the kernel for a dump of `request_type`, with `header` (the request's family specific header) zeroed out to
+/// cover every family, and hands each message's type and body to `handle` as it comes in
+fn dump(request_type: u16, header: &[u8], mut handle: impl FnMut(u16, &[u8])) -> anyhow::Result<()> {
+    let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE) };
+
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context("Unable to open a netlink socket.");
+    }
+
+    let socket = NetlinkSocket { fd };
+    let sequence = 1u32;
+
+    let mut request = Vec::with_capacity(NLMSG_HEADER_LEN + header.len());
+    request.extend(((NLMSG_HEADER_LEN + header.len()) as u32).to_ne_bytes());
+    request.extend(request_type.to_ne_bytes());
+    request.extend((NLM_F_REQUEST | NLM_F_DUMP).to_ne_bytes());
+    request.extend(sequence.to_ne_bytes());
+    // the kernel fills in our port id
+    request.extend(0u32.to_ne_bytes());
+    request.extend(header);
+
+    // with no address given, the request goes to the kernel
+    let sent = unsafe { libc::send(socket.fd, request.as_ptr() as *const libc::c_void, request.len(), 0) };
+
+    if sent < 0 {
+        return Err(std::io::Error::last_os_error()).context("Unable to send a netlink request.");
+    }
+
+    let mut buffer = vec![0u8; RECV_BUFFER_SIZE];
+
+    loop {
+        let received = unsafe { libc::recv(socket.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(), 0) };
+
+        if received < 0 {
+            return Err(std::io::Error::last_os_error()).context("Unable to receive a netlink reply.");
+        }
+
+        if received == 0 {
+            bail!("The netlink dump ended early.");
+        }
+
+        let mut messages = &buffer[.. received as usize];
+
+        while messages.len() >= NLMSG_HEADER_LEN {
+            let message_len = u32::from_ne_bytes([messages[0], messages[1], messages[2], messages[3]]) as usize;
+            let message_type = u16::from_ne_bytes([messages[4], messages[5]]);
+
+            if message_len < NLMSG_HEADER_LEN || message_len > messages.len() {
+                bail!("Received a malformed netlink message.");
+            }
+
+            let body = &messages[NLMSG_HEADER_LEN .. message_len];
+
+            match message_type {
+                NLMSG_DONE => return Ok(()),
+                NLMSG_ERROR => {
+                    // the negated errno, followed by the request that caused it
+                    let error = parse_u32(body).unwrap_or(0) as i32;
+
+                    return Err(std::io::Error::from_raw_os_error(-error)).context("The netlink request failed.");
+                }
+                _ => handle(message_type, body),
+            }
+
+            messages = &messages[align(message_len).min(messages.len()) ..];
+        }
+    }
+}
+
+/// The `(type, data)` of each attribute in `buffer`
+fn attributes(mut buffer: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if buffer.len() < RTATTR_HEADER_LEN {
+            return None;
+        }
+
+        let attribute_len = u16::from_ne_bytes([buffer[0], buffer[1]]) as usize;
+        let attribute_type = u16::from_ne_bytes([buffer[2], buffer[3]]);
+
+        if attribute_len < RTATTR_HEADER_LEN || attribute_len > buffer.len() {
+            return None;
+        }
+
+        let data = &buffer[RTATTR_HEADER_LEN .. attribute_len];
+        buffer = &buffer[align(attribute_len).min(buffer.len()) ..];
+
+        Some((attribute_type, data))
+    })
+}
+
+/// Netlink messages and attributes are padded out to 4 bytes
+fn align(len: usize) -> usize { (len + 3) & !3 }
+
+fn parse_u32(data: &[u8]) -> Option<u32> { Some(u32::from_ne_bytes(data.get(.. 4)?.try_into().ok()?)) }
+
+fn parse_ip(family: u8, data: &[u8]) -> Option<IpAddr> {
+    match family as libc::c_int {
+        libc::AF_INET => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(data.get(.. 4)?).ok()?))),
+        libc::AF_INET6 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(data.get(.. 16)?).ok()?))),
+        _ => None,
+    }
+}
+
+/// Default routes carry no destination, so they start out as the family's unspecified address
+fn unspecified(family: u8) -> IpAddr {
+    match family as libc::c_int {
+        libc::AF_INET6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    }
+}
@@ -0,0 +1,221 @@
+use crate::armada::config::network_limits::NetworkLimits;
+use crate::armada::timing_wheel::TimingWheel;
+use crate::armada::token_bucket::TokenBucket;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasherDefault;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use twox_hash::XxHash64;
+
+// the most probes held back at once. Past this, the caller should stop feeding in new ones until some are released
+const MAX_HELD_PROBES: usize = 1024 * 64;
+// how often idle hosts and networks are forgotten
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// how far ahead of its rate a host or network may get. Held back probes are only released once the wake up wheel ticks
+// over and we get around to them, so without some slack every late wake up would cost a host its tokens
+const BURST_DURATION: Duration = Duration::from_millis(5);
+const MIN_BURST: usize = 2;
+
+/// A host, or the network it belongs to. Each has limits of its own
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Destination {
+    Host(IpAddr),
+    Network(IpAddr),
+}
+
+struct DestinationState<T> {
+    // spaces probes out evenly, without letting more than a few milliseconds' worth build up
+    rate_limiter: Option<TokenBucket>,
+    inflight_limit: Option<usize>,
+    inflight: usize,
+    // probes held back by this destination's limits, oldest first
+    held: VecDeque<T>,
+    // whether it's waiting on tokens to release its held probes
+    scheduled: bool,
+}
+
+impl<T> DestinationState<T> {
+    fn new(packets_per_second: Option<usize>, inflight_limit: Option<usize>) -> Self {
+        Self {
+            rate_limiter: packets_per_second.map(|packets_per_second| {
+                let burst = (packets_per_second as f64 * BURST_DURATION.as_secs_f64()) as usize;
+
+                TokenBucket::new(packets_per_second, burst.max(MIN_BURST))
+            }),
+            inflight_limit,
+            inflight: 0,
+            held: VecDeque::new(),
+            scheduled: false,
+        }
+    }
+
+    /// How many more probes can go out at `now`
+    fn room(&mut self, now: Instant) -> usize {
+        let tokens = self.rate_limiter.as_mut().map_or(usize::MAX, |rate_limiter| rate_limiter.available(now));
+        let inflight_room = self.inflight_limit.map_or(usize::MAX, |limit| limit.saturating_sub(self.inflight));
+
+        tokens.min(inflight_room)
+    }
+
+    /// Whether it could be forgotten without loosening its limits
+    fn is_idle(&mut self, now: Instant) -> bool {
+        self.held.is_empty()
+            && self.inflight == 0
+            && !self.scheduled
+            && self.rate_limiter.as_mut().is_none_or(|rate_limiter| rate_limiter.is_full(now))
+    }
+}
+
+/// Keeps a scan within its per host and per network limits. Probes to a host or network that's at its limits are held
+/// back, while probes elsewhere go ahead, and are released once it has room again, either because tokens came in or
+/// because probes to it were answered or timed out.
+pub(crate) struct NetworkScheduler<T> {
+    limits: NetworkLimits,
+    destinations: HashMap<Destination, DestinationState<T>, BuildHasherDefault<XxHash64>>,
+    // destinations waiting on tokens, by when they'll have them
+    wake_ups: TimingWheel<Destination>,
+    woken: Vec<Destination>,
+    held_len: usize,
+    next_sweep: Instant,
+}
+
+impl<T> NetworkScheduler<T> {
+    pub(crate) fn new(limits: NetworkLimits) -> Self {
+        // the wheel only needs to reach as far out as the slowest rate's tokens
+        let slowest_rate = [limits.host_packets_per_second(), limits.network_packets_per_second()]
+            .into_iter()
+            .flatten()
+            .min()
+            .unwrap_or(1);
+
+        Self {
+            limits,
+            destinations: HashMap::default(),
+            wake_ups: TimingWheel::new(Duration::from_secs(1) / slowest_rate.min(u32::MAX as usize) as u32),
+            woken: Vec::new(),
+            held_len: 0,
+            next_sweep: Instant::now() + SWEEP_INTERVAL,
+        }
+    }
+
+    /// Whether no probes are held back
+    pub(crate) fn is_empty(&self) -> bool { self.held_len == 0 }
+
+    /// Whether so many probes are held back that no new ones should be fed in for now
+    pub(crate) fn is_full(&self) -> bool { self.held_len >= MAX_HELD_PROBES }
+
+    /// When the next held back probes might be released for tokens coming in, if any are waiting on them
+    pub(crate) fn next_wake_up(&self) -> Option<Instant> { self.wake_ups.next_expiry() }
+
+    /// Lets the probe to `ip` go out if neither the host nor its network is at its limits, counting it against both
+    /// until it's `release`d. Otherwise it's held back, and later handed back out by `release` or `wake`.
+    pub(crate) fn admit(&mut self, probe: T, ip: IpAddr, now: Instant) -> bool {
+        let destinations = self.destinations_of(ip);
+
+        for destination in destinations.into_iter().flatten() {
+            let limits = self.limits;
+            let state = self
+                .destinations
+                .entry(destination)
+                .or_insert_with(|| match destination {
+                    Destination::Host(_) => DestinationState::new(limits.host_packets_per_second(), limits.host_inflight()),
+                    Destination::Network(_) => {
+                        DestinationState::new(limits.network_packets_per_second(), limits.network_inflight())
+                    }
+                });
+
+            if state.room(now) == 0 {
+                state.held.push_back(probe);
+                self.held_len += 1;
+                self.schedule_wake_up(destination, now);
+                return false;
+            }
+        }
+
+        for destination in destinations.into_iter().flatten() {
+            if let Some(state) = self.destinations.get_mut(&destination) {
+                if let Some(rate_limiter) = &mut state.rate_limiter {
+                    rate_limiter.consume(1);
+                }
+
+                state.inflight += 1;
+            }
+        }
+
+        true
+    }
+
+    /// Stops counting an admitted probe to `ip` as in flight, which may make room for probes held back on its account.
+    /// Those are added to `released`.
+    pub(crate) fn release(&mut self, ip: IpAddr, now: Instant, released: &mut Vec<T>) {
+        for destination in self.destinations_of(ip).into_iter().flatten() {
+            if let Some(state) = self.destinations.get_mut(&destination) {
+                state.inflight = state.inflight.saturating_sub(1);
+            }
+
+            self.release_held(destination, now, released);
+        }
+    }
+
+    /// Releases the probes held back by every host or network whose tokens have come in by `now`, adding them to
+    /// `released`
+    pub(crate) fn wake(&mut self, now: Instant, released: &mut Vec<T>) {
+        self.wake_ups.expire(now, &mut self.woken);
+
+        while let Some(destination) = self.woken.pop() {
+            if let Some(state) = self.destinations.get_mut(&destination) {
+                state.scheduled = false;
+            }
+
+            self.release_held(destination, now, released);
+        }
+
+        if self.next_sweep <= now {
+            self.next_sweep = now + SWEEP_INTERVAL;
+            self.destinations.retain(|_, state| !state.is_idle(now));
+        }
+    }
+
+    /// Releases as many of the destination's held back probes as it has room for. If there are more it lacks the
+    /// tokens for, it's woken up again once those come in.
+    fn release_held(&mut self, destination: Destination, now: Instant, released: &mut Vec<T>) {
+        let state = match self.destinations.get_mut(&destination) {
+            Some(state) if !state.held.is_empty() => state,
+            _ => return,
+        };
+
+        let release_count = state.room(now).min(state.held.len());
+
+        released.extend(state.held.drain(.. release_count));
+        self.held_len -= release_count;
+
+        self.schedule_wake_up(destination, now);
+    }
+
+    /// Schedules a wake up for when the destination has tokens again, if it's holding probes back for lack of them
+    fn schedule_wake_up(&mut self, destination: Destination, now: Instant) {
+        let state = match self.destinations.get_mut(&destination) {
+            Some(state) => state,
+            None => return,
+        };
+
+        if state.scheduled || state.held.is_empty() {
+            return;
+        }
+
+        if let Some(rate_limiter) = &mut state.rate_limiter {
+            if rate_limiter.available(now) == 0 {
+                state.scheduled = true;
+                self.wake_ups.insert(rate_limiter.next_available(), destination);
+            }
+        }
+    }
+
+    /// The host and network of `ip`, unless they aren't limited
+    fn destinations_of(&self, ip: IpAddr) -> [Option<Destination>; 2] {
+        [
+            self.limits.limits_hosts().then_some(Destination::Host(ip)),
+            self.limits.limits_networks().then(|| Destination::Network(self.limits.network_of(ip))),
+        ]
+    }
+}
@@ -1,79 +1,1070 @@
-use pnet::packet::ip::IpNextHeaderProtocols;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use crate::armada::config::ip_header::IpHeader;
+use crate::armada::config::syn_profile::{SynOption, SynProfile};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Range;
 use pnet::packet::tcp::MutableTcpPacket;
 
-pub(crate) fn create_syn_tcp_packet_v4<'b>(
-    source_ip: &Ipv4Addr,
-    remote_ip: &Ipv4Addr,
+// a bare tcp header, used by every probe that doesn't carry options
+const TCP_HEADER_LEN: usize = 20;
+// the largest probe we build: a SYN with a full 40 bytes of options. SCTP INITs are 32 bytes
+pub(crate) const MAX_PROBE_PACKET_LEN: usize = 60;
+
+// where the checksum sits within a tcp header
+const TCP_CHECKSUM_OFFSET: usize = 16;
+
+/// A TCP probe built once per scan. Only the addresses, the ports and the sequence number change from probe to probe,
+/// so rather than rebuilding the header and summing the whole segment each time, those fields are patched into
+/// a copy and the checksum is updated for them alone (RFC 1624). The template holds zeros in their place, which turns
+/// the update into just adding in the new values.
+pub(crate) struct TcpProbeTemplate {
+    segment: [u8; MAX_PROBE_PACKET_LEN],
+    segment_len: usize,
+    // the unfolded one's complement sum of the template and the pseudo-header fields that never change
+    partial_sum: u32,
+}
+
+impl TcpProbeTemplate {
+    /// Builds the probe for a scan, carrying `flags`. SYN probes get their window and options from `syn_profile`.
+    pub(crate) fn new(flags: u16, syn_profile: &SynProfile) -> Self {
+        use pnet::packet::tcp::TcpFlags::SYN;
+
+        let mut segment = [0u8; MAX_PROBE_PACKET_LEN];
+
+        // only SYN probes carry options. Everything else is a bare header
+        let (window, options_len) = match flags & SYN {
+            0 => (1024, 0),
+            _ => (syn_profile.window(), write_tcp_options(syn_profile.options(), &mut segment[TCP_HEADER_LEN ..])),
+        };
+        let segment_len = TCP_HEADER_LEN + options_len;
+
+        let mut tcp_packet = MutableTcpPacket::new(&mut segment[.. segment_len]).expect("A probe always fits its buffer.");
+
+        tcp_packet.set_window(window);
+        tcp_packet.set_data_offset((segment_len / 4) as u8);
+        tcp_packet.set_flags(flags);
+
+        // the protocol and segment length are the constant half of the pseudo-header
+        let partial_sum = sum_words(&segment[.. segment_len])
+            + IpNextHeaderProtocols::Tcp.0 as u32
+            + segment_len as u32;
+
+        Self { segment, segment_len, partial_sum }
+    }
+
+    /// Writes the next probe from `source_ip`:`source_port` to `remote_ip`:`remote_port` to the front of the buffer,
+    /// advancing `tcp_seq`. Returns the length of the probe.
+    pub(crate) fn write_v4(
+        &self,
+        source_ip: &Ipv4Addr,
+        source_port: u16,
+        remote_ip: &Ipv4Addr,
+        remote_port: u16,
+        buffer: &mut [u8],
+        tcp_seq: &mut u32,
+    ) -> Option<usize> {
+        let address_sum = sum_words(&source_ip.octets()) + sum_words(&remote_ip.octets());
+
+        self.write(address_sum, source_port, remote_port, buffer, tcp_seq)
+    }
+
+    /// Like `write_v4`, for probes over IPv6
+    pub(crate) fn write_v6(
+        &self,
+        source_ip: &Ipv6Addr,
+        source_port: u16,
+        remote_ip: &Ipv6Addr,
+        remote_port: u16,
+        buffer: &mut [u8],
+        tcp_seq: &mut u32,
+    ) -> Option<usize> {
+        let address_sum = sum_words(&source_ip.octets()) + sum_words(&remote_ip.octets());
+
+        self.write(address_sum, source_port, remote_port, buffer, tcp_seq)
+    }
+
+    fn write(
+        &self,
+        address_sum: u32,
+        source_port: u16,
+        remote_port: u16,
+        buffer: &mut [u8],
+        tcp_seq: &mut u32,
+    ) -> Option<usize> {
+        let segment = buffer.get_mut(.. self.segment_len)?;
+
+        *tcp_seq = tcp_seq.wrapping_add(1);
+
+        segment.copy_from_slice(&self.segment[.. self.segment_len]);
+        segment[0 .. 2].copy_from_slice(&source_port.to_be_bytes());
+        segment[2 .. 4].copy_from_slice(&remote_port.to_be_bytes());
+        segment[4 .. 8].copy_from_slice(&tcp_seq.to_be_bytes());
+
+        let port_sum = source_port as u32 + remote_port as u32;
+        let mut sum = self.partial_sum + address_sum + port_sum + (*tcp_seq >> 16) + (*tcp_seq & 0xFFFF);
+
+        while sum >> 16 != 0 {
+            sum = (sum >> 16) + (sum & 0xFFFF);
+        }
+
+        segment[TCP_CHECKSUM_OFFSET .. TCP_CHECKSUM_OFFSET + 2].copy_from_slice(&(!sum as u16).to_be_bytes());
+
+        Some(self.segment_len)
+    }
+}
+
+/// Writes the options to the front of the buffer, padded out to a multiple of 4 bytes with EOLs, and returns their length
+fn write_tcp_options(options: &[SynOption], buffer: &mut [u8]) -> usize {
+    // every probe of a scan carries the same timestamp, as if they were all sent the same moment
+    let timestamp = rand::random::<u32>();
+    let mut options_len = 0;
+
+    for option in options {
+        let option_buffer = &mut buffer[options_len .. options_len + option.size()];
+
+        match option {
+            SynOption::Eol => option_buffer[0] = 0,
+            SynOption::Nop => option_buffer[0] = 1,
+            SynOption::Mss(mss) => {
+                option_buffer[.. 2].copy_from_slice(&[2, 4]);
+                option_buffer[2 ..].copy_from_slice(&mss.to_be_bytes());
+            }
+            SynOption::WindowScale(shift) => option_buffer.copy_from_slice(&[3, 3, *shift]),
+            SynOption::SackPermitted => option_buffer.copy_from_slice(&[4, 2]),
+            SynOption::Timestamps => {
+                option_buffer[.. 2].copy_from_slice(&[8, 10]);
+                // the echo reply is left at 0, there being no timestamp to echo yet
+                option_buffer[2 .. 6].copy_from_slice(&timestamp.to_be_bytes());
+            }
+        }
+
+        options_len += option.size();
+    }
+
+    // the padding is already zeroed, and zero is EOL
+    (options_len + 3) & !3
+}
+
+/// Adds up the buffer as big endian 16 bit words, without folding the carries back in
+fn sum_words(buffer: &[u8]) -> u32 {
+    buffer
+        .chunks(2)
+        .map(|word| match word {
+            [high, low] => u16::from_be_bytes([*high, *low]) as u32,
+            [high] => (*high as u32) << 8,
+            _ => 0,
+        })
+        .sum()
+}
+
+// sctp common header (12 bytes) + an INIT chunk without any optional parameters (20 bytes)
+const SCTP_COMMON_HEADER_LEN: usize = 12;
+const SCTP_INIT_CHUNK_LEN: usize = 20;
+const SCTP_INIT_PACKET_LEN: usize = SCTP_COMMON_HEADER_LEN + SCTP_INIT_CHUNK_LEN;
+
+pub(crate) const SCTP_CHUNK_INIT: u8 = 1;
+pub(crate) const SCTP_CHUNK_INIT_ACK: u8 = 2;
+pub(crate) const SCTP_CHUNK_ABORT: u8 = 6;
+
+/// A fully built SCTP packet. pnet doesn't ship an SCTP packet type, so this only exposes the raw bytes for sending.
+pub(crate) struct SctpPacket<'b> {
+    buffer: &'b [u8],
+}
+
+impl<'b> pnet::packet::Packet for SctpPacket<'b> {
+    fn packet(&self) -> &[u8] { self.buffer }
+
+    fn payload(&self) -> &[u8] { &self.buffer[SCTP_COMMON_HEADER_LEN ..] }
+}
+
+/// Creates an SCTP packet carrying a single INIT chunk.
+/// Unlike TCP, the SCTP checksum doesn't cover a pseudo-header so this works for both IPv4 and IPv6.
+pub(crate) fn create_sctp_init_packet<'b>(
     source_port: u16,
     remote_port: u16,
     buffer: &'b mut [u8],
-    tcp_seq: &mut u32,
-) -> Option<MutableTcpPacket<'b>> {
-    use pnet::packet::Packet;
+    initiate_tag: &mut u32,
+) -> Option<SctpPacket<'b>> {
+    let buffer = buffer.get_mut(.. SCTP_INIT_PACKET_LEN)?;
+
+    // the initiate tag must never be 0
+    *initiate_tag = initiate_tag.wrapping_add(1).max(1);
+
+    // common header. The verification tag of an INIT is always 0
+    buffer[0 .. 2].copy_from_slice(&source_port.to_be_bytes());
+    buffer[2 .. 4].copy_from_slice(&remote_port.to_be_bytes());
+    buffer[4 .. 8].copy_from_slice(&0u32.to_be_bytes());
+    buffer[8 .. 12].copy_from_slice(&0u32.to_be_bytes());
+
+    // INIT chunk
+    let chunk = &mut buffer[SCTP_COMMON_HEADER_LEN ..];
+    chunk[0] = SCTP_CHUNK_INIT;
+    chunk[1] = 0;
+    chunk[2 .. 4].copy_from_slice(&(SCTP_INIT_CHUNK_LEN as u16).to_be_bytes());
+    chunk[4 .. 8].copy_from_slice(&initiate_tag.to_be_bytes());
+    // advertised receiver window credit
+    chunk[8 .. 12].copy_from_slice(&65_535u32.to_be_bytes());
+    // number of outbound and maximum inbound streams
+    chunk[12 .. 14].copy_from_slice(&10u16.to_be_bytes());
+    chunk[14 .. 16].copy_from_slice(&2_048u16.to_be_bytes());
+    // initial TSN
+    chunk[16 .. 20].copy_from_slice(&initiate_tag.to_be_bytes());
 
-    let mut tcp_packet = create_syn_tcp_packet_inner(source_port, remote_port, buffer, tcp_seq)?;
+    // the crc32c is computed with the checksum field zeroed and is stored little endian (RFC 4960 appendix B)
+    let checksum = crc32c(buffer);
+    buffer[8 .. 12].copy_from_slice(&checksum.to_le_bytes());
 
-    let checksum = pnet::util::ipv4_checksum(
-        tcp_packet.packet(),
-        8,
-        &[],
-        source_ip,
-        remote_ip,
-        IpNextHeaderProtocols::Tcp,
-    );
-    tcp_packet.set_checksum(checksum);
+    Some(SctpPacket { buffer })
+}
+
+/// Parses the ports and first chunk type out of an SCTP packet
+/// Returns `(source port, destination port, chunk type)`
+pub(crate) fn parse_sctp_packet(buffer: &[u8]) -> Option<(u16, u16, u8)> {
+    // we need the common header and at least the chunk type of the first chunk
+    if buffer.len() <= SCTP_COMMON_HEADER_LEN {
+        return None;
+    }
+
+    let source_port = u16::from_be_bytes([buffer[0], buffer[1]]);
+    let destination_port = u16::from_be_bytes([buffer[2], buffer[3]]);
+    let chunk_type = buffer[SCTP_COMMON_HEADER_LEN];
 
-    Some(tcp_packet)
+    Some((source_port, destination_port, chunk_type))
 }
 
-pub(crate) fn create_syn_tcp_packet_v6<'b>(
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    // reflected castagnoli polynomial
+    const POLYNOMIAL: u32 = 0x82F6_3B78;
+
+    let mut table = [0u32; 256];
+    let mut idx = 0;
+
+    while idx < 256 {
+        let mut crc = idx as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+
+        table[idx] = crc;
+        idx += 1;
+    }
+
+    table
+}
+
+fn crc32c(buffer: &[u8]) -> u32 {
+    !buffer.iter().fold(!0u32, |crc, byte| {
+        CRC32C_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+pub(crate) const IPV4_HEADER_LEN: usize = 20;
+pub(crate) const IPV6_HEADER_LEN: usize = 40;
+// what the kernel would have used (net.ipv4.ip_default_ttl and net.ipv6.conf.*.hop_limit)
+const DEFAULT_TTL: u8 = 64;
+const DEFAULT_HOP_LIMIT: u8 = 64;
+
+/// Writes the IP header in front of each segment for transports that don't leave it to the kernel, counting up the
+/// IPv4 identification from probe to probe unless the scan fixes it
+pub(crate) struct IpPacketWriter {
+    ip_header: IpHeader,
+    identification: u16,
+}
+
+impl IpPacketWriter {
+    pub(crate) fn new() -> Self {
+        Self {
+            ip_header: IpHeader::default(),
+            identification: rand::random(),
+        }
+    }
+
+    /// Uses the header fields of the next scan from here on
+    pub(crate) fn set_ip_header(&mut self, ip_header: IpHeader) { self.ip_header = ip_header; }
+
+    /// Writes the IP header followed by the segment to the buffer and returns the length of the packet, or `None` if
+    /// it doesn't fit or the addresses are of different families
+    pub(crate) fn write_packet(
+        &mut self,
+        buffer: &mut [u8],
+        segment: &[u8],
+        protocol: IpNextHeaderProtocol,
+        source: IpAddr,
+        destination: IpAddr,
+    ) -> Option<usize> {
+        let ip_header_len = match (source, destination) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                self.identification = self.identification.wrapping_add(1);
+
+                write_ipv4_header(buffer, &source, &destination, protocol, segment.len(), self.identification, &self.ip_header)
+            }
+            (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                write_ipv6_header(buffer, &source, &destination, protocol, segment.len(), &self.ip_header)
+            }
+            _ => None,
+        }?;
+
+        let packet_len = ip_header_len + segment.len();
+        buffer.get_mut(ip_header_len .. packet_len)?.copy_from_slice(segment);
+
+        Some(packet_len)
+    }
+}
+
+/// Writes an IPv4 header for a segment of `payload_len` bytes to the front of the buffer, with the fields `ip_header`
+/// sets. `identification` is used unless it sets one. Returns the length of the header.
+pub(crate) fn write_ipv4_header(
+    buffer: &mut [u8],
+    source_ip: &Ipv4Addr,
+    remote_ip: &Ipv4Addr,
+    protocol: IpNextHeaderProtocol,
+    payload_len: usize,
+    identification: u16,
+    ip_header: &IpHeader,
+) -> Option<usize> {
+    use pnet::packet::ipv4::{checksum, Ipv4Flags, MutableIpv4Packet};
+
+    let mut ip_packet = MutableIpv4Packet::new(buffer.get_mut(.. IPV4_HEADER_LEN)?)?;
+    let tos = ip_header.tos().unwrap_or(0);
+
+    ip_packet.set_version(4);
+    ip_packet.set_header_length((IPV4_HEADER_LEN / 4) as u8);
+    ip_packet.set_dscp(tos >> 2);
+    ip_packet.set_ecn(tos & 0b11);
+    ip_packet.set_total_length((IPV4_HEADER_LEN + payload_len) as u16);
+    ip_packet.set_identification(ip_header.identification().unwrap_or(identification));
+    ip_packet.set_flags(match ip_header.dont_fragment().unwrap_or(true) {
+        true => Ipv4Flags::DontFragment,
+        false => 0,
+    });
+    ip_packet.set_fragment_offset(0);
+    ip_packet.set_ttl(ip_header.ttl().unwrap_or(DEFAULT_TTL));
+    ip_packet.set_next_level_protocol(protocol);
+    ip_packet.set_source(*source_ip);
+    ip_packet.set_destination(*remote_ip);
+    ip_packet.set_checksum(0);
+
+    let checksum = checksum(&ip_packet.to_immutable());
+    ip_packet.set_checksum(checksum);
+
+    Some(IPV4_HEADER_LEN)
+}
+
+/// Writes an IPv6 header for a segment of `payload_len` bytes to the front of the buffer, with the fields `ip_header`
+/// sets. Returns the length of the header.
+pub(crate) fn write_ipv6_header(
+    buffer: &mut [u8],
     source_ip: &Ipv6Addr,
     remote_ip: &Ipv6Addr,
-    source_port: u16,
-    remote_port: u16,
-    buffer: &'b mut [u8],
-    tcp_seq: &mut u32,
-) -> Option<MutableTcpPacket<'b>> {
-    use pnet::packet::Packet;
+    protocol: IpNextHeaderProtocol,
+    payload_len: usize,
+    ip_header: &IpHeader,
+) -> Option<usize> {
+    use pnet::packet::ipv6::MutableIpv6Packet;
 
-    let mut tcp_packet = create_syn_tcp_packet_inner(source_port, remote_port, buffer, tcp_seq)?;
+    let mut ip_packet = MutableIpv6Packet::new(buffer.get_mut(.. IPV6_HEADER_LEN)?)?;
 
-    let checksum = pnet::util::ipv6_checksum(
-        tcp_packet.packet(),
-        8,
-        &[],
-        source_ip,
-        remote_ip,
-        IpNextHeaderProtocols::Tcp,
-    );
-    tcp_packet.set_checksum(checksum);
+    ip_packet.set_version(6);
+    ip_packet.set_traffic_class(ip_header.tos().unwrap_or(0));
+    ip_packet.set_flow_label(0);
+    ip_packet.set_payload_length(payload_len as u16);
+    ip_packet.set_next_header(protocol);
+    ip_packet.set_hop_limit(ip_header.hop_limit().unwrap_or(DEFAULT_HOP_LIMIT));
+    ip_packet.set_source(*source_ip);
+    ip_packet.set_destination(*remote_ip);
 
-    Some(tcp_packet)
+    Some(IPV6_HEADER_LEN)
 }
 
-fn create_syn_tcp_packet_inner<'b>(
-    source_port: u16,
-    remote_port: u16,
-    buffer: &'b mut [u8],
-    tcp_seq: &mut u32,
-) -> Option<MutableTcpPacket<'b>> {
-    use pnet::packet::tcp::TcpFlags::SYN;
-    use pnet::packet::tcp::TcpOption;
-
-    // inc 2?
-    *tcp_seq += 1;
-
-    let mut tcp_packet = MutableTcpPacket::new(buffer)?;
-
-    tcp_packet.set_source(source_port);
-    tcp_packet.set_destination(remote_port);
-    tcp_packet.set_sequence(*tcp_seq);
-    tcp_packet.set_acknowledgement(0);
-    tcp_packet.set_window(1024);
-    tcp_packet.set_data_offset(8);
-    tcp_packet.set_flags(SYN);
-    tcp_packet.set_options(&[TcpOption::mss(1460)]);
-
-    Some(tcp_packet)
+// IPv6 extension headers (RFC 8200 and friends) we can see past
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_AUTHENTICATION: u8 = 51;
+const IPV6_DESTINATION_OPTIONS: u8 = 60;
+const IPV6_MOBILITY: u8 = 135;
+const IPV6_HOST_IDENTITY: u8 = 139;
+const IPV6_SHIM6: u8 = 140;
+const IPV6_FRAGMENT_HEADER_LEN: usize = 8;
+
+/// A received packet that doesn't hold together, e.g. a runt or one whose header lengths or checksum are off
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct MalformedPacket;
+
+/// Where the transport layer segment of a received IP packet lies, what it holds and where it came from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct IpPayload {
+    pub(crate) protocol: IpNextHeaderProtocol,
+    pub(crate) segment: Range<usize>,
+    pub(crate) source: IpAddr,
+}
+
+/// Checks a received IPv4 packet's version, header length, total length and header checksum, and finds its segment.
+/// A `truncated` packet was cut short by our receive buffer, so its total length may run past the end of it and the
+/// segment is whatever's left. Packets that are valid but carry no segment we can read, i.e. fragments past the first,
+/// come out as `None`.
+pub(crate) fn parse_ipv4(packet: &[u8], truncated: bool) -> Result<Option<IpPayload>, MalformedPacket> {
+    if packet.len() < IPV4_HEADER_LEN || packet[0] >> 4 != 4 {
+        return Err(MalformedPacket);
+    }
+
+    let header_len = (packet[0] & 0x0f) as usize * 4;
+    let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+
+    if header_len < IPV4_HEADER_LEN || header_len > packet.len() || total_len < header_len {
+        return Err(MalformedPacket);
+    }
+
+    if total_len > packet.len() && !truncated {
+        return Err(MalformedPacket);
+    }
+
+    // a correct header, checksum included, adds up to all ones
+    let mut sum = sum_words(&packet[.. header_len]);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    if sum != 0xffff {
+        return Err(MalformedPacket);
+    }
+
+    let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+
+    if fragment_offset != 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(IpPayload {
+        protocol: IpNextHeaderProtocol::new(packet[9]),
+        segment: header_len .. total_len.min(packet.len()),
+        source: IpAddr::V4(Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15])),
+    }))
+}
+
+/// Checks a received IPv6 packet's version and payload length and walks its extension headers to find the segment.
+/// `truncated` is as for `parse_ipv4`. Packets that are valid but carry no segment we can read, i.e. fragments past the
+/// first or ones whose headers run past what we received, come out as `None`. Encrypted (ESP) payloads and packets
+/// with no next header come out as those protocols, which we never scan.
+pub(crate) fn parse_ipv6(packet: &[u8], truncated: bool) -> Result<Option<IpPayload>, MalformedPacket> {
+    if packet.len() < IPV6_HEADER_LEN || packet[0] >> 4 != 6 {
+        return Err(MalformedPacket);
+    }
+
+    // jumbograms, whose payload length is zero, don't fit any link we receive on
+    let declared_end = IPV6_HEADER_LEN + u16::from_be_bytes([packet[4], packet[5]]) as usize;
+
+    if declared_end > packet.len() && !truncated {
+        return Err(MalformedPacket);
+    }
+
+    let received_end = declared_end.min(packet.len());
+    let mut next_header = packet[6];
+    let mut offset = IPV6_HEADER_LEN;
+
+    // every extension header is at least 8 bytes long, so this always comes to an end
+    loop {
+        let header_len = match next_header {
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DESTINATION_OPTIONS | IPV6_MOBILITY | IPV6_HOST_IDENTITY
+            | IPV6_SHIM6 => {
+                // in 8 byte units, not counting the first 8
+                packet.get(offset + 1).map(|len| (*len as usize + 1) * 8)
+            }
+            // in 4 byte units, not counting the first 8
+            IPV6_AUTHENTICATION => packet.get(offset + 1).map(|len| (*len as usize + 2) * 4),
+            IPV6_FRAGMENT => Some(IPV6_FRAGMENT_HEADER_LEN),
+            _ => break,
+        };
+
+        let header = match header_len {
+            Some(header_len) if offset + header_len <= received_end => &packet[offset .. offset + header_len],
+            Some(header_len) if offset + header_len > declared_end => return Err(MalformedPacket),
+            // it's all there on the wire, we just didn't receive enough of it
+            _ if truncated => return Ok(None),
+            _ => return Err(MalformedPacket),
+        };
+
+        if next_header == IPV6_FRAGMENT && u16::from_be_bytes([header[2], header[3]]) >> 3 != 0 {
+            return Ok(None);
+        }
+
+        next_header = header[0];
+        offset += header.len();
+    }
+
+    let mut source = [0u8; 16];
+    source.copy_from_slice(&packet[8 .. 24]);
+
+    Ok(Some(IpPayload {
+        protocol: IpNextHeaderProtocol::new(next_header),
+        segment: offset .. received_end,
+        source: IpAddr::V6(Ipv6Addr::from(source)),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::armada::config::scan_type::ScanType;
+    use crate::armada::work::PortState;
+    use pnet::packet::tcp::TcpPacket;
+
+    // the tcp header a SYN probe of the profile is built from, with the random timestamp value (if any) zeroed out
+    fn syn_header(syn_profile: &SynProfile) -> Vec<u8> {
+        use pnet::packet::tcp::TcpFlags::SYN;
+
+        let template = TcpProbeTemplate::new(SYN, syn_profile);
+        let mut header = template.segment[.. template.segment_len].to_vec();
+
+        if let Some(timestamp) = header[TCP_HEADER_LEN ..].windows(2).position(|option| option == [8, 10]) {
+            let value = TCP_HEADER_LEN + timestamp + 2;
+            header[value .. value + 4].fill(0);
+        }
+
+        header
+    }
+
+    #[test]
+    fn syn_profiles_carry_their_exact_options() {
+        let presets: [(SynProfile, u16, &[u8]); 4] = [
+            (SynProfile::armada(), 1024, &[2, 4, 0x05, 0xB4, 0, 0, 0, 0, 0, 0, 0, 0]),
+            (
+                SynProfile::linux(),
+                64240,
+                &[2, 4, 0x05, 0xB4, 4, 2, 8, 10, 0, 0, 0, 0, 0, 0, 0, 0, 1, 3, 3, 7],
+            ),
+            (SynProfile::windows(), 64240, &[2, 4, 0x05, 0xB4, 1, 3, 3, 8, 1, 1, 4, 2]),
+            (
+                SynProfile::macos(),
+                65535,
+                &[2, 4, 0x05, 0xB4, 1, 3, 3, 6, 1, 1, 8, 10, 0, 0, 0, 0, 0, 0, 0, 0, 4, 2, 0, 0],
+            ),
+        ];
+
+        for (syn_profile, window, options) in presets {
+            let header = syn_header(&syn_profile);
+            let tcp_packet = pnet::packet::tcp::TcpPacket::new(&header).unwrap();
+
+            assert_eq!(&header[TCP_HEADER_LEN ..], options, "{:?}", syn_profile);
+            assert_eq!(tcp_packet.get_data_offset() as usize * 4, header.len());
+            assert_eq!(tcp_packet.get_window(), window);
+        }
+    }
+
+    #[test]
+    fn only_syn_probes_carry_the_profile() {
+        use pnet::packet::tcp::TcpFlags::ACK;
+
+        let template = TcpProbeTemplate::new(ACK, &SynProfile::linux());
+        let tcp_packet = pnet::packet::tcp::TcpPacket::new(&template.segment[.. template.segment_len]).unwrap();
+
+        assert_eq!(template.segment_len, TCP_HEADER_LEN);
+        assert_eq!(tcp_packet.get_data_offset(), 5);
+        assert_eq!(tcp_packet.get_window(), 1024);
+    }
+
+    #[test]
+    fn adjusted_profiles_keep_within_the_header() {
+        let options = vec![SynOption::Timestamps; 4];
+        assert!(SynProfile::new().with_options(options.clone()).is_ok());
+
+        // one byte more than a tcp header has room for
+        let mut too_long = options;
+        too_long.push(SynOption::Nop);
+        assert!(SynProfile::new().with_options(too_long).is_err());
+
+        // the MSS takes the place of the one there, or else goes ahead of the other options
+        let header = syn_header(&SynProfile::windows().with_mss(1400).unwrap());
+        assert_eq!(header[TCP_HEADER_LEN .. TCP_HEADER_LEN + 4], [2, 4, 0x05, 0x78]);
+        assert_eq!(header.len(), 32);
+
+        let profile = SynProfile::new().with_options(vec![SynOption::SackPermitted]).unwrap().with_mss(536).unwrap();
+        assert_eq!(profile.options(), [SynOption::Mss(536), SynOption::SackPermitted]);
+
+        // 38 bytes of options leave no room for an MSS to go ahead of them
+        let nearly_full = [vec![SynOption::Timestamps; 3], vec![SynOption::Nop; 8]].concat();
+        assert!(SynProfile::new().with_options(nearly_full).unwrap().with_mss(1460).is_err());
+    }
+
+    const PROBE_FLAGS: [u16; 5] = {
+        use pnet::packet::tcp::TcpFlags::{ACK, FIN, PSH, SYN, URG};
+
+        [SYN, ACK, FIN, 0, FIN | PSH | URG]
+    };
+
+    // a probe built field by field with the template's flags, window and options, then checksummed in full
+    fn reference_probe(
+        template: &TcpProbeTemplate,
+        source_ip: IpAddr,
+        source_port: u16,
+        remote_ip: IpAddr,
+        remote_port: u16,
+        tcp_seq: u32,
+    ) -> Vec<u8> {
+        let mut buffer = template.segment[.. template.segment_len].to_vec();
+        let flags = TcpPacket::new(&buffer).unwrap().get_flags();
+        let window = TcpPacket::new(&buffer).unwrap().get_window();
+
+        // only the options are kept from the template
+        buffer[.. TCP_HEADER_LEN].fill(0);
+
+        let mut tcp_packet = MutableTcpPacket::new(&mut buffer).unwrap();
+        tcp_packet.set_source(source_port);
+        tcp_packet.set_destination(remote_port);
+        tcp_packet.set_sequence(tcp_seq);
+        tcp_packet.set_window(window);
+        tcp_packet.set_data_offset((template.segment_len / 4) as u8);
+        tcp_packet.set_flags(flags);
+
+        let checksum = match (source_ip, remote_ip) {
+            (IpAddr::V4(source_ip), IpAddr::V4(remote_ip)) => {
+                pnet::util::ipv4_checksum(&buffer, 8, &[], &source_ip, &remote_ip, IpNextHeaderProtocols::Tcp)
+            }
+            (IpAddr::V6(source_ip), IpAddr::V6(remote_ip)) => {
+                pnet::util::ipv6_checksum(&buffer, 8, &[], &source_ip, &remote_ip, IpNextHeaderProtocols::Tcp)
+            }
+            _ => unreachable!(),
+        };
+        buffer[TCP_CHECKSUM_OFFSET .. TCP_CHECKSUM_OFFSET + 2].copy_from_slice(&checksum.to_be_bytes());
+
+        buffer
+    }
+
+    fn template_probe(
+        template: &TcpProbeTemplate,
+        source_ip: IpAddr,
+        source_port: u16,
+        remote_ip: IpAddr,
+        remote_port: u16,
+        tcp_seq: &mut u32,
+    ) -> Vec<u8> {
+        let mut buffer = [0u8; MAX_PROBE_PACKET_LEN];
+
+        let len = match (source_ip, remote_ip) {
+            (IpAddr::V4(source_ip), IpAddr::V4(remote_ip)) => {
+                template.write_v4(&source_ip, source_port, &remote_ip, remote_port, &mut buffer, tcp_seq)
+            }
+            (IpAddr::V6(source_ip), IpAddr::V6(remote_ip)) => {
+                template.write_v6(&source_ip, source_port, &remote_ip, remote_port, &mut buffer, tcp_seq)
+            }
+            _ => unreachable!(),
+        };
+
+        buffer[.. len.unwrap()].to_vec()
+    }
+
+    // the one's complement sum of the segment and its pseudo-header, which comes to 0xFFFF for a valid checksum
+    fn verify_sum(segment: &[u8], source_ip: IpAddr, remote_ip: IpAddr) -> u16 {
+        let address_sum = match (source_ip, remote_ip) {
+            (IpAddr::V4(source_ip), IpAddr::V4(remote_ip)) => {
+                sum_words(&source_ip.octets()) + sum_words(&remote_ip.octets())
+            }
+            (IpAddr::V6(source_ip), IpAddr::V6(remote_ip)) => {
+                sum_words(&source_ip.octets()) + sum_words(&remote_ip.octets())
+            }
+            _ => unreachable!(),
+        };
+        let mut sum = sum_words(segment) + address_sum + IpNextHeaderProtocols::Tcp.0 as u32 + segment.len() as u32;
+
+        while sum >> 16 != 0 {
+            sum = (sum >> 16) + (sum & 0xFFFF);
+        }
+
+        sum as u16
+    }
+
+    fn random_ip(ipv6: bool) -> IpAddr {
+        match ipv6 {
+            true => IpAddr::V6(Ipv6Addr::from(rand::random::<u128>())),
+            false => IpAddr::V4(Ipv4Addr::from(rand::random::<u32>())),
+        }
+    }
+
+    #[test]
+    fn templated_probes_match_a_full_rebuild() {
+        for syn_profile in [SynProfile::armada(), SynProfile::linux(), SynProfile::windows(), SynProfile::macos()] {
+            for flags in PROBE_FLAGS {
+                let template = TcpProbeTemplate::new(flags, &syn_profile);
+
+                for ipv6 in [false, true] {
+                    for _ in 0 .. 1000 {
+                        let (source_ip, remote_ip) = (random_ip(ipv6), random_ip(ipv6));
+                        let (source_port, remote_port) = (rand::random(), rand::random());
+                        let mut tcp_seq = rand::random::<u32>();
+
+                        let probe =
+                            template_probe(&template, source_ip, source_port, remote_ip, remote_port, &mut tcp_seq);
+                        let reference =
+                            reference_probe(&template, source_ip, source_port, remote_ip, remote_port, tcp_seq);
+
+                        assert_eq!(probe, reference);
+                        assert_eq!(verify_sum(&probe, source_ip, remote_ip), 0xFFFF);
+                    }
+                }
+            }
+        }
+    }
+
+    // (source, source port, remote, remote port, sequence number, segment)
+    type PinnedProbe = (&'static str, u16, &'static str, u16, u32, [u8; 32]);
+
+    // SYN probes the way the builders from before there was a template wrote them: a window of 1024, a data offset of 8
+    // and an MSS of 1460 followed by 8 bytes of padding
+    #[rustfmt::skip]
+    const PRE_TEMPLATE_SYN_PROBES: [PinnedProbe; 7] = [
+        ("10.0.0.1", 50000, "192.0.2.1", 443, 0x12345678, [
+            0xC3, 0x50, 0x01, 0xBB, 0x12, 0x34, 0x56, 0x78, 0x00, 0x00, 0x00, 0x00, 0x80, 0x02, 0x04, 0x00,
+            0x7A, 0x64, 0x00, 0x00, 0x02, 0x04, 0x05, 0xB4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]),
+        ("255.255.255.255", 65535, "255.255.255.255", 65535, 0xFFFFFFFF, [
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x80, 0x02, 0x04, 0x00,
+            0x74, 0x1F, 0x00, 0x00, 0x02, 0x04, 0x05, 0xB4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]),
+        ("0.0.0.0", 0, "0.0.0.0", 0, 1, [
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x80, 0x02, 0x04, 0x00,
+            0x74, 0x1E, 0x00, 0x00, 0x02, 0x04, 0x05, 0xB4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]),
+        // the sum folds to 0xFFFF, for a checksum of 0x0000
+        ("10.0.0.1", 50000, "192.0.2.1", 443, 0xE310, [
+            0xC3, 0x50, 0x01, 0xBB, 0x00, 0x00, 0xE3, 0x10, 0x00, 0x00, 0x00, 0x00, 0x80, 0x02, 0x04, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x02, 0x04, 0x05, 0xB4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]),
+        ("fd01::2", 50000, "2001:db8::1", 443, 0x12345678, [
+            0xC3, 0x50, 0x01, 0xBB, 0x12, 0x34, 0x56, 0x78, 0x00, 0x00, 0x00, 0x00, 0x80, 0x02, 0x04, 0x00,
+            0x1B, 0xA9, 0x00, 0x00, 0x02, 0x04, 0x05, 0xB4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]),
+        ("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff", 65535,
+         "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff", 65535, 0xFFFFFFFF, [
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x80, 0x02, 0x04, 0x00,
+            0x74, 0x1F, 0x00, 0x00, 0x02, 0x04, 0x05, 0xB4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]),
+        ("fd01::2", 50000, "2001:db8::1", 443, 0x8455, [
+            0xC3, 0x50, 0x01, 0xBB, 0x00, 0x00, 0x84, 0x55, 0x00, 0x00, 0x00, 0x00, 0x80, 0x02, 0x04, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x02, 0x04, 0x05, 0xB4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]),
+    ];
+
+    #[test]
+    fn armada_syn_probes_match_the_pre_template_builders() {
+        use pnet::packet::tcp::TcpFlags::SYN;
+
+        let template = TcpProbeTemplate::new(SYN, &SynProfile::armada());
+
+        for (source_ip, source_port, remote_ip, remote_port, sequence, expected) in PRE_TEMPLATE_SYN_PROBES {
+            let (source_ip, remote_ip) = (source_ip.parse().unwrap(), remote_ip.parse().unwrap());
+            // the sequence number is advanced before it's written
+            let mut tcp_seq = sequence - 1;
+            let probe = template_probe(&template, source_ip, source_port, remote_ip, remote_port, &mut tcp_seq);
+
+            assert_eq!(tcp_seq, sequence);
+            assert_eq!(probe, expected, "{} -> {}", source_ip, remote_ip);
+        }
+    }
+
+    #[test]
+    fn templated_checksums_hold_at_the_edges() {
+        use pnet::packet::tcp::TcpFlags::SYN;
+
+        let template = TcpProbeTemplate::new(SYN, &SynProfile::linux());
+        let addrs = [
+            (IpAddr::V4(Ipv4Addr::new(0xFF, 0xFF, 0xFF, 0xFF)), IpAddr::V4(Ipv4Addr::new(0xFF, 0xFF, 0xFF, 0xFF))),
+            (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))),
+            (IpAddr::V6(Ipv6Addr::from(u128::MAX)), IpAddr::V6(Ipv6Addr::from(u128::MAX))),
+            (IpAddr::V6("fd01::2".parse().unwrap()), IpAddr::V6("2001:db8::1".parse().unwrap())),
+        ];
+
+        for (source_ip, remote_ip) in addrs {
+            for port in [0, u16::MAX] {
+                // the sequence number wrapping around to 0, starting out at 0, filling its low word and carrying out
+                // of it
+                for (previous_seq, sequence) in [(u32::MAX, 0), (0, 1), (0xFFFE, 0xFFFF), (0xFFFF, 0x1_0000)] {
+                    let mut tcp_seq = previous_seq;
+                    let probe = template_probe(&template, source_ip, port, remote_ip, port, &mut tcp_seq);
+                    let checksum = u16::from_be_bytes([probe[TCP_CHECKSUM_OFFSET], probe[TCP_CHECKSUM_OFFSET + 1]]);
+
+                    assert_eq!(tcp_seq, sequence);
+                    assert_eq!(probe, reference_probe(&template, source_ip, port, remote_ip, port, sequence));
+                    assert_eq!(verify_sum(&probe, source_ip, remote_ip), 0xFFFF);
+                    // anything but an all zero segment sums to non-zero, so 0xFFFF is never a checksum
+                    assert_ne!(checksum, 0xFFFF);
+                }
+            }
+        }
+    }
+
+    // replies captured off a veth pair between two Linux 6.18 network namespaces, from 10.200.0.1 and fd01::1 to connects
+    // and datagrams from 10.200.0.2 and fd01::2, everything from the IP header on
+
+    // a SYN-ACK from port 9100, with MSS, SACK permitted, timestamps and window scale
+    #[rustfmt::skip]
+    const CAPTURED_IPV4_SYN_ACK: &[u8] = &[
+        0x45, 0x00, 0x00, 0x3C, 0x00, 0x00, 0x40, 0x00, 0x40, 0x06, 0x25, 0x2A, 0x0A, 0xC8, 0x00, 0x01,
+        0x0A, 0xC8, 0x00, 0x02, 0x23, 0x8C, 0xE8, 0xAA, 0x13, 0x2C, 0xF1, 0x10, 0x0F, 0xB0, 0x3E, 0x94,
+        0xA0, 0x12, 0xFE, 0x88, 0x3F, 0x0C, 0x00, 0x00, 0x02, 0x04, 0x05, 0xB4, 0x04, 0x02, 0x08, 0x0A,
+        0x1D, 0x00, 0x32, 0xF8, 0xD5, 0x68, 0x70, 0xAC, 0x01, 0x03, 0x03, 0x0A,
+    ];
+
+    // a RST from closed port 9101
+    #[rustfmt::skip]
+    const CAPTURED_IPV4_RST: &[u8] = &[
+        0x45, 0x00, 0x00, 0x28, 0x00, 0x00, 0x40, 0x00, 0x40, 0x06, 0x25, 0x3E, 0x0A, 0xC8, 0x00, 0x01,
+        0x0A, 0xC8, 0x00, 0x02, 0x23, 0x8D, 0xAD, 0xC0, 0x00, 0x00, 0x00, 0x00, 0x5B, 0x4D, 0x61, 0xF8,
+        0x50, 0x14, 0x00, 0x00, 0x0B, 0xAB, 0x00, 0x00,
+    ];
+
+    // a SYN-ACK from port 9100 echoing the record route option of the SYN, with both hops filled in
+    #[rustfmt::skip]
+    const CAPTURED_IPV4_SYN_ACK_WITH_RECORD_ROUTE: &[u8] = &[
+        0x48, 0x00, 0x00, 0x48, 0x00, 0x00, 0x40, 0x00, 0x40, 0x06, 0x7B, 0xFD, 0x0A, 0xC8, 0x00, 0x01,
+        0x0A, 0xC8, 0x00, 0x02, 0x07, 0x0B, 0x0C, 0x0A, 0xC8, 0x00, 0x02, 0x0A, 0xC8, 0x00, 0x01, 0x00,
+        0x23, 0x8C, 0xE8, 0xB4, 0x4A, 0x1F, 0x00, 0xFA, 0x8E, 0xDC, 0xB2, 0x3A, 0xA0, 0x12, 0xFE, 0x88,
+        0xE3, 0x81, 0x00, 0x00, 0x02, 0x04, 0x05, 0xB4, 0x04, 0x02, 0x08, 0x0A, 0xFA, 0x27, 0x56, 0x53,
+        0x07, 0x8C, 0x5F, 0xD7, 0x01, 0x03, 0x03, 0x0A,
+    ];
+
+    // the first 48 bytes of the first fragment of a UDP datagram to port 9200
+    #[rustfmt::skip]
+    const CAPTURED_IPV4_FIRST_FRAGMENT: &[u8] = &[
+        0x45, 0x00, 0x05, 0xDC, 0xAE, 0x9A, 0x20, 0x00, 0x40, 0x11, 0x90, 0xE4, 0x0A, 0xC8, 0x00, 0x01,
+        0x0A, 0xC8, 0x00, 0x02, 0x94, 0x57, 0x23, 0xF0, 0x05, 0xD8, 0x65, 0x25, 0x00, 0x01, 0x02, 0x03,
+        0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F, 0x10, 0x11, 0x12, 0x13,
+    ];
+
+    // the last fragment of the same datagram
+    #[rustfmt::skip]
+    const CAPTURED_IPV4_LAST_FRAGMENT: &[u8] = &[
+        0x45, 0x00, 0x00, 0x24, 0xAE, 0x9A, 0x00, 0xB9, 0x40, 0x11, 0xB5, 0xE3, 0x0A, 0xC8, 0x00, 0x01,
+        0x0A, 0xC8, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+
+    // a SYN-ACK from port 9100 of a socket with a hop-by-hop header (router alert) and a destination options header set
+    #[rustfmt::skip]
+    const CAPTURED_IPV6_SYN_ACK_WITH_OPTIONS: &[u8] = &[
+        0x60, 0x04, 0x81, 0xFE, 0x00, 0x38, 0x00, 0x40, 0xFD, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0xFD, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x3C, 0x00, 0x05, 0x02, 0x00, 0x00, 0x01, 0x00,
+        0x06, 0x00, 0x01, 0x04, 0x00, 0x00, 0x00, 0x00, 0x23, 0x8C, 0xEB, 0xEA, 0xE5, 0x1D, 0x42, 0x9C,
+        0x35, 0xFF, 0xE4, 0x96, 0xA0, 0x12, 0xFB, 0x04, 0x04, 0x25, 0x00, 0x00, 0x02, 0x04, 0x05, 0xA0,
+        0x04, 0x02, 0x08, 0x0A, 0xC9, 0x65, 0x49, 0xC9, 0x34, 0x42, 0xB5, 0x98, 0x01, 0x03, 0x03, 0x0A,
+    ];
+
+    // a RST from closed port 9101
+    #[rustfmt::skip]
+    const CAPTURED_IPV6_RST: &[u8] = &[
+        0x60, 0x01, 0x68, 0x11, 0x00, 0x14, 0x06, 0x40, 0xFD, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0xFD, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x23, 0x8D, 0xB1, 0xA0, 0x00, 0x00, 0x00, 0x00,
+        0xD4, 0xF8, 0xA7, 0x23, 0x50, 0x14, 0x00, 0x00, 0x64, 0x80, 0x00, 0x00,
+    ];
+
+    // the first 64 bytes of the first fragment of a UDP datagram to port 9200
+    #[rustfmt::skip]
+    const CAPTURED_IPV6_FIRST_FRAGMENT: &[u8] = &[
+        0x60, 0x03, 0xB4, 0x07, 0x05, 0xB0, 0x2C, 0x40, 0xFD, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0xFD, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x11, 0x00, 0x00, 0x01, 0x6E, 0x30, 0x64, 0x59,
+        0xE1, 0x39, 0x23, 0xF0, 0x05, 0xB8, 0xF5, 0x4D, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    // the last fragment of the same datagram
+    #[rustfmt::skip]
+    const CAPTURED_IPV6_LAST_FRAGMENT: &[u8] = &[
+        0x60, 0x03, 0xB4, 0x07, 0x00, 0x18, 0x2C, 0x40, 0xFD, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0xFD, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x11, 0x00, 0x05, 0xA8, 0x6E, 0x30, 0x64, 0x59,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    const UNASSIGNED: u8 = 253;
+
+    // sums whatever the header length claims, as far as the packet goes
+    fn refill_ipv4_checksum(packet: &mut [u8]) {
+        let header_len = ((packet[0] & 0x0f) as usize * 4).min(packet.len());
+
+        packet[10 .. 12].fill(0);
+        let mut sum = sum_words(&packet[.. header_len]);
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        packet[10 .. 12].copy_from_slice(&(!sum as u16).to_be_bytes());
+    }
+
+    #[test]
+    fn captured_replies_give_up_their_segment() {
+        let source_v4 = IpAddr::V4(Ipv4Addr::new(10, 200, 0, 1));
+        let source_v6 = IpAddr::V6("fd01::1".parse().unwrap());
+
+        // (packet, where its segment starts, its source, what a SYN scan makes of it)
+        let replies = [
+            (CAPTURED_IPV4_SYN_ACK, IPV4_HEADER_LEN, source_v4, (9100, 59562, PortState::Open)),
+            (CAPTURED_IPV4_RST, IPV4_HEADER_LEN, source_v4, (9101, 44480, PortState::Closed)),
+            (
+                CAPTURED_IPV4_SYN_ACK_WITH_RECORD_ROUTE,
+                IPV4_HEADER_LEN + 12,
+                source_v4,
+                (9100, 59572, PortState::Open),
+            ),
+            (CAPTURED_IPV6_SYN_ACK_WITH_OPTIONS, IPV6_HEADER_LEN + 16, source_v6, (9100, 60394, PortState::Open)),
+            (CAPTURED_IPV6_RST, IPV6_HEADER_LEN, source_v6, (9101, 45472, PortState::Closed)),
+        ];
+
+        for (packet, segment_start, source, response) in replies {
+            let payload = match source {
+                IpAddr::V4(_) => parse_ipv4(packet, false),
+                IpAddr::V6(_) => parse_ipv6(packet, false),
+            };
+            let payload = payload.unwrap().unwrap();
+
+            assert_eq!(payload.protocol, IpNextHeaderProtocols::Tcp);
+            assert_eq!(payload.source, source);
+            assert_eq!(payload.segment, segment_start .. packet.len());
+            assert_eq!(ScanType::Syn.interpret_response(&packet[payload.segment]), Some(response));
+        }
+
+        // anything past the total length, e.g. ethernet padding, isn't part of the segment
+        let padded = [CAPTURED_IPV4_RST, &[0; 6]].concat();
+        assert_eq!(parse_ipv4(&padded, false).unwrap().unwrap().segment, IPV4_HEADER_LEN .. 40);
+
+        // protocols we don't know are handed on as they are, for the caller to ignore
+        let mut unassigned = CAPTURED_IPV6_SYN_ACK_WITH_OPTIONS.to_vec();
+        unassigned[IPV6_HEADER_LEN + 8] = UNASSIGNED;
+        let payload = parse_ipv6(&unassigned, false).unwrap().unwrap();
+
+        assert_eq!(payload.protocol, IpNextHeaderProtocol::new(UNASSIGNED));
+        assert_eq!(payload.segment, IPV6_HEADER_LEN + 16 .. unassigned.len());
+    }
+
+    #[test]
+    fn only_first_fragments_give_up_their_segment() {
+        let udp = IpNextHeaderProtocols::Udp;
+
+        // the first fragment still carries the start of the segment, later ones don't
+        let payload = parse_ipv4(CAPTURED_IPV4_FIRST_FRAGMENT, true).unwrap().unwrap();
+        assert_eq!((payload.protocol, payload.segment), (udp, IPV4_HEADER_LEN .. 48));
+        assert_eq!(parse_ipv4(CAPTURED_IPV4_LAST_FRAGMENT, false), Ok(None));
+
+        let payload = parse_ipv6(CAPTURED_IPV6_FIRST_FRAGMENT, true).unwrap().unwrap();
+        assert_eq!((payload.protocol, payload.segment), (udp, IPV6_HEADER_LEN + IPV6_FRAGMENT_HEADER_LEN .. 64));
+        assert_eq!(parse_ipv6(CAPTURED_IPV6_LAST_FRAGMENT, false), Ok(None));
+    }
+
+    #[test]
+    fn truncated_ipv4_packets_keep_what_was_received() {
+        let received = &CAPTURED_IPV4_SYN_ACK[.. 30];
+
+        assert_eq!(parse_ipv4(received, true).unwrap().unwrap().segment, IPV4_HEADER_LEN .. 30);
+        assert_eq!(parse_ipv4(received, false), Err(MalformedPacket));
+    }
+
+    #[test]
+    fn malformed_ipv4_packets_are_rejected() {
+        let packet = CAPTURED_IPV4_RST;
+        let with_header = |edit: &dyn Fn(&mut Vec<u8>)| {
+            let mut packet = packet.to_vec();
+            edit(&mut packet);
+            refill_ipv4_checksum(&mut packet);
+            packet
+        };
+
+        let malformed = [
+            // runts
+            Vec::new(),
+            packet[.. IPV4_HEADER_LEN - 1].to_vec(),
+            // not IPv4 at all
+            with_header(&|packet| packet[0] = 0x65),
+            // a header length short of the fixed header, and one past the end of the packet
+            with_header(&|packet| packet[0] = 0x44),
+            with_header(&|packet| packet[0] = 0x4F),
+            // a total length short of the header, and one past the end of the packet
+            with_header(&|packet| packet[2 .. 4].copy_from_slice(&19u16.to_be_bytes())),
+            with_header(&|packet| packet[2 .. 4].copy_from_slice(&41u16.to_be_bytes())),
+        ];
+
+        for packet in malformed {
+            assert_eq!(parse_ipv4(&packet, false), Err(MalformedPacket), "{:02x?}", packet);
+        }
+
+        // even a truncated packet has to fit its header
+        assert_eq!(parse_ipv4(&with_header(&|packet| packet[0] = 0x4F), true), Err(MalformedPacket));
+
+        let mut bad_checksum = packet.to_vec();
+        bad_checksum[8] -= 1;
+        assert_eq!(parse_ipv4(&bad_checksum, false), Err(MalformedPacket));
+        assert_eq!(parse_ipv4(&bad_checksum, true), Err(MalformedPacket));
+    }
+
+    #[test]
+    fn truncated_ipv6_packets_keep_what_was_received() {
+        let packet = CAPTURED_IPV6_SYN_ACK_WITH_OPTIONS;
+
+        // cut short within the segment, the rest of it is what's there
+        let received = &packet[.. IPV6_HEADER_LEN + 16 + 10];
+        assert_eq!(parse_ipv6(received, true).unwrap().unwrap().segment, IPV6_HEADER_LEN + 16 .. received.len());
+        assert_eq!(parse_ipv6(received, false), Err(MalformedPacket));
+
+        // cut short within the destination options header, there's no telling where the segment starts
+        let received = &packet[.. IPV6_HEADER_LEN + 12];
+        assert_eq!(parse_ipv6(received, true), Ok(None));
+        assert_eq!(parse_ipv6(received, false), Err(MalformedPacket));
+    }
+
+    #[test]
+    fn malformed_ipv6_packets_are_rejected() {
+        let packet = CAPTURED_IPV6_SYN_ACK_WITH_OPTIONS;
+
+        let mut not_ipv6 = packet.to_vec();
+        not_ipv6[0] = 0x45;
+
+        // the hop-by-hop header claims more than the whole payload
+        let mut overlong_header = packet.to_vec();
+        overlong_header[IPV6_HEADER_LEN + 1] = 8;
+
+        let mut overlong_payload = packet.to_vec();
+        overlong_payload[4 .. 6].copy_from_slice(&100u16.to_be_bytes());
+
+        let malformed = [
+            Vec::new(),
+            packet[.. IPV6_HEADER_LEN - 1].to_vec(),
+            not_ipv6,
+            overlong_header.clone(),
+            overlong_payload,
+        ];
+
+        for packet in malformed {
+            assert_eq!(parse_ipv6(&packet, false), Err(MalformedPacket), "{:02x?}", packet);
+        }
+
+        assert_eq!(parse_ipv6(&overlong_header, true), Err(MalformedPacket));
+    }
+
+    #[test]
+    fn crc32c_matches_the_check_value() {
+        // the standard check value of CRC-32C (castagnoli)
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(b""), 0);
+    }
+
+    #[test]
+    fn sctp_init_packets_carry_their_crc32c() {
+        let mut buffer = [0u8; MAX_PROBE_PACKET_LEN];
+        let mut initiate_tag = u32::MAX;
+
+        let packet = create_sctp_init_packet(50000, 443, &mut buffer, &mut initiate_tag).unwrap().buffer.to_vec();
+
+        // the tag wraps past 0, which it may never be
+        assert_eq!(initiate_tag, 1);
+        assert_eq!(packet.len(), SCTP_INIT_PACKET_LEN);
+        assert_eq!(parse_sctp_packet(&packet), Some((50000, 443, SCTP_CHUNK_INIT)));
+
+        let mut zeroed = packet.clone();
+        zeroed[8 .. 12].fill(0);
+
+        assert_eq!(packet[8 .. 12], crc32c(&zeroed).to_le_bytes());
+    }
 }
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+use std::net::SocketAddr;
+use std::time::Instant;
+use twox_hash::XxHash64;
+
+/// Identifies a single send of a probe. A probe's ticket goes stale once it's answered, times out or is sent again,
+/// so anything still scheduled for an earlier send can be told apart from the current one.
+#[derive(Clone, Copy)]
+pub(crate) struct ProbeTicket {
+    index: u32,
+    generation: u32,
+}
+
+/// What the worker knows about a remote from the moment it's queued until it's answered or runs out of retries
+struct ProbeRecord {
+    remote: SocketAddr,
+    retries: u8,
+    // when it was last sent, to time the reply with
+    sent_at: Instant,
+    // bumped on every send, and kept across reuse of the slot, so tickets for earlier sends never match
+    generation: u32,
+    inflight: bool,
+    // the key of its expiry on the worker's timing wheel while it's in flight
+    expiry: u32,
+}
+
+/// Every probe the worker is tracking, stored in a slab so they can be referred to by index. Freed slots are reused,
+/// so once a scan reaches its peak number of tracked probes, tracking more needs no allocations.
+pub(crate) struct ProbeSlab {
+    records: Vec<ProbeRecord>,
+    // indexes of slots available for reuse
+    free_indexes: Vec<u32>,
+    // slab index of each remote being tracked, to match replies against
+    indexes: HashMap<SocketAddr, u32, BuildHasherDefault<XxHash64>>,
+    inflight_len: usize,
+}
+
+impl ProbeSlab {
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        Self {
+            records: Vec::with_capacity(capacity),
+            free_indexes: Vec::with_capacity(capacity),
+            indexes: HashMap::with_capacity_and_hasher(capacity, Default::default()),
+            inflight_len: 0,
+        }
+    }
+
+    /// Whether there are no probes left to track, whether in flight or waiting to be sent
+    pub(crate) fn is_empty(&self) -> bool { self.indexes.is_empty() }
+
+    /// The number of probes sent and neither answered nor timed out yet
+    pub(crate) fn inflight_len(&self) -> usize { self.inflight_len }
+
+    /// Starts tracking a probe to `remote` and returns its slab index, or `None` if one is already being tracked
+    pub(crate) fn insert(&mut self, remote: SocketAddr) -> Option<u32> {
+        if self.indexes.contains_key(&remote) {
+            return None;
+        }
+
+        let index = match self.free_indexes.pop() {
+            Some(index) => {
+                let record = &mut self.records[index as usize];
+                record.remote = remote;
+                record.retries = 0;
+                index
+            }
+            None => {
+                self.records.push(ProbeRecord {
+                    remote,
+                    retries: 0,
+                    sent_at: Instant::now(),
+                    generation: 0,
+                    inflight: false,
+                    expiry: 0,
+                });
+                (self.records.len() - 1) as u32
+            }
+        };
+
+        self.indexes.insert(remote, index);
+
+        Some(index)
+    }
+
+    pub(crate) fn remote(&self, index: u32) -> SocketAddr { self.records[index as usize].remote }
+
+    /// Marks the probe as in flight as of `sent_at` and returns the ticket to schedule its expiry with
+    pub(crate) fn mark_sent(&mut self, index: u32, sent_at: Instant) -> ProbeTicket {
+        let record = &mut self.records[index as usize];
+        record.sent_at = sent_at;
+        record.generation = record.generation.wrapping_add(1);
+
+        if !record.inflight {
+            record.inflight = true;
+            self.inflight_len += 1;
+        }
+
+        ProbeTicket { index, generation: record.generation }
+    }
+
+    /// Remembers the key of the expiry scheduled for the probe's latest send, to cancel it with once it's answered
+    pub(crate) fn set_expiry(&mut self, index: u32, expiry: u32) { self.records[index as usize].expiry = expiry; }
+
+    /// Stops tracking the probe to `remote` if it's in flight, i.e. if this is the reply it was waiting on. Returns how
+    /// many times it was retried before it was answered, when it was last sent and the key of its expiry.
+    pub(crate) fn answer(&mut self, remote: &SocketAddr) -> Option<(u8, Instant, u32)> {
+        match self.indexes.get(remote) {
+            Some(index) if self.records[*index as usize].inflight => {
+                let record = &self.records[*index as usize];
+                let answered = (record.retries, record.sent_at, record.expiry);
+                self.remove(*index);
+                Some(answered)
+            }
+            _ => None,
+        }
+    }
+
+    /// Takes the probe `ticket` was issued for out of flight, unless it was answered or sent again in the meantime.
+    /// Returns its slab index and how many times it's been retried so far. The caller decides whether it's retried or
+    /// removed.
+    pub(crate) fn expire(&mut self, ticket: ProbeTicket) -> Option<(u32, u8)> {
+        let record = &mut self.records[ticket.index as usize];
+
+        if !record.inflight || record.generation != ticket.generation {
+            return None;
+        }
+
+        record.inflight = false;
+        self.inflight_len -= 1;
+
+        Some((ticket.index, record.retries))
+    }
+
+    /// Counts another retry against a probe that's about to be sent again
+    pub(crate) fn retry(&mut self, index: u32) { self.records[index as usize].retries += 1; }
+
+    /// Stops tracking a probe, freeing up its slot
+    pub(crate) fn remove(&mut self, index: u32) {
+        let record = &mut self.records[index as usize];
+
+        if record.inflight {
+            record.inflight = false;
+            self.inflight_len -= 1;
+        }
+
+        self.indexes.remove(&record.remote);
+        self.free_indexes.push(index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::armada::timing_wheel::TimingWheel;
+    use std::time::Duration;
+
+    fn ms(millis: u64) -> Duration { Duration::from_millis(millis) }
+
+    #[test]
+    fn rescheduled_probes_only_expire_for_their_latest_send() {
+        let mut wheel = TimingWheel::new(ms(100));
+        let start = Instant::now();
+        let mut probes = ProbeSlab::with_capacity(4);
+        let mut expired = Vec::new();
+
+        let first = probes.insert("192.0.2.1:80".parse().unwrap()).unwrap();
+        let second = probes.insert("192.0.2.2:80".parse().unwrap()).unwrap();
+
+        wheel.insert(start + ms(10), probes.mark_sent(first, start));
+        wheel.insert(start + ms(20), probes.mark_sent(second, start));
+
+        // the first probe goes out again before its timeout, so its new expiry comes after the second probe's
+        wheel.insert(start + ms(30), probes.mark_sent(first, start + ms(5)));
+
+        // the wheel counts its ticks from a moment later than `start`, so give it one more tick to catch up
+        wheel.expire(start + ms(31), &mut expired);
+
+        // the first send's ticket comes off the wheel too, but went stale with the resend
+        assert_eq!(expired.len(), 3);
+
+        let expiries: Vec<_> = expired.into_iter().filter_map(|ticket| probes.expire(ticket)).collect();
+        assert_eq!(expiries, [(second, 0), (first, 0)]);
+        assert_eq!(probes.inflight_len(), 0);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::armada::config::rate_limit::RateLimit;
+use std::time::{Duration, Instant};
+
+// adaptive scans start out at this rate, unless their rate limit is lower
+const INITIAL_RATE: f64 = 1_000.0;
+// and never back off below this one
+const MIN_RATE: f64 = 100.0;
+// it takes a probe timeout to learn whether a probe was lost, so that's how often we adjust the rate, within reason
+const MIN_ADJUSTMENT_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_ADJUSTMENT_INTERVAL: Duration = Duration::from_secs(1);
+// any more loss than this and we're sending too fast
+const LOSS_THRESHOLD: f64 = 0.02;
+// how much of the rate is kept when we back off
+const DECREASE_FACTOR: f64 = 0.5;
+// past slow start, every interval adds this much of the rate we last saw loss at
+const INCREASE_FRACTION: f64 = 0.05;
+// the fewest outcomes worth estimating loss from
+const MIN_SAMPLES: u64 = 100;
+// how much of the rate has to be used before we speed up. Sending slower than allowed tells us nothing about whether
+// the network could take more
+const MIN_UTILIZATION: f64 = 0.8;
+
+/// Adapts a scan's rate to what the network can take, AIMD style. The rate starts out low and doubles every interval
+/// until we first see loss. From then on it's cut in half whenever we see loss, and otherwise grows by a fixed step.
+pub(crate) struct RateController {
+    max_rate: f64,
+    rate: f64,
+    // the rate we last saw loss at. Until we have, we're in slow start
+    loss_rate: Option<f64>,
+    interval: Duration,
+    interval_start: Instant,
+    counts: IntervalCounts,
+    // the total replies dropped by the kernel at the start of the interval
+    dropped_replies: u64,
+    // the reply ratio of the previous interval, to tell whether speeding up since cost us replies
+    previous_reply_ratio: Option<f64>,
+    increased: bool,
+    // whether the loss seen this interval may still stem from before we last backed off
+    recovering: bool,
+}
+
+/// What happened to our probes over the current interval
+#[derive(Default)]
+struct IntervalCounts {
+    probes_sent: u64,
+    // the outcomes of first probes. Retries don't count, since the ports they go to were already silent once
+    first_answered: u64,
+    first_unanswered: u64,
+    // ports that only answered a retry
+    retry_answered: u64,
+}
+
+impl RateController {
+    pub(crate) fn new(rate_limit: &RateLimit, port_timeout: Duration) -> Self {
+        let max_rate = rate_limit.packets_per_second() as f64;
+
+        Self {
+            max_rate,
+            rate: INITIAL_RATE.min(max_rate),
+            loss_rate: None,
+            interval: port_timeout.clamp(MIN_ADJUSTMENT_INTERVAL, MAX_ADJUSTMENT_INTERVAL),
+            interval_start: Instant::now(),
+            counts: IntervalCounts::default(),
+            dropped_replies: 0,
+            previous_reply_ratio: None,
+            increased: false,
+            recovering: false,
+        }
+    }
+
+    /// The rate to send at, in packets per second
+    pub(crate) fn rate(&self) -> usize { self.rate as usize }
+
+    pub(crate) fn record_sent(&mut self, count: usize) { self.counts.probes_sent += count as u64; }
+
+    /// Records a probe that was answered after `retries` retries
+    pub(crate) fn record_answer(&mut self, retries: u8) {
+        match retries {
+            0 => self.counts.first_answered += 1,
+            _ => self.counts.retry_answered += 1,
+        }
+    }
+
+    /// Records a probe that timed out after `retries` retries
+    pub(crate) fn record_timeout(&mut self, retries: u8) {
+        if retries == 0 {
+            self.counts.first_unanswered += 1;
+        }
+    }
+
+    /// Adjusts the rate once an interval is up, going by what happened over it and the total number of replies the
+    /// kernel has dropped so far. Returns the new rate if it changed.
+    pub(crate) fn adjust(&mut self, now: Instant, dropped_replies: u64) -> Option<usize> {
+        let elapsed = now.saturating_duration_since(self.interval_start);
+
+        if elapsed < self.interval {
+            return None;
+        }
+
+        let counts = std::mem::take(&mut self.counts);
+        let new_dropped_replies = dropped_replies.saturating_sub(self.dropped_replies);
+        let first_probes = counts.first_answered + counts.first_unanswered;
+        let reply_ratio = (first_probes >= MIN_SAMPLES).then(|| counts.first_answered as f64 / first_probes as f64);
+
+        self.interval_start = now;
+        self.dropped_replies = dropped_replies;
+
+        let loss = self.estimate_loss(&counts, reply_ratio, new_dropped_replies);
+        let previous_rate = self.rate;
+
+        self.previous_reply_ratio = reply_ratio;
+
+        // replies to probes sent before we backed off may still be coming in, so give the lower rate an interval
+        if self.recovering {
+            self.recovering = false;
+            self.increased = false;
+            return None;
+        }
+
+        if loss > LOSS_THRESHOLD {
+            self.loss_rate = Some(self.rate);
+            self.rate = (self.rate * DECREASE_FACTOR).max(MIN_RATE.min(self.max_rate));
+            self.recovering = true;
+        } else if counts.probes_sent as f64 >= self.rate * elapsed.as_secs_f64() * MIN_UTILIZATION {
+            self.rate = match self.loss_rate {
+                None => self.rate * 2.0,
+                Some(loss_rate) => self.rate + loss_rate * INCREASE_FRACTION,
+            }
+            .min(self.max_rate);
+        }
+
+        self.increased = self.rate > previous_rate;
+
+        (self.rate != previous_rate).then(|| self.rate())
+    }
+
+    /// The largest fraction of probes or replies we can tell went missing over the interval
+    fn estimate_loss(&self, counts: &IntervalCounts, reply_ratio: Option<f64>, dropped_replies: u64) -> f64 {
+        let answered = counts.first_answered + counts.retry_answered;
+
+        // a port that only answers a retry must have had its first probe, or the reply to it, lost
+        let retry_loss = match answered >= MIN_SAMPLES {
+            true => counts.retry_answered as f64 / answered as f64,
+            false => 0.0,
+        };
+
+        // if fewer first probes are answered than before we sped up, speeding up cost us replies. Only count shortfalls
+        // bigger than chance would explain
+        let reply_ratio_loss = match (self.increased, self.previous_reply_ratio, reply_ratio) {
+            (true, Some(previous_ratio), Some(_)) if previous_ratio > 0.0 => {
+                let expected_answers = previous_ratio * (counts.first_answered + counts.first_unanswered) as f64;
+                let missing_answers = expected_answers - counts.first_answered as f64;
+
+                match missing_answers > 3.0 * expected_answers.sqrt() {
+                    true => missing_answers / expected_answers,
+                    false => 0.0,
+                }
+            }
+            _ => 0.0,
+        };
+
+        // the kernel only drops replies when we can't keep up with them
+        let dropped_loss = match dropped_replies {
+            0 => 0.0,
+            dropped => dropped as f64 / (answered + dropped) as f64,
+        };
+
+        retry_loss.max(reply_ratio_loss).max(dropped_loss)
+    }
+}
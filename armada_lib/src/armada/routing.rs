@@ -0,0 +1,198 @@
+use crate::armada::config::network_limits::prefix_of;
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use twox_hash::XxHash64;
+
+// route types from linux/rtnetlink.h. Only these deliver to a host we could have a source address for
+#[cfg(target_os = "linux")]
+const RTN_UNICAST: u8 = 1;
+#[cfg(target_os = "linux")]
+const RTN_LOCAL: u8 = 2;
+// the tables the kernel consults when no policy rules were added (local, main and default), in the order it does so
+#[cfg(target_os = "linux")]
+const ROUTING_TABLES: [u32; 3] = [255, 254, 253];
+
+/// The routes of one routing table that share a prefix length, by their destination prefix. Each holds its metric and
+/// the source address its targets are scanned from, if it has one to offer.
+type RouteLevel = HashMap<IpAddr, (u32, Option<IpAddr>), BuildHasherDefault<XxHash64>>;
+
+/// Picks the address each target is scanned from. Targets are looked up in the kernel's routing tables, and scanned from
+/// the address the kernel would pick for them: the route's preferred source, or else an address on the interface the
+/// route goes out of. With source addresses given, only those are picked from, and targets whose routes don't lead to
+/// any of them are scanned from each of the given addresses in turn, as are all targets where the routing tables can't
+/// be read. Only Linux can read them, and policy routing rules are not taken into account.
+/// Link-local targets with a zone skip the routing tables and are scanned from a link-local address of their zone's
+/// interface, which is one of the given addresses if any of those are on it.
+pub(crate) struct SourceSelector {
+    // per table, in lookup order, the routes of each prefix length, longest first
+    tables: Vec<Vec<(u8, RouteLevel)>>,
+    // the link-local IPv6 addresses of each interface, by index
+    link_local_addrs: HashMap<u32, Vec<Ipv6Addr>>,
+    ipv4_addrs: Vec<Ipv4Addr>,
+    ipv6_addrs: Vec<Ipv6Addr>,
+    next_ipv4_index: usize,
+    next_ipv6_index: usize,
+}
+
+impl SourceSelector {
+    /// Picks from `ipv4_addrs` and `ipv6_addrs`, or any local address for a family if none of it are given
+    pub(crate) fn new(ipv4_addrs: Vec<Ipv4Addr>, ipv6_addrs: Vec<Ipv6Addr>) -> Self {
+        let mut selector = Self {
+            tables: Vec::new(),
+            link_local_addrs: HashMap::new(),
+            ipv4_addrs,
+            ipv6_addrs,
+            next_ipv4_index: 0,
+            next_ipv6_index: 0,
+        };
+
+        #[cfg(target_os = "linux")]
+        if let Err(e) = selector.load_routes() {
+            tracing::warn!(err = ?e, "Unable to read the routing tables. Targets will be scanned from each source address in turn.");
+        }
+
+        selector
+    }
+
+    /// The address to scan `destination` from, if there's any for its family. Its port doesn't matter.
+    pub(crate) fn source_for(&mut self, destination: SocketAddr) -> Option<IpAddr> {
+        let source = match destination {
+            SocketAddr::V6(destination) if destination.scope_id() != 0 => self.zone_source(destination.scope_id()),
+            destination => self.route_source(destination.ip()),
+        };
+
+        if source.is_some() {
+            return source;
+        }
+
+        match destination.ip() {
+            IpAddr::V4(_) => {
+                let source = self.ipv4_addrs.get(self.next_ipv4_index % self.ipv4_addrs.len().max(1))?;
+                self.next_ipv4_index = self.next_ipv4_index.wrapping_add(1);
+                Some(IpAddr::V4(*source))
+            }
+            IpAddr::V6(_) => {
+                let source = self.ipv6_addrs.get(self.next_ipv6_index % self.ipv6_addrs.len().max(1))?;
+                self.next_ipv6_index = self.next_ipv6_index.wrapping_add(1);
+                Some(IpAddr::V6(*source))
+            }
+        }
+    }
+
+    /// The source address of the route `destination` takes, if we know it and it has one
+    fn route_source(&self, destination: IpAddr) -> Option<IpAddr> {
+        for levels in &self.tables {
+            for (prefix_len, routes) in levels {
+                if let Some((_, source)) = routes.get(&prefix_of(destination, *prefix_len)) {
+                    return *source;
+                }
+            }
+        }
+
+        None
+    }
+
+    /// A link-local address on the interface with the index `scope_id`, preferring the given ones
+    fn zone_source(&self, scope_id: u32) -> Option<IpAddr> {
+        let link_local_addrs = self.link_local_addrs.get(&scope_id)?;
+
+        link_local_addrs
+            .iter()
+            .find(|address| self.ipv6_addrs.contains(address))
+            .or_else(|| link_local_addrs.first())
+            .copied()
+            .map(IpAddr::V6)
+    }
+
+    /// Reads the routing tables, working out the source address for each route up front
+    #[cfg(target_os = "linux")]
+    fn load_routes(&mut self) -> anyhow::Result<()> {
+        use crate::armada::netlink;
+
+        let addresses = netlink::addresses()?;
+        let mut tables: Vec<HashMap<u8, RouteLevel>> = ROUTING_TABLES.iter().map(|_| HashMap::new()).collect();
+
+        for route in netlink::routes()? {
+            let table_index = match ROUTING_TABLES.iter().position(|table| *table == route.table) {
+                Some(table_index) => table_index,
+                None => continue,
+            };
+
+            let source = match route.route_type {
+                RTN_UNICAST | RTN_LOCAL => self.route_source_address(&route, &addresses),
+                // unreachable, blackhole and the like. The kernel stops looking here too
+                _ => None,
+            };
+
+            let destination = prefix_of(route.destination, route.prefix_len);
+
+            tables[table_index]
+                .entry(route.prefix_len)
+                .or_default()
+                .entry(destination)
+                .and_modify(|entry| {
+                    // of routes to the same prefix, the one with the lowest metric wins
+                    if route.priority < entry.0 {
+                        *entry = (route.priority, source);
+                    }
+                })
+                .or_insert((route.priority, source));
+        }
+
+        self.tables = tables
+            .into_iter()
+            .map(|levels| {
+                let mut levels: Vec<_> = levels.into_iter().collect();
+                levels.sort_unstable_by(|(a, _), (b, _)| b.cmp(a));
+                levels
+            })
+            .collect();
+
+        for address in &addresses {
+            if let IpAddr::V6(ipv6_addr) = address.address {
+                if is_link_local(&address.address) {
+                    self.link_local_addrs.entry(address.interface_index).or_default().push(ipv6_addr);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The address targets routed over `route` are scanned from
+    #[cfg(target_os = "linux")]
+    fn route_source_address(
+        &self,
+        route: &crate::armada::netlink::Route,
+        addresses: &[crate::armada::netlink::InterfaceAddress],
+    ) -> Option<IpAddr> {
+        let given_addrs: Vec<IpAddr> = match route.destination {
+            IpAddr::V4(_) => self.ipv4_addrs.iter().copied().map(IpAddr::V4).collect(),
+            IpAddr::V6(_) => self.ipv6_addrs.iter().copied().map(IpAddr::V6).collect(),
+        };
+
+        // link-local targets are reached from link-local addresses and everything else from anything but
+        let interface_addrs = addresses
+            .iter()
+            .filter(|address| Some(address.interface_index) == route.interface_index)
+            .map(|address| address.address)
+            .filter(|address| address.is_ipv4() == route.destination.is_ipv4())
+            .filter(|address| is_link_local(address) == is_link_local(&route.destination));
+
+        match given_addrs.is_empty() {
+            true => route.preferred_source.or_else(|| interface_addrs.into_iter().next()),
+            false => route
+                .preferred_source
+                .filter(|source| given_addrs.contains(source))
+                .or_else(|| interface_addrs.into_iter().find(|address| given_addrs.contains(address))),
+        }
+    }
+}
+
+fn is_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ipv4) => ipv4.is_link_local(),
+        IpAddr::V6(ipv6) => ipv6.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
@@ -0,0 +1,75 @@
+use crate::armada::config::network_limits::NetworkLimits;
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+use std::net::IpAddr;
+use std::time::Duration;
+use twox_hash::XxHash64;
+
+// how much each new sample moves the smoothed round trip time and its variation, as in TCP (RFC 6298)
+const RTT_GAIN: f64 = 1.0 / 8.0;
+const RTT_VARIATION_GAIN: f64 = 1.0 / 4.0;
+// how many variations past the smoothed round trip time a reply may still come in
+const RTT_VARIATION_FACTOR: f64 = 4.0;
+// bounds on the timeouts we estimate. The initial timeout always lies within them
+const MIN_TIMEOUT: Duration = Duration::from_millis(100);
+const MAX_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The smoothed round trip time to a network, and how much it varies
+struct RttEstimate {
+    smoothed_rtt: f64,
+    rtt_variation: f64,
+}
+
+/// Estimates how long to wait on a reply from each network before giving up on it, going by how long its replies have
+/// taken so far, the way TCP estimates its retransmission timeout. Hosts are grouped into the same networks as for the
+/// network limits, so that a network's first few replies set the timeout for all of its hosts. Until a network has
+/// replied at all, its probes wait out the initial timeout.
+pub(crate) struct RttEstimator {
+    initial_timeout: Duration,
+    min_timeout: Duration,
+    max_timeout: Duration,
+    network_limits: NetworkLimits,
+    estimates: HashMap<IpAddr, RttEstimate, BuildHasherDefault<XxHash64>>,
+}
+
+impl RttEstimator {
+    pub(crate) fn new(initial_timeout: Duration, network_limits: NetworkLimits) -> Self {
+        Self {
+            initial_timeout,
+            min_timeout: MIN_TIMEOUT.min(initial_timeout),
+            max_timeout: MAX_TIMEOUT.max(initial_timeout),
+            network_limits,
+            estimates: HashMap::default(),
+        }
+    }
+
+    /// The longest timeout that will ever be handed out
+    pub(crate) fn max_timeout(&self) -> Duration { self.max_timeout }
+
+    /// How long to wait on a reply from `ip`
+    pub(crate) fn timeout(&self, ip: IpAddr) -> Duration {
+        match self.estimates.get(&self.network_limits.network_of(ip)) {
+            Some(estimate) => {
+                let timeout = estimate.smoothed_rtt + RTT_VARIATION_FACTOR * estimate.rtt_variation;
+                Duration::from_secs_f64(timeout).clamp(self.min_timeout, self.max_timeout)
+            }
+            None => self.initial_timeout,
+        }
+    }
+
+    /// Records how long a reply from `ip` took. Only replies to probes that were sent once should be recorded, since
+    /// there's no telling which send a reply to a retried probe answers.
+    pub(crate) fn record(&mut self, ip: IpAddr, rtt: Duration) {
+        let rtt = rtt.as_secs_f64();
+
+        self.estimates
+            .entry(self.network_limits.network_of(ip))
+            .and_modify(|estimate| {
+                let deviation = (estimate.smoothed_rtt - rtt).abs();
+
+                estimate.rtt_variation += RTT_VARIATION_GAIN * (deviation - estimate.rtt_variation);
+                estimate.smoothed_rtt += RTT_GAIN * (rtt - estimate.smoothed_rtt);
+            })
+            .or_insert(RttEstimate { smoothed_rtt: rtt, rtt_variation: rtt / 2.0 });
+    }
+}
@@ -1,39 +1,141 @@
-use pnet::packet::tcp::TcpPacket;
-use pnet::packet::{ipv4::Ipv4Packet, Packet};
+use crate::armada::packet::{parse_ipv4, MalformedPacket};
 use pnet::transport::TransportChannelType::{Layer3, Layer4};
 use pnet::transport::TransportProtocol::{Ipv4, Ipv6};
-use pnet::transport::{TransportReceiver, TransportSender};
-use pnet_sys::{Buf, BufLen, MutBuf, SockAddr, SockLen};
-use std::net::IpAddr;
+use pnet::transport::{TransportChannelType, TransportReceiver, TransportSender};
+use pnet_sys::{Buf, BufLen, CSocket, MutBuf, SockAddr, SockLen};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
 use std::{mem, net};
 
+// the most segments handed to the kernel in a single sendmmsg call
+const MAX_SEND_BATCH_SIZE: usize = 64;
+// the most replies a RecvBatch holds, and so the most taken from the kernel in a single recvmmsg call
+const MAX_RECV_BATCH_SIZE: usize = 64;
+// replies are truncated to this, which leaves plenty of room for the IP and transport headers we care about
+const RECV_SLOT_SIZE: usize = 512;
+// these let us go past net.core.rmem_max and wmem_max, but only with CAP_NET_ADMIN
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SO_RCVBUFFORCE: Option<libc::c_int> = Some(libc::SO_RCVBUFFORCE);
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const SO_RCVBUFFORCE: Option<libc::c_int> = None;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SO_SNDBUFFORCE: Option<libc::c_int> = Some(libc::SO_SNDBUFFORCE);
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const SO_SNDBUFFORCE: Option<libc::c_int> = None;
+
 pub trait TcpSenderExt {
-    fn try_send_to<T: Packet>(
+    /// Sends an already built transport layer segment (TCP or SCTP). Only the destination's address and scope id are
+    /// used, the port being in the segment already
+    fn try_send_to(
         &mut self,
-        packet: T,
-        destination: IpAddr,
+        buffer: &[u8],
+        destination: SocketAddr,
     ) -> std::io::Result<Option<usize>>;
+
+    /// Sends a batch of already built segments, given as (segment, source, destination), with as few syscalls as
+    /// possible. The kernel picks the source address, so it's ignored here.
+    /// Returns how many segments, counted from the front of the batch, were sent before the socket stopped accepting them.
+    fn try_send_batch_to(
+        &mut self,
+        segments: &[(&[u8], IpAddr, SocketAddr)],
+    ) -> std::io::Result<usize>;
+
+    /// Asks the kernel for a send buffer of `size` bytes. Without CAP_NET_ADMIN it won't go past net.core.wmem_max.
+    fn set_send_buffer_size(&self, size: usize) -> std::io::Result<()>;
+
+    /// Has the kernel route everything sent over the socket out of the named interface
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn bind_to_interface(&self, interface_name: &str) -> std::io::Result<()>;
+
+    /// Has the kernel take the IP header from the front of everything sent over the socket rather than build it
+    /// (IP_HDRINCL, or IPV6_HDRINCL for IPv6 sockets)
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn include_ip_header(&self, is_ipv4: bool) -> std::io::Result<()>;
 }
 
 pub trait TcpReceiverExt {
-    fn try_next(&mut self) -> std::io::Result<Option<(TcpPacket, IpAddr)>>;
+    /// Receives the next transport layer segment (TCP or SCTP) without interpreting it. Packets that don't hold together
+    /// are reported as `InvalidData` errors
+    // only called directly where recvmmsg isn't available
+    #[cfg_attr(any(target_os = "linux", target_os = "android", target_os = "freebsd"), allow(dead_code))]
+    fn try_next(&mut self) -> std::io::Result<Option<(&[u8], SocketAddr)>>;
+
+    /// Replaces the contents of the batch with as many segments as are immediately available, with as few syscalls as possible.
+    /// Packets that don't hold together are left out and counted by the batch. Returns the number of segments received.
+    fn try_next_batch(&mut self, batch: &mut RecvBatch) -> std::io::Result<usize>;
+
+    /// The number of packets the kernel dropped because the socket's receive buffer was full
+    fn dropped_packets(&self) -> std::io::Result<u64>;
+
+    /// Asks the kernel for a receive buffer of `size` bytes. Without CAP_NET_ADMIN it won't go past net.core.rmem_max.
+    fn set_receive_buffer_size(&self, size: usize) -> std::io::Result<()>;
+
+    /// The number of bytes the kernel actually lets queue up on the socket
+    fn receive_buffer_size(&self) -> std::io::Result<usize>;
+
+    /// Has the kernel discard every packet the classic BPF `program` doesn't accept, before it's ever queued on the socket
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn attach_filter(&self, program: &[libc::sock_filter]) -> std::io::Result<()>;
+
+    /// Only lets packets that arrived on the named interface reach the socket
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn bind_to_interface(&self, interface_name: &str) -> std::io::Result<()>;
+}
+
+/// Reusable storage for segments received by `TcpReceiverExt::try_next_batch`
+pub struct RecvBatch {
+    buffer: Vec<u8>,
+    addrs: Vec<pnet_sys::SockAddrStorage>,
+    // (start, end, remote) of each received segment within the buffer
+    segments: Vec<(usize, usize, SocketAddr)>,
+    cursor: usize,
+    // packets received into the batch so far that didn't hold together, and so were left out of it
+    malformed_packets: u64,
+}
+
+impl RecvBatch {
+    /// Makes room for `capacity` segments, up to a limit of 64
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.min(MAX_RECV_BATCH_SIZE);
+
+        Self {
+            buffer: vec![0; capacity * RECV_SLOT_SIZE],
+            addrs: vec![unsafe { mem::zeroed() }; capacity],
+            segments: Vec::with_capacity(capacity),
+            cursor: 0,
+            malformed_packets: 0,
+        }
+    }
+
+    /// How many malformed packets were skipped over while filling the batch, ever since it was created
+    pub fn malformed_packets(&self) -> u64 { self.malformed_packets }
+
+    /// Whether every received segment has already been handed out
+    pub fn is_drained(&self) -> bool { self.cursor >= self.segments.len() }
+
+    /// Forgets every received segment
+    pub fn clear(&mut self) {
+        self.segments.clear();
+        self.cursor = 0;
+    }
+
+    /// Hands out the next received segment and the address it came from
+    pub fn next(&mut self) -> Option<(&[u8], SocketAddr)> {
+        let (start, end, remote) = *self.segments.get(self.cursor)?;
+        self.cursor += 1;
+
+        Some((&self.buffer[start .. end], remote))
+    }
 }
 
 impl TcpSenderExt for TransportSender {
-    fn try_send_to<T: Packet>(
+    fn try_send_to(
         &mut self,
-        packet: T,
-        destination: IpAddr,
+        buffer: &[u8],
+        destination: SocketAddr,
     ) -> std::io::Result<Option<usize>> {
         let mut socket_addr_storage = unsafe { mem::zeroed() };
-
-        let sockaddr = match destination {
-            IpAddr::V4(ip_addr) => net::SocketAddr::V4(net::SocketAddrV4::new(ip_addr, 0)),
-            IpAddr::V6(ip_addr) => net::SocketAddr::V6(net::SocketAddrV6::new(ip_addr, 0, 0, 0)),
-        };
-
-        let socket_len = pnet_sys::addr_to_sockaddr(sockaddr, &mut socket_addr_storage);
-        let buffer = packet.packet();
+        let socket_len = pnet_sys::addr_to_sockaddr(raw_socket_addr(destination), &mut socket_addr_storage);
 
         let send_len = unsafe {
             match libc::sendto(
@@ -44,19 +146,121 @@ impl TcpSenderExt for TransportSender {
                 (&mut socket_addr_storage as *mut pnet_sys::SockAddrStorage) as *mut SockAddr,
                 socket_len,
             ) {
-                // -1 == Would block! We couldn't send a packet immediately so let's return None
-                -1 => return Ok(None),
-                len if len < 0 => Err(std::io::Error::last_os_error()),
+                // the socket is full, so nothing can be sent right now. Anything else is a real failure
+                len if len < 0 => match std::io::Error::last_os_error() {
+                    e if is_backpressure(&e) => return Ok(None),
+                    e => Err(e),
+                },
                 len => Ok(len as usize),
             }?
         };
 
         Ok(Some(send_len))
     }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    fn try_send_batch_to(
+        &mut self,
+        segments: &[(&[u8], IpAddr, SocketAddr)],
+    ) -> std::io::Result<usize> {
+        let mut total_sent = 0;
+
+        for chunk in segments.chunks(MAX_SEND_BATCH_SIZE) {
+            // everything handed to the kernel has to outlive the sendmmsg call, so it all lives on the stack here
+            let mut socket_addrs: [pnet_sys::SockAddrStorage; MAX_SEND_BATCH_SIZE] = unsafe { mem::zeroed() };
+            let mut iovecs: [libc::iovec; MAX_SEND_BATCH_SIZE] = unsafe { mem::zeroed() };
+            let mut messages: [libc::mmsghdr; MAX_SEND_BATCH_SIZE] = unsafe { mem::zeroed() };
+
+            for (idx, (buffer, _, destination)) in chunk.iter().enumerate() {
+                let socket_len = pnet_sys::addr_to_sockaddr(raw_socket_addr(*destination), &mut socket_addrs[idx]);
+
+                iovecs[idx].iov_base = buffer.as_ptr() as *mut libc::c_void;
+                iovecs[idx].iov_len = buffer.len();
+
+                messages[idx].msg_hdr.msg_name = (&mut socket_addrs[idx] as *mut pnet_sys::SockAddrStorage) as *mut libc::c_void;
+                messages[idx].msg_hdr.msg_namelen = socket_len;
+                messages[idx].msg_hdr.msg_iov = &mut iovecs[idx];
+                messages[idx].msg_hdr.msg_iovlen = 1;
+            }
+
+            let sent = unsafe {
+                libc::sendmmsg(self.socket.fd, messages.as_mut_ptr(), chunk.len() as libc::c_uint, 0)
+            };
+
+            if sent < 0 {
+                let e = std::io::Error::last_os_error();
+
+                // a full socket just means nothing more can be sent right now, while anything else is reported if it
+                // kept the very first segment from going out so the caller can move past it
+                if total_sent > 0 || is_backpressure(&e) {
+                    return Ok(total_sent);
+                }
+
+                return Err(e);
+            }
+
+            total_sent += sent as usize;
+
+            // the socket filled up partway through this chunk
+            if (sent as usize) < chunk.len() {
+                return Ok(total_sent);
+            }
+        }
+
+        Ok(total_sent)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    fn try_send_batch_to(
+        &mut self,
+        segments: &[(&[u8], IpAddr, SocketAddr)],
+    ) -> std::io::Result<usize> {
+        // no sendmmsg here so we fall back to one syscall per segment
+        for (idx, (buffer, _, destination)) in segments.iter().enumerate() {
+            if self.try_send_to(buffer, *destination)?.is_none() {
+                return Ok(idx);
+            }
+        }
+
+        Ok(segments.len())
+    }
+
+    fn set_send_buffer_size(&self, size: usize) -> std::io::Result<()> {
+        set_buffer_size(self.socket.fd, libc::SO_SNDBUF, SO_SNDBUFFORCE, size)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn bind_to_interface(&self, interface_name: &str) -> std::io::Result<()> {
+        bind_to_device(self.socket.fd, interface_name)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn include_ip_header(&self, is_ipv4: bool) -> std::io::Result<()> {
+        let (level, option) = match is_ipv4 {
+            true => (libc::IPPROTO_IP, libc::IP_HDRINCL),
+            false => (libc::IPPROTO_IPV6, libc::IPV6_HDRINCL),
+        };
+        let enabled: libc::c_int = 1;
+
+        let result = unsafe {
+            libc::setsockopt(
+                self.socket.fd,
+                level,
+                option,
+                &enabled as *const libc::c_int as *const libc::c_void,
+                mem::size_of_val(&enabled) as SockLen,
+            )
+        };
+
+        match result {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
 }
 
 impl TcpReceiverExt for TransportReceiver {
-    fn try_next(&mut self) -> std::io::Result<Option<(TcpPacket, IpAddr)>> {
+    fn try_next(&mut self) -> std::io::Result<Option<(&[u8], SocketAddr)>> {
         let buffer = &mut self.buffer;
         let mut socket_addr_storage: pnet_sys::SockAddrStorage = unsafe { mem::zeroed() };
         let mut caddrlen = mem::size_of::<pnet_sys::SockAddrStorage>() as SockLen;
@@ -71,54 +275,279 @@ impl TcpReceiverExt for TransportReceiver {
                 (&mut socket_addr_storage as *mut pnet_sys::SockAddrStorage) as *mut SockAddr,
                 &mut caddrlen,
             ) {
-                // -1 == Would block! We don't have a packet immediately available so let's return None
-                -1 => return Ok(None),
-                len if len < 0 => Err(std::io::Error::last_os_error()),
+                len if len < 0 => match std::io::Error::last_os_error() {
+                    // we don't have a packet immediately available so let's return None
+                    e if is_nothing_to_receive(&e) => return Ok(None),
+                    e => Err(e),
+                },
                 len => Ok(len as usize),
             }?
         };
 
-        let offset = match self.channel_type {
-            Layer4(Ipv4(_)) => {
-                let ip_header = Ipv4Packet::new(&self.buffer[..]).unwrap();
+        // a full buffer may have been cut short
+        let truncated = recv_len == self.buffer.len();
+        let segment = segment_range(self.channel_type, &mut self.buffer[.. recv_len], truncated)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "received a malformed packet"))?;
 
-                ip_header.get_header_length() as usize * 4usize
-            }
-            Layer4(Ipv6(_)) => {
-                /*let ip_header = Ipv6Packet::new(&self.buffer[..]).unwrap();
+        // the kernel hands us the port as 0, and the scope id of the arrival interface for link-local sources
+        let addr = pnet_sys::sockaddr_to_addr(
+            &socket_addr_storage,
+            mem::size_of::<pnet_sys::SockAddrStorage>(),
+        )?;
 
-                ip_header.get_header_length() as usize * 4usize*/
+        Ok(Some((&self.buffer[segment], addr)))
+    }
 
-                // https://en.wikipedia.org/wiki/IPv6_packet#Fixed_header -> 40 octets
-                40
-            }
-            Layer3(_) => {
-                fixup_packet(&mut self.buffer[..]);
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    fn try_next_batch(&mut self, batch: &mut RecvBatch) -> std::io::Result<usize> {
+        batch.clear();
 
-                0
-            }
+        let capacity = batch.addrs.len();
+        // like when sending, what the kernel writes through lives on the stack so receiving never allocates
+        let mut iovecs: [libc::iovec; MAX_RECV_BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut messages: [libc::mmsghdr; MAX_RECV_BATCH_SIZE] = unsafe { mem::zeroed() };
+
+        for (idx, slot) in batch.buffer.chunks_exact_mut(RECV_SLOT_SIZE).enumerate() {
+            iovecs[idx].iov_base = slot.as_mut_ptr() as *mut libc::c_void;
+            iovecs[idx].iov_len = slot.len();
+
+            messages[idx].msg_hdr.msg_name = (&mut batch.addrs[idx] as *mut pnet_sys::SockAddrStorage) as *mut libc::c_void;
+            messages[idx].msg_hdr.msg_namelen = mem::size_of::<pnet_sys::SockAddrStorage>() as SockLen;
+            messages[idx].msg_hdr.msg_iov = &mut iovecs[idx];
+            messages[idx].msg_hdr.msg_iovlen = 1;
+        }
+
+        // this is safe as the iovecs and addresses outlive the call and nothing is moved while in use
+        let received = unsafe {
+            libc::recvmmsg(
+                self.socket.fd,
+                messages.as_mut_ptr(),
+                capacity as libc::c_uint,
+                libc::MSG_DONTWAIT, // nonblocking so we just take what's already queued
+                std::ptr::null_mut(),
+            )
         };
 
-        // sometimes hosts will return odd packets...
-        if recv_len < offset {
-            return Ok(None);
+        if received < 0 {
+            return match std::io::Error::last_os_error() {
+                // we don't have any packets immediately available
+                e if is_nothing_to_receive(&e) => Ok(0),
+                e => Err(e),
+            };
+        }
+
+        for (idx, message) in messages.iter().take(received as usize).enumerate() {
+            let start = idx * RECV_SLOT_SIZE;
+            let recv_len = (message.msg_len as usize).min(RECV_SLOT_SIZE);
+            let truncated = message.msg_hdr.msg_flags & libc::MSG_TRUNC != 0;
+
+            // sometimes hosts will return odd packets...
+            let segment = match segment_range(self.channel_type, &mut batch.buffer[start .. start + recv_len], truncated) {
+                Ok(segment) => segment,
+                Err(MalformedPacket) => {
+                    batch.malformed_packets += 1;
+                    continue;
+                }
+            };
+
+            let addr = pnet_sys::sockaddr_to_addr(&batch.addrs[idx], mem::size_of::<pnet_sys::SockAddrStorage>())?;
+
+            batch.segments.push((start + segment.start, start + segment.end, addr));
         }
 
-        let packet = match TcpPacket::new(&self.buffer[offset..recv_len]) {
-            Some(tcp_packet) => tcp_packet,
-            None => return Ok(None),
+        Ok(batch.segments.len())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    fn try_next_batch(&mut self, batch: &mut RecvBatch) -> std::io::Result<usize> {
+        batch.clear();
+
+        // no recvmmsg here so we fall back to one syscall per segment, copying each into its slot
+        for idx in 0 .. batch.addrs.len() {
+            let (segment, addr) = match self.try_next() {
+                Ok(Some(next)) => next,
+                Ok(None) => break,
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    batch.malformed_packets += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let start = idx * RECV_SLOT_SIZE;
+            let len = segment.len().min(RECV_SLOT_SIZE);
+            batch.buffer[start .. start + len].copy_from_slice(&segment[.. len]);
+            batch.segments.push((start, start + len, addr));
+        }
+
+        Ok(batch.segments.len())
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn dropped_packets(&self) -> std::io::Result<u64> {
+        // SK_MEMINFO_VARS, the number of counters the kernel reports
+        let mut meminfo = [0u32; 9];
+        let mut meminfo_len = mem::size_of_val(&meminfo) as SockLen;
+
+        let result = unsafe {
+            libc::getsockopt(
+                self.socket.fd,
+                libc::SOL_SOCKET,
+                libc::SO_MEMINFO,
+                meminfo.as_mut_ptr() as *mut libc::c_void,
+                &mut meminfo_len,
+            )
         };
 
-        let addr = pnet_sys::sockaddr_to_addr(
-            &socket_addr_storage,
-            mem::size_of::<pnet_sys::SockAddrStorage>(),
-        )?;
-        let ip = match addr {
-            net::SocketAddr::V4(sa) => IpAddr::V4(*sa.ip()),
-            net::SocketAddr::V6(sa) => IpAddr::V6(*sa.ip()),
+        match result {
+            0 => Ok(meminfo[libc::SK_MEMINFO_DROPS as usize] as u64),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn dropped_packets(&self) -> std::io::Result<u64> {
+        // there's no portable way to ask
+        Ok(0)
+    }
+
+    fn set_receive_buffer_size(&self, size: usize) -> std::io::Result<()> {
+        set_buffer_size(self.socket.fd, libc::SO_RCVBUF, SO_RCVBUFFORCE, size)
+    }
+
+    fn receive_buffer_size(&self) -> std::io::Result<usize> {
+        let mut size: libc::c_int = 0;
+        let mut size_len = mem::size_of_val(&size) as SockLen;
+
+        let result = unsafe {
+            libc::getsockopt(
+                self.socket.fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVBUF,
+                &mut size as *mut libc::c_int as *mut libc::c_void,
+                &mut size_len,
+            )
+        };
+
+        match result {
+            0 => Ok(size as usize),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn attach_filter(&self, program: &[libc::sock_filter]) -> std::io::Result<()> {
+        let program = libc::sock_fprog {
+            len: program.len() as libc::c_ushort,
+            filter: program.as_ptr() as *mut libc::sock_filter,
+        };
+
+        // the kernel copies the program, so it doesn't have to outlive this call
+        let result = unsafe {
+            libc::setsockopt(
+                self.socket.fd,
+                libc::SOL_SOCKET,
+                libc::SO_ATTACH_FILTER,
+                &program as *const libc::sock_fprog as *const libc::c_void,
+                mem::size_of_val(&program) as SockLen,
+            )
         };
 
-        Ok(Some((packet, ip)))
+        match result {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn bind_to_interface(&self, interface_name: &str) -> std::io::Result<()> {
+        bind_to_device(self.socket.fd, interface_name)
+    }
+}
+
+/// Binds the socket to an interface with SO_BINDTODEVICE, which needs CAP_NET_RAW just like the raw sockets themselves
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_to_device(fd: CSocket, interface_name: &str) -> std::io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            interface_name.as_ptr() as *const libc::c_void,
+            interface_name.len() as SockLen,
+        )
+    };
+
+    match result {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// The address a raw socket sends to. Raw IPv6 sockets reject any port other than their protocol's, so it's zeroed, but
+/// link-local destinations keep the scope id that tells the kernel which interface to send them out of
+fn raw_socket_addr(destination: SocketAddr) -> SocketAddr {
+    match destination {
+        SocketAddr::V4(destination) => SocketAddr::V4(net::SocketAddrV4::new(*destination.ip(), 0)),
+        SocketAddr::V6(destination) => SocketAddr::V6(net::SocketAddrV6::new(*destination.ip(), 0, 0, destination.scope_id())),
+    }
+}
+
+/// Whether a failed send only means the socket (or the device queue behind it) is full for now
+fn is_backpressure(error: &std::io::Error) -> bool {
+    error.kind() == std::io::ErrorKind::WouldBlock || error.raw_os_error() == Some(libc::ENOBUFS)
+}
+
+/// Whether a failed receive only means nothing has arrived yet
+fn is_nothing_to_receive(error: &std::io::Error) -> bool {
+    matches!(error.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted)
+}
+
+/// Sets one of the socket's buffer sizes, going past the system wide limit if we're allowed to
+fn set_buffer_size(fd: CSocket, option: libc::c_int, force_option: Option<libc::c_int>, size: usize) -> std::io::Result<()> {
+    let size = size.min(libc::c_int::MAX as usize) as libc::c_int;
+
+    let set_option = |option: libc::c_int| unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            &size as *const libc::c_int as *const libc::c_void,
+            mem::size_of_val(&size) as SockLen,
+        )
+    };
+
+    // without the privileges to force it, the kernel quietly caps the size instead of failing
+    if force_option.is_some_and(|force_option| set_option(force_option) == 0) {
+        return Ok(());
+    }
+
+    match set_option(option) {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// Where in a received packet the transport layer segment lies
+fn segment_range(
+    channel_type: TransportChannelType,
+    buffer: &mut [u8],
+    truncated: bool,
+) -> Result<Range<usize>, MalformedPacket> {
+    match channel_type {
+        Layer4(Ipv4(_)) => match parse_ipv4(buffer, truncated)? {
+            Some(payload) => Ok(payload.segment),
+            // only fragments past the first carry no segment, and the kernel reassembles fragments before raw sockets
+            // ever see them
+            None => Err(MalformedPacket),
+        },
+        // the kernel strips the IPv6 header, along with any extension headers, before handing us the packet
+        Layer4(Ipv6(_)) => Ok(0 .. buffer.len()),
+        Layer3(_) => {
+            fixup_packet(buffer);
+
+            Ok(0 .. buffer.len())
+        }
     }
 }
 
@@ -127,7 +556,11 @@ fn fixup_packet(buffer: &mut [u8]) {
     use pnet_packet::ipv4::MutableIpv4Packet;
 
     let buflen = buffer.len();
-    let mut new_packet = MutableIpv4Packet::new(buffer).unwrap();
+    let mut new_packet = match MutableIpv4Packet::new(buffer) {
+        Some(new_packet) => new_packet,
+        // too short to hold an IP header, which the caller's parsing will find out about
+        None => return,
+    };
 
     let length = u16::from_be(new_packet.get_total_length());
     new_packet.set_total_length(length);
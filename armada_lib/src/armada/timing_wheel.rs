@@ -0,0 +1,363 @@
+use std::time::{Duration, Instant};
+
+// how finely deadlines are told apart. Nothing expires early, but it may expire up to this much late
+const TICK: Duration = Duration::from_millis(1);
+// bounds on the number of slots. Deadlines further out than a full turn of the wheel simply wait out the extra turns
+const MIN_SLOTS: usize = 64;
+const MAX_SLOTS: usize = 1 << 16;
+
+// marks the end of a slot's list of entries, or of the free list
+const NIL: u32 = u32::MAX;
+
+/// A hashed timing wheel that hands items back once their deadline passes. Each tick of time owns a slot, so
+/// scheduling, cancelling and expiring an item are O(1) no matter how many are waiting, and items come back in deadline
+/// order (and in the order they were scheduled within a tick).
+pub(crate) struct TimingWheel<T> {
+    // all ticks are counted from here
+    start: Instant,
+    // the next tick whose slot hasn't been expired yet
+    current_tick: u64,
+    // the first and last entry in each slot's list. Slot `tick % slots.len()` holds the items whose deadline falls on
+    // that tick
+    slots: Vec<(u32, u32)>,
+    // one bit per slot, set while the slot's list isn't empty, so the next occupied slot is found a word at a time
+    occupied: Vec<u64>,
+    // the entries of every slot's list. Expired and removed ones are reused, so once the wheel has held as many items as
+    // it ever will at once, scheduling more needs no allocations
+    entries: Vec<Entry<T>>,
+    // the first of the entries that are free for reuse, linked through their `next`
+    free_entry: u32,
+    len: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Entry<T> {
+    // the tick the item's deadline falls on
+    tick: u64,
+    item: T,
+    previous: u32,
+    next: u32,
+}
+
+impl<T: Copy> TimingWheel<T> {
+    /// Creates a wheel sized for deadlines up to `horizon` out to take no more than a turn
+    pub(crate) fn new(horizon: Duration) -> Self { Self::with_capacity(horizon, 0) }
+
+    /// Creates a wheel like [`TimingWheel::new`] with room for `capacity` items before it needs to allocate
+    pub(crate) fn with_capacity(horizon: Duration, capacity: usize) -> Self {
+        let slot_count = ((horizon.as_nanos() / TICK.as_nanos()) as usize + 1)
+            .next_power_of_two()
+            .clamp(MIN_SLOTS, MAX_SLOTS);
+
+        Self {
+            start: Instant::now(),
+            current_tick: 0,
+            slots: vec![(NIL, NIL); slot_count],
+            occupied: vec![0; slot_count / 64],
+            entries: Vec::with_capacity(capacity),
+            free_entry: NIL,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool { self.len == 0 }
+
+    /// Schedules `item` to expire once `deadline` has passed. Returns the key to remove it with until it expires.
+    pub(crate) fn insert(&mut self, deadline: Instant, item: T) -> u32 {
+        // round up so nothing expires early, and anything already overdue goes out with the next expiry
+        let tick = (deadline.saturating_duration_since(self.start).as_nanos().div_ceil(TICK.as_nanos()) as u64)
+            .max(self.current_tick);
+        let slot_index = self.slot_index(tick);
+        let entry = Entry { tick, item, previous: self.slots[slot_index].1, next: NIL };
+
+        let index = match self.free_entry {
+            NIL => {
+                self.entries.push(entry);
+                (self.entries.len() - 1) as u32
+            }
+            index => {
+                self.free_entry = self.entries[index as usize].next;
+                self.entries[index as usize] = entry;
+                index
+            }
+        };
+
+        // items join the back of their slot's list, keeping those of the same tick in the order they were scheduled
+        match self.slots[slot_index] {
+            (NIL, _) => {
+                self.slots[slot_index] = (index, index);
+                self.occupied[slot_index / 64] |= 1 << (slot_index % 64);
+            }
+            (_, last) => {
+                self.entries[last as usize].next = index;
+                self.slots[slot_index].1 = index;
+            }
+        }
+
+        self.len += 1;
+
+        index
+    }
+
+    /// Takes the item `key` was returned for off the wheel before it expires
+    pub(crate) fn remove(&mut self, key: u32) -> T {
+        let Entry { tick, item, .. } = self.entries[key as usize];
+        self.unlink(self.slot_index(tick), key);
+
+        item
+    }
+
+    /// Moves every item whose deadline had passed by `now` onto the end of `expired`
+    pub(crate) fn expire(&mut self, now: Instant, expired: &mut Vec<T>) {
+        let now_tick = (now.saturating_duration_since(self.start).as_nanos() / TICK.as_nanos()) as u64;
+
+        // with nothing scheduled there's nothing to catch up on
+        if self.is_empty() {
+            self.current_tick = self.current_tick.max(now_tick + 1);
+            return;
+        }
+
+        while self.current_tick <= now_tick && !self.is_empty() {
+            let tick = self.current_tick;
+            let slot_index = self.slot_index(tick);
+            let mut index = self.slots[slot_index].0;
+
+            // a slot only holds items for later turns if the wheel was sized too small for their deadlines
+            while index != NIL {
+                let Entry { tick: deadline_tick, item, next, .. } = self.entries[index as usize];
+
+                if deadline_tick <= tick {
+                    expired.push(item);
+                    self.unlink(slot_index, index);
+                }
+
+                index = next;
+            }
+
+            self.current_tick += 1;
+        }
+
+        self.current_tick = self.current_tick.max(now_tick + 1);
+    }
+
+    /// When the earliest item could be due, if anything is scheduled. This may be earlier than its actual deadline,
+    /// but never later.
+    pub(crate) fn next_expiry(&self) -> Option<Instant> {
+        if self.is_empty() {
+            return None;
+        }
+
+        // every item sits somewhere within one turn of the current tick, so the first occupied slot from here on is the
+        // earliest tick anything could be due
+        let current_slot = self.slot_index(self.current_tick);
+        let slot = self.first_occupied_slot(current_slot)?;
+        let tick = self.current_tick + (slot.wrapping_sub(current_slot) & (self.slots.len() - 1)) as u64;
+
+        Some(self.start + Duration::from_nanos(TICK.as_nanos() as u64 * tick))
+    }
+
+    /// The first slot from `from` on, wrapping around past the last slot, whose list isn't empty
+    fn first_occupied_slot(&self, from: usize) -> Option<usize> {
+        let first_word = from / 64;
+        let words = self.occupied.len();
+
+        // the rest of the first word, every other word, and then whatever came before `from` in the first word
+        let later_in_first_word = self.occupied[first_word] & (u64::MAX << (from % 64));
+        let earlier_in_first_word = self.occupied[first_word] & !(u64::MAX << (from % 64));
+
+        std::iter::once((first_word, later_in_first_word))
+            .chain((1 .. words).map(|offset| {
+                let word = (first_word + offset) % words;
+                (word, self.occupied[word])
+            }))
+            .chain(std::iter::once((first_word, earlier_in_first_word)))
+            .find(|(_, bits)| *bits != 0)
+            .map(|(word, bits)| word * 64 + bits.trailing_zeros() as usize)
+    }
+
+    fn slot_index(&self, tick: u64) -> usize { (tick as usize) & (self.slots.len() - 1) }
+
+    /// Takes the entry at `index` out of its slot's list and frees it up
+    fn unlink(&mut self, slot_index: usize, index: u32) {
+        let Entry { previous, next, .. } = self.entries[index as usize];
+        let slot = &mut self.slots[slot_index];
+
+        match previous {
+            NIL => slot.0 = next,
+            previous => self.entries[previous as usize].next = next,
+        }
+
+        match next {
+            NIL => slot.1 = previous,
+            next => self.entries[next as usize].previous = previous,
+        }
+
+        if slot.0 == NIL {
+            self.occupied[slot_index / 64] &= !(1 << (slot_index % 64));
+        }
+
+        self.entries[index as usize].next = self.free_entry;
+        self.free_entry = index;
+        self.len -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration { Duration::from_millis(millis) }
+
+    #[test]
+    fn items_expire_in_deadline_order_and_never_early() {
+        let mut wheel = TimingWheel::new(ms(100));
+        let start = wheel.start;
+        let mut expired = Vec::new();
+
+        wheel.insert(start + ms(30), 'c');
+        wheel.insert(start + ms(10), 'a');
+        wheel.insert(start + ms(20), 'b');
+        // within a tick, items come back in the order they were scheduled
+        wheel.insert(start + ms(10), 'd');
+
+        assert_eq!(wheel.next_expiry(), Some(start + ms(10)));
+
+        wheel.expire(start + ms(9), &mut expired);
+        assert!(expired.is_empty());
+
+        wheel.expire(start + ms(20), &mut expired);
+        assert_eq!(expired, ['a', 'd', 'b']);
+
+        wheel.expire(start + ms(100), &mut expired);
+        assert_eq!(expired, ['a', 'd', 'b', 'c']);
+        assert!(wheel.is_empty());
+        assert_eq!(wheel.next_expiry(), None);
+    }
+
+    #[test]
+    fn deadlines_past_a_full_turn_wait_out_the_extra_turns() {
+        // the smallest wheel there is, so 64 ticks make a turn
+        let mut wheel = TimingWheel::new(ms(1));
+        let start = wheel.start;
+        let mut expired = Vec::new();
+
+        // all of these share a slot
+        wheel.insert(start + ms(5 + 2 * 64), 3);
+        wheel.insert(start + ms(5 + 64), 2);
+        wheel.insert(start + ms(5), 1);
+
+        wheel.expire(start + ms(5), &mut expired);
+        assert_eq!(expired, [1]);
+
+        wheel.expire(start + ms(5 + 64 - 1), &mut expired);
+        assert_eq!(expired, [1]);
+
+        wheel.expire(start + ms(5 + 64), &mut expired);
+        assert_eq!(expired, [1, 2]);
+
+        wheel.expire(start + ms(5 + 2 * 64), &mut expired);
+        assert_eq!(expired, [1, 2, 3]);
+    }
+
+    #[test]
+    fn overdue_items_go_out_with_the_next_expiry() {
+        let mut wheel = TimingWheel::new(ms(100));
+        let start = wheel.start;
+        let mut expired = Vec::new();
+
+        wheel.insert(start + ms(50), 'a');
+        wheel.expire(start + ms(50), &mut expired);
+
+        // its deadline was already behind the wheel when it was scheduled
+        wheel.insert(start + ms(10), 'b');
+        assert!(wheel.next_expiry().unwrap() <= start + ms(51));
+
+        wheel.expire(start + ms(51), &mut expired);
+        assert_eq!(expired, ['a', 'b']);
+    }
+
+    #[test]
+    fn items_scheduled_again_expire_for_their_latest_deadline() {
+        let mut wheel = TimingWheel::new(ms(100));
+        let start = wheel.start;
+        let mut expired = Vec::new();
+
+        wheel.insert(start + ms(10), 'a');
+        wheel.insert(start + ms(20), 'b');
+
+        wheel.expire(start + ms(10), &mut expired);
+        assert_eq!(expired, ['a']);
+
+        // scheduled again after it expired, so it now comes after the item that was waiting on it
+        wheel.insert(start + ms(30), 'a');
+        assert_eq!(wheel.next_expiry(), Some(start + ms(20)));
+
+        wheel.expire(start + ms(20), &mut expired);
+        assert_eq!(wheel.next_expiry(), Some(start + ms(30)));
+
+        wheel.expire(start + ms(30), &mut expired);
+        assert_eq!(expired, ['a', 'b', 'a']);
+        assert_eq!(wheel.next_expiry(), None);
+    }
+
+    #[test]
+    fn removed_items_never_expire() {
+        let mut wheel = TimingWheel::with_capacity(ms(100), 4);
+        let start = wheel.start;
+        let mut expired = Vec::new();
+
+        // the first, a middle and the last of a slot's items, and the only item of another
+        let first = wheel.insert(start + ms(10), 'a');
+        wheel.insert(start + ms(10), 'b');
+        let middle = wheel.insert(start + ms(10), 'c');
+        wheel.insert(start + ms(10), 'd');
+        let last = wheel.insert(start + ms(10), 'e');
+        let only = wheel.insert(start + ms(20), 'f');
+
+        assert_eq!(wheel.remove(middle), 'c');
+        assert_eq!(wheel.remove(first), 'a');
+        assert_eq!(wheel.remove(last), 'e');
+        assert_eq!(wheel.remove(only), 'f');
+        assert_eq!(wheel.next_expiry(), Some(start + ms(10)));
+
+        // what's left of the slot's list still runs in order, and takes new items at its back
+        wheel.insert(start + ms(10), 'g');
+
+        wheel.expire(start + ms(20), &mut expired);
+        assert_eq!(expired, ['b', 'd', 'g']);
+        assert!(wheel.is_empty());
+        assert_eq!(wheel.next_expiry(), None);
+    }
+
+    #[test]
+    fn next_expiry_wraps_around_the_wheel() {
+        let mut wheel = TimingWheel::new(ms(100));
+        let start = wheel.start;
+        let turn = wheel.slots.len() as u64;
+        let mut expired = Vec::new();
+
+        // the current tick is near the end of a turn and the item's slot is near the start of the next one
+        wheel.expire(start + ms(turn - 3), &mut expired);
+        wheel.insert(start + ms(turn + 5), 'a');
+        assert_eq!(wheel.next_expiry(), Some(start + ms(turn + 5)));
+
+        // earlier in the same word as the current slot, one full turn of the wheel away
+        wheel.expire(start + ms(turn + 5), &mut expired);
+        wheel.insert(start + ms(turn + 5 + turn - 1), 'b');
+        assert_eq!(wheel.next_expiry(), Some(start + ms(turn + 5 + turn - 1)));
+    }
+
+    #[test]
+    fn next_expiry_holds_past_u32_ticks() {
+        let mut wheel = TimingWheel::new(ms(100));
+        let start = wheel.start;
+        let mut expired = Vec::new();
+
+        // about 50 days in, more ticks than a u32 holds
+        let far = (u32::MAX as u64) + 1000;
+        wheel.expire(start + ms(far), &mut expired);
+        wheel.insert(start + ms(far + 5), 'a');
+
+        assert_eq!(wheel.next_expiry(), Some(start + ms(far + 5)));
+    }
+}
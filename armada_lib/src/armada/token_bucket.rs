@@ -0,0 +1,78 @@
+use std::time::{Duration, Instant};
+
+// tokens are handed out no more often than this, so pacing a high rate doesn't take a wakeup per packet
+const PACING_INTERVAL: Duration = Duration::from_micros(250);
+
+/// Paces sends to a rate limit. Tokens trickle in continuously rather than all at once at the start of an interval, so
+/// whoever spends them, one per packet or connection attempt, is spread out evenly. Tokens left unspent pile up to the
+/// limit's burst.
+pub(crate) struct TokenBucket {
+    tokens_per_second: f64,
+    burst: f64,
+    // the fewest tokens handed out at once, i.e. a pacing interval's worth
+    quantum: f64,
+    tokens: f64,
+    // when the tokens were last topped up
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket, so the first burst can go out right away
+    pub(crate) fn new(tokens_per_second: usize, burst: usize) -> Self {
+        let tokens_per_second = tokens_per_second as f64;
+        let burst = burst as f64;
+
+        Self {
+            tokens_per_second,
+            burst,
+            quantum: quantum(tokens_per_second, burst),
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Has tokens come in at `tokens_per_second` from `now` on, piling up to `burst`
+    pub(crate) fn set_rate(&mut self, now: Instant, tokens_per_second: usize, burst: usize) {
+        // the tokens up until now came in at the old rate
+        self.available(now);
+
+        self.tokens_per_second = tokens_per_second as f64;
+        self.burst = burst as f64;
+        self.quantum = quantum(self.tokens_per_second, self.burst);
+        self.tokens = self.tokens.min(self.burst);
+    }
+
+    /// How many whole tokens can be spent at `now`. Until at least a quantum of them has built up, that's none.
+    pub(crate) fn available(&mut self, now: Instant) -> usize {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.tokens_per_second).min(self.burst);
+        self.last_refill = self.last_refill.max(now);
+
+        match self.tokens >= self.quantum {
+            true => self.tokens as usize,
+            false => 0,
+        }
+    }
+
+    /// Whether as many tokens as fit have piled up by `now`
+    pub(crate) fn is_full(&mut self, now: Instant) -> bool {
+        self.available(now);
+        self.tokens >= self.burst
+    }
+
+    /// Spends `count` tokens, which the caller should have checked are available
+    pub(crate) fn consume(&mut self, count: usize) { self.tokens -= count as f64; }
+
+    /// When the next quantum of tokens will be available
+    pub(crate) fn next_available(&self) -> Instant {
+        let missing_tokens = (self.quantum - self.tokens).max(0.0);
+
+        self.last_refill + Duration::from_secs_f64(missing_tokens / self.tokens_per_second)
+    }
+}
+
+/// A pacing interval's worth of tokens, but at least one and no more than fit in the bucket
+fn quantum(tokens_per_second: f64, burst: f64) -> f64 {
+    (tokens_per_second * PACING_INTERVAL.as_secs_f64()).floor().clamp(1.0, burst)
+}
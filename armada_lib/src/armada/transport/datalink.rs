@@ -0,0 +1,445 @@
+use crate::armada::config::ip_header::IpHeader;
+use crate::armada::packet::{parse_ipv4, parse_ipv6, write_ipv6_header, IpPacketWriter, MalformedPacket, IPV6_HEADER_LEN};
+#[cfg(target_os = "linux")]
+use crate::armada::transport::packet_socket;
+use crate::armada::transport::poll::Poller;
+use crate::armada::transport::{ProbeSender, ReplyReceiver, Transport};
+use crate::utils::{get_default_ipv4_interface, get_default_ipv6_interface};
+use pnet::datalink::{Channel, Config, DataLinkReceiver, DataLinkSender, NetworkInterface};
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, bail, Context};
+use tracing::warn;
+
+pub(super) const ETHERNET_HEADER_LEN: usize = 14;
+// large enough for any frame we send and any reply segment we hand back
+pub(super) const FRAME_BUFFER_LEN: usize = 1_518;
+
+// where we can't open a packet socket of our own, pnet doesn't hand out the channel's socket so we can't wait for it to
+// become readable, only check it this often. This has to stay well below the worker's backlog wait, which assumes a
+// sleeping receiver has nothing to receive
+const RECEIVE_POLL_INTERVAL: Duration = Duration::from_micros(50);
+// a default sized socket receive buffer holds a couple hundred of our small replies
+const SOCKET_REPLY_CAPACITY: u64 = 256;
+const NEIGHBOR_RESOLUTION_ATTEMPTS: usize = 3;
+const NEIGHBOR_RESOLUTION_TIMEOUT: Duration = Duration::from_millis(500);
+
+const ICMPV6_NEIGHBOR_SOLICITATION: u8 = 135;
+const ICMPV6_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+const NDP_OPTION_SOURCE_LINK_LAYER_ADDRESS: u8 = 1;
+
+/// Sends complete Ethernet frames on the interface holding the default route, or the one we were told to use.
+/// Every probe is addressed to the gateway's MAC, which is resolved once up front, so the kernel does no routing
+/// or IP header work per packet.
+pub(crate) struct FrameSender {
+    sender: Box<dyn DataLinkSender>,
+    link: Link,
+    frame_buffer: [u8; FRAME_BUFFER_LEN],
+}
+
+/// Receives complete Ethernet frames on the interface the frames are sent on
+pub(crate) struct FrameReceiver {
+    receiver: Box<dyn DataLinkReceiver>,
+    segment_buffer: [u8; FRAME_BUFFER_LEN],
+    malformed_packets: u64,
+    // wakes us once there are frames to receive, if we have the socket to wait on
+    poller: Option<Poller>,
+}
+
+/// Opens a datalink channel on the named interface, or the default route's if none is named, and resolves the gateway
+/// MACs for both address families.
+pub(crate) fn open(interface_name: Option<&str>) -> anyhow::Result<Transport> {
+    let (interface, link) = Link::open(interface_name)?;
+
+    // pnet's channels can't be waited on, so on Linux we send and receive through a packet socket of our own
+    #[cfg(target_os = "linux")]
+    let (sender, receiver, poller) = {
+        let (sender, receiver, poller) = packet_socket::open(&interface)?;
+        (Box::new(sender) as Box<dyn DataLinkSender>, Box::new(receiver) as Box<dyn DataLinkReceiver>, Some(poller))
+    };
+    #[cfg(not(target_os = "linux"))]
+    let ((sender, receiver), poller) = (open_channel(&interface, Duration::ZERO)?, None);
+
+    let sender = FrameSender {
+        sender,
+        link,
+        frame_buffer: [0; FRAME_BUFFER_LEN],
+    };
+
+    let receiver = FrameReceiver {
+        receiver,
+        segment_buffer: [0; FRAME_BUFFER_LEN],
+        malformed_packets: 0,
+        poller,
+    };
+
+    Ok(Transport {
+        sender: Box::new(sender),
+        receiver: Box::new(receiver),
+    })
+}
+
+impl ProbeSender for FrameSender {
+    fn set_ip_header(&mut self, ip_header: IpHeader) -> anyhow::Result<()> {
+        self.link.set_ip_header(ip_header);
+
+        Ok(())
+    }
+
+    fn try_send_to(
+        &mut self,
+        segment: &[u8],
+        protocol: IpNextHeaderProtocol,
+        source: IpAddr,
+        destination: SocketAddr,
+    ) -> std::io::Result<Option<usize>> {
+        let frame_len = self.link.write_frame(&mut self.frame_buffer, segment, protocol, source, destination.ip())?;
+
+        match self.sender.send_to(&self.frame_buffer[.. frame_len], None) {
+            Some(Ok(())) => Ok(Some(segment.len())),
+            // the socket isn't writable right now
+            Some(Err(e)) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Some(Err(e)) => Err(e),
+            None => Ok(None),
+        }
+    }
+}
+
+impl ReplyReceiver for FrameReceiver {
+    fn try_next(&mut self, protocol: IpNextHeaderProtocol) -> std::io::Result<Option<(&[u8], SocketAddr)>> {
+        loop {
+            let frame = match self.receiver.next() {
+                Ok(frame) => frame,
+                // nothing left to read
+                Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e),
+            };
+
+            // skip anything that isn't a reply over the protocol we're scanning
+            let (segment, remote) = match parse_frame(frame, protocol, false) {
+                Ok(Some(parsed)) => parsed,
+                Ok(None) => continue,
+                Err(MalformedPacket) => {
+                    self.malformed_packets += 1;
+                    continue;
+                }
+            };
+
+            // the frame is owned by the receiver, so the segment has to be copied out before we can return it
+            let segment_len = segment.len();
+            self.segment_buffer[.. segment_len].copy_from_slice(segment);
+
+            return Ok(Some((&self.segment_buffer[.. segment_len], remote)));
+        }
+    }
+
+    fn wait(&mut self, timeout: Duration) -> std::io::Result<()> {
+        match &mut self.poller {
+            Some(poller) => poller.wait(timeout),
+            None => {
+                std::thread::sleep(timeout.min(RECEIVE_POLL_INTERVAL));
+
+                Ok(())
+            }
+        }
+    }
+
+    fn reply_capacity(&self) -> u64 { SOCKET_REPLY_CAPACITY }
+
+    fn malformed_packets(&self) -> u64 { self.malformed_packets }
+}
+
+/// Everything needed to wrap a segment in a frame bound for the default gateway of an interface
+pub(super) struct Link {
+    source_mac: MacAddr,
+    ipv4_gateway_mac: Option<MacAddr>,
+    ipv6_gateway_mac: Option<MacAddr>,
+    ip_packet_writer: IpPacketWriter,
+}
+
+impl Link {
+    /// Finds the interface (the one holding the default route unless one is named) and resolves its gateways' MACs
+    pub(super) fn open(interface_name: Option<&str>) -> anyhow::Result<(NetworkInterface, Self)> {
+        // only default routes with a gateway lead anywhere we can address frames to
+        let ipv4_route = get_default_ipv4_interface()?.filter(|route| route.gateway.is_some());
+        let ipv6_route = get_default_ipv6_interface()?.filter(|route| route.gateway.is_some());
+
+        let interface_name = match interface_name {
+            Some(interface_name) => interface_name.to_string(),
+            None => ipv4_route.as_ref()
+                .or(ipv6_route.as_ref())
+                .map(|route| route.name.clone())
+                .context("Unable to find a default route for layer-2 scanning.")?,
+        };
+
+        let interface = pnet::datalink::interfaces()
+            .into_iter()
+            .find(|interface| interface.name == interface_name)
+            .ok_or_else(|| anyhow!("Unable to find interface '{}'.", interface_name))?;
+
+        let source_mac = interface.mac
+            .filter(|mac| *mac != MacAddr::zero())
+            .ok_or_else(|| anyhow!("Interface '{}' has no MAC address.", interface_name))?;
+
+        // the gateways may only be reached over the interface we picked
+        let ipv4_gateway = ipv4_route
+            .filter(|route| route.name == interface_name)
+            .and_then(|route| match route.gateway {
+                Some(IpAddr::V4(gateway)) => Some(gateway),
+                _ => None,
+            });
+        let ipv6_gateway = ipv6_route
+            .filter(|route| route.name == interface_name)
+            .and_then(|route| match route.gateway {
+                Some(IpAddr::V6(gateway)) => Some(gateway),
+                _ => None,
+            });
+
+        if ipv4_gateway.is_none() && ipv6_gateway.is_none() {
+            bail!("Interface '{}' doesn't hold a default route, so there's no gateway to send probes to.", interface_name);
+        }
+
+        let ipv4_gateway_mac = match ipv4_gateway {
+            Some(gateway) => Some(resolve_ipv4_neighbor(&interface, source_mac, gateway)?),
+            None => None,
+        };
+
+        let ipv6_gateway_mac = match ipv6_gateway {
+            Some(gateway) => match resolve_ipv6_neighbor(&interface, source_mac, gateway) {
+                Ok(mac) => Some(mac),
+                Err(e) => {
+                    warn!(err = ?e, "Unable to resolve the IPv6 gateway. IPv6 targets can't be scanned in layer-2 mode.");
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let link = Self {
+            source_mac,
+            ipv4_gateway_mac,
+            ipv6_gateway_mac,
+            ip_packet_writer: IpPacketWriter::new(),
+        };
+
+        Ok((interface, link))
+    }
+
+    /// Builds the IP headers of the next scan's probes with the given fields
+    pub(super) fn set_ip_header(&mut self, ip_header: IpHeader) { self.ip_packet_writer.set_ip_header(ip_header); }
+
+    /// Writes the Ethernet and IP headers followed by the segment to the buffer and returns the length of the frame
+    pub(super) fn write_frame(
+        &mut self,
+        buffer: &mut [u8],
+        segment: &[u8],
+        protocol: IpNextHeaderProtocol,
+        source: IpAddr,
+        destination: IpAddr,
+    ) -> std::io::Result<usize> {
+        let (gateway_mac, ether_type) = match destination {
+            IpAddr::V4(_) => (self.ipv4_gateway_mac, EtherTypes::Ipv4),
+            IpAddr::V6(_) => (self.ipv6_gateway_mac, EtherTypes::Ipv6),
+        };
+
+        let gateway_mac = gateway_mac.ok_or_else(|| {
+            std::io::Error::new(ErrorKind::AddrNotAvailable, "no gateway for this address family")
+        })?;
+
+        if buffer.len() < ETHERNET_HEADER_LEN {
+            return Err(std::io::Error::new(ErrorKind::InvalidInput, "buffer is too small for a frame"));
+        }
+
+        write_ethernet_header(buffer, gateway_mac, self.source_mac, ether_type);
+
+        let packet_len = self.ip_packet_writer
+            .write_packet(&mut buffer[ETHERNET_HEADER_LEN ..], segment, protocol, source, destination)
+            .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "unable to fit this segment in a frame"))?;
+
+        let frame_len = ETHERNET_HEADER_LEN + packet_len;
+
+        Ok(frame_len)
+    }
+}
+
+fn open_channel(
+    interface: &NetworkInterface,
+    read_timeout: Duration,
+) -> anyhow::Result<(Box<dyn DataLinkSender>, Box<dyn DataLinkReceiver>)> {
+    let config = Config {
+        read_timeout: Some(read_timeout),
+        write_timeout: Some(Duration::ZERO),
+        promiscuous: false,
+        ..Default::default()
+    };
+
+    match pnet::datalink::channel(interface, config).context("Error on datalink channel initialization")? {
+        Channel::Ethernet(sender, receiver) => Ok((sender, receiver)),
+        _ => bail!("Interface '{}' isn't an Ethernet interface.", interface.name),
+    }
+}
+
+fn write_ethernet_header(buffer: &mut [u8], destination: MacAddr, source: MacAddr, ether_type: EtherType) {
+    let mut ethernet_packet = MutableEthernetPacket::new(&mut buffer[.. ETHERNET_HEADER_LEN])
+        .expect("The frame buffer always fits an Ethernet header.");
+
+    ethernet_packet.set_destination(destination);
+    ethernet_packet.set_source(source);
+    ethernet_packet.set_ethertype(ether_type);
+}
+
+/// Pulls the transport layer segment and the remote address out of an Ethernet frame if it carries `protocol`. Frames
+/// only ever come from the gateway, so the address never has a zone.
+/// Frames too short for their headers, or whose IP packet doesn't hold together, are reported as malformed. `truncated`
+/// frames were cut short by our receive buffer, which is only malformed if it left us without their headers.
+pub(super) fn parse_frame(
+    frame: &[u8],
+    protocol: IpNextHeaderProtocol,
+    truncated: bool,
+) -> Result<Option<(&[u8], SocketAddr)>, MalformedPacket> {
+    let ethernet_packet = EthernetPacket::new(frame).ok_or(MalformedPacket)?;
+    let ip_buffer = &frame[ETHERNET_HEADER_LEN ..];
+
+    let payload = match ethernet_packet.get_ethertype() {
+        EtherTypes::Ipv4 => parse_ipv4(ip_buffer, truncated)?,
+        EtherTypes::Ipv6 => parse_ipv6(ip_buffer, truncated)?,
+        _ => None,
+    };
+
+    Ok(payload
+        .filter(|payload| payload.protocol == protocol)
+        .map(|payload| (&ip_buffer[payload.segment], SocketAddr::new(payload.source, 0))))
+}
+
+/// Resolves the MAC of an on-link IPv4 neighbor with ARP
+fn resolve_ipv4_neighbor(interface: &NetworkInterface, source_mac: MacAddr, neighbor: Ipv4Addr) -> anyhow::Result<MacAddr> {
+    use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
+
+    let source_ip = interface.ips.iter()
+        .find_map(|network| match network.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("Interface '{}' has no IPv4 address to ARP from.", interface.name))?;
+
+    let (mut sender, mut receiver) = open_channel(interface, NEIGHBOR_RESOLUTION_TIMEOUT)?;
+
+    // ethernet header + arp request
+    let mut request = [0u8; ETHERNET_HEADER_LEN + 28];
+    write_ethernet_header(&mut request, MacAddr::broadcast(), source_mac, EtherTypes::Arp);
+
+    {
+        let mut arp_packet = MutableArpPacket::new(&mut request[ETHERNET_HEADER_LEN ..])
+            .expect("The request buffer always fits an ARP packet.");
+
+        arp_packet.set_hardware_type(ArpHardwareTypes::Ethernet);
+        arp_packet.set_protocol_type(EtherTypes::Ipv4);
+        arp_packet.set_hw_addr_len(6);
+        arp_packet.set_proto_addr_len(4);
+        arp_packet.set_operation(ArpOperations::Request);
+        arp_packet.set_sender_hw_addr(source_mac);
+        arp_packet.set_sender_proto_addr(source_ip);
+        arp_packet.set_target_hw_addr(MacAddr::zero());
+        arp_packet.set_target_proto_addr(neighbor);
+    }
+
+    resolve_neighbor(&mut *sender, &mut *receiver, &request, |frame| {
+        let ethernet_packet = EthernetPacket::new(frame)?;
+
+        if ethernet_packet.get_ethertype() != EtherTypes::Arp {
+            return None;
+        }
+
+        let arp_packet = ArpPacket::new(ethernet_packet.payload())?;
+
+        match arp_packet.get_operation() == ArpOperations::Reply && arp_packet.get_sender_proto_addr() == neighbor {
+            true => Some(arp_packet.get_sender_hw_addr()),
+            false => None,
+        }
+    }).ok_or_else(|| anyhow!("No ARP reply from gateway {}.", neighbor))
+}
+
+/// Resolves the MAC of an on-link IPv6 neighbor with a neighbor solicitation
+fn resolve_ipv6_neighbor(interface: &NetworkInterface, source_mac: MacAddr, neighbor: Ipv6Addr) -> anyhow::Result<MacAddr> {
+    // solicitations have to come from our link-local address
+    let source_ip = interface.ips.iter()
+        .find_map(|network| match network.ip() {
+            IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfe80 => Some(ip),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("Interface '{}' has no link-local IPv6 address to solicit from.", interface.name))?;
+
+    let (mut sender, mut receiver) = open_channel(interface, NEIGHBOR_RESOLUTION_TIMEOUT)?;
+
+    // the solicited-node multicast group of the neighbor and its matching multicast MAC
+    let neighbor_octets = neighbor.octets();
+    let solicited_node = Ipv6Addr::new(
+        0xff02, 0, 0, 0, 0, 1,
+        0xff00 | neighbor_octets[13] as u16,
+        u16::from_be_bytes([neighbor_octets[14], neighbor_octets[15]]),
+    );
+    let solicited_node_mac = MacAddr::new(0x33, 0x33, 0xff, neighbor_octets[13], neighbor_octets[14], neighbor_octets[15]);
+
+    // type, code, checksum, reserved, target address, source link-layer address option
+    let mut solicitation = [0u8; 32];
+    solicitation[0] = ICMPV6_NEIGHBOR_SOLICITATION;
+    solicitation[8 .. 24].copy_from_slice(&neighbor_octets);
+    solicitation[24] = NDP_OPTION_SOURCE_LINK_LAYER_ADDRESS;
+    solicitation[25] = 1;
+    solicitation[26 .. 32].copy_from_slice(&source_mac.octets());
+
+    let checksum = pnet::util::ipv6_checksum(&solicitation, 1, &[], &source_ip, &solicited_node, IpNextHeaderProtocols::Icmpv6);
+    solicitation[2 .. 4].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut request = [0u8; ETHERNET_HEADER_LEN + IPV6_HEADER_LEN + 32];
+    write_ethernet_header(&mut request, solicited_node_mac, source_mac, EtherTypes::Ipv6);
+    // neighbor discovery packets must carry a hop limit of 255
+    write_ipv6_header(
+        &mut request[ETHERNET_HEADER_LEN ..],
+        &source_ip,
+        &solicited_node,
+        IpNextHeaderProtocols::Icmpv6,
+        solicitation.len(),
+        &IpHeader::new().with_hop_limit(255),
+    ).expect("The request buffer always fits an IPv6 header.");
+    request[ETHERNET_HEADER_LEN + IPV6_HEADER_LEN ..].copy_from_slice(&solicitation);
+
+    resolve_neighbor(&mut *sender, &mut *receiver, &request, |frame| {
+        let (advertisement, _) = parse_frame(frame, IpNextHeaderProtocols::Icmpv6, false).ok().flatten()?;
+
+        // the advertisement's target tells us who it's for, the frame's source tells us where they are
+        match advertisement.first() == Some(&ICMPV6_NEIGHBOR_ADVERTISEMENT) && advertisement.get(8 .. 24) == Some(&neighbor_octets[..]) {
+            true => EthernetPacket::new(frame).map(|ethernet_packet| ethernet_packet.get_source()),
+            false => None,
+        }
+    }).ok_or_else(|| anyhow!("No neighbor advertisement from gateway {}.", neighbor))
+}
+
+/// Sends the request until `parse_reply` recognizes a reply or we run out of attempts
+fn resolve_neighbor(
+    sender: &mut dyn DataLinkSender,
+    receiver: &mut dyn DataLinkReceiver,
+    request: &[u8],
+    parse_reply: impl Fn(&[u8]) -> Option<MacAddr>,
+) -> Option<MacAddr> {
+    for _ in 0 .. NEIGHBOR_RESOLUTION_ATTEMPTS {
+        if let Some(Err(e)) = sender.send_to(request, None) {
+            warn!(err = ?e, "Failed to send neighbor resolution request");
+        }
+
+        let deadline = Instant::now() + NEIGHBOR_RESOLUTION_TIMEOUT;
+
+        while Instant::now() < deadline {
+            if let Some(mac) = receiver.next().ok().and_then(&parse_reply) {
+                return Some(mac);
+            }
+        }
+    }
+
+    None
+}
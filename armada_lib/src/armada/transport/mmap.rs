@@ -0,0 +1,409 @@
+use crate::armada::config::ip_header::IpHeader;
+use crate::armada::packet::MalformedPacket;
+use crate::armada::transport::datalink::{parse_frame, Link};
+use crate::armada::transport::poll::Poller;
+use crate::armada::transport::{ProbeSender, ReplyReceiver, Transport};
+use pnet::packet::ip::IpNextHeaderProtocol;
+use std::net::{IpAddr, SocketAddr};
+use std::ptr::addr_of;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{bail, Context};
+
+// not every libc release exposes tpacket_versions, so we define the one we need
+const TPACKET_V3: libc::c_int = 2;
+
+// the receive ring. Blocks are handed to us whole, either when full or once they've been open for RX_BLOCK_TIMEOUT_MS
+const RX_BLOCK_SIZE: usize = 1 << 20;
+const RX_BLOCK_COUNT: usize = 32;
+const RX_FRAME_SIZE: usize = 2_048;
+const RX_BLOCK_TIMEOUT_MS: u32 = 10;
+
+// the transmit ring. Every slot holds a single frame, which for us is never more than a hundred bytes or so
+const TX_BLOCK_SIZE: usize = 1 << 16;
+const TX_BLOCK_COUNT: usize = 16;
+const TX_FRAME_SIZE: usize = 256;
+
+// frame data starts right after the (aligned) frame header in both rings
+const TPACKET3_HDR_ALIGNED_LEN: usize =
+    (std::mem::size_of::<libc::tpacket3_hdr>() + libc::TPACKET_ALIGNMENT - 1) & !(libc::TPACKET_ALIGNMENT - 1);
+
+/// The packet socket and the rings mapped from it, shared by both halves of the transport.
+/// The receive and transmit rings are disjoint regions of the mapping and each is only touched by its own half.
+struct PacketRing {
+    fd: libc::c_int,
+    ring: *mut u8,
+    ring_len: usize,
+}
+
+// see above, neither half touches the other's region of the ring
+unsafe impl Send for PacketRing {}
+unsafe impl Sync for PacketRing {}
+
+/// Sends complete Ethernet frames through a PACKET_MMAP (TPACKET_V3) transmit ring shared with the kernel.
+/// Frames are written straight into the ring and the kernel is only poked once per batch.
+pub(crate) struct RingSender {
+    ring: Arc<PacketRing>,
+    link: Link,
+    tx_frame_index: usize,
+}
+
+/// Receives complete Ethernet frames through a PACKET_MMAP (TPACKET_V3) receive ring shared with the kernel.
+/// Replies are read in place, so no packet is copied through a syscall.
+pub(crate) struct RingReceiver {
+    ring: Arc<PacketRing>,
+    rx_block_index: usize,
+    // whether we're holding the current receive block, how many of its packets are left and where the next one is
+    rx_block_held: bool,
+    rx_packets_left: u32,
+    rx_next_packet_offset: usize,
+    // the kernel resets its counter every time we read it
+    dropped_packets: u64,
+    malformed_packets: u64,
+    // wakes us once the kernel retires a block
+    poller: Poller,
+}
+
+/// Sets up the rings on the named interface, or the one holding the default route, and resolves the gateway MACs.
+/// This fails without root or CAP_NET_RAW.
+pub(crate) fn open(interface_name: Option<&str>) -> anyhow::Result<Transport> {
+    let (interface, link) = Link::open(interface_name)?;
+
+    if !interface.is_up() {
+        bail!("Interface '{}' is down.", interface.name);
+    }
+
+    let protocol = (libc::ETH_P_ALL as u16).to_be() as libc::c_int;
+    let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, protocol) };
+
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context("Error on packet socket initialization");
+    }
+
+    // from here on the ring owns the socket (and later the mapping) so dropping it on error cleans up
+    let mut ring = PacketRing {
+        fd,
+        ring: std::ptr::null_mut(),
+        ring_len: 0,
+    };
+
+    ring.set_option(libc::PACKET_VERSION, &TPACKET_V3)
+        .context("Unable to switch the packet socket to TPACKET_V3.")?;
+
+    let mut rx_request: libc::tpacket_req3 = unsafe { std::mem::zeroed() };
+    rx_request.tp_block_size = RX_BLOCK_SIZE as libc::c_uint;
+    rx_request.tp_block_nr = RX_BLOCK_COUNT as libc::c_uint;
+    rx_request.tp_frame_size = RX_FRAME_SIZE as libc::c_uint;
+    rx_request.tp_frame_nr = (RX_BLOCK_SIZE / RX_FRAME_SIZE * RX_BLOCK_COUNT) as libc::c_uint;
+    rx_request.tp_retire_blk_tov = RX_BLOCK_TIMEOUT_MS;
+
+    ring.set_option(libc::PACKET_RX_RING, &rx_request)
+        .context("Unable to set up the receive ring.")?;
+
+    // the kernel refuses block timeouts and private areas on the transmit ring
+    let mut tx_request: libc::tpacket_req3 = unsafe { std::mem::zeroed() };
+    tx_request.tp_block_size = TX_BLOCK_SIZE as libc::c_uint;
+    tx_request.tp_block_nr = TX_BLOCK_COUNT as libc::c_uint;
+    tx_request.tp_frame_size = TX_FRAME_SIZE as libc::c_uint;
+    tx_request.tp_frame_nr = (TX_BLOCK_SIZE / TX_FRAME_SIZE * TX_BLOCK_COUNT) as libc::c_uint;
+
+    ring.set_option(libc::PACKET_TX_RING, &tx_request)
+        .context("Unable to set up the transmit ring.")?;
+
+    // both are only optimizations. Older kernels don't know about them and that's fine
+    let _ = ring.set_option(libc::PACKET_QDISC_BYPASS, &1 as &libc::c_int);
+    let _ = ring.set_option(libc::PACKET_IGNORE_OUTGOING, &1 as &libc::c_int);
+
+    // the receive ring comes first in the mapping, followed by the transmit ring
+    let ring_len = RX_BLOCK_SIZE * RX_BLOCK_COUNT + TX_BLOCK_SIZE * TX_BLOCK_COUNT;
+    let mapping = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            ring_len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_POPULATE,
+            fd,
+            0,
+        )
+    };
+
+    if mapping == libc::MAP_FAILED {
+        return Err(std::io::Error::last_os_error()).context("Unable to map the packet rings.");
+    }
+
+    ring.ring = mapping as *mut u8;
+    ring.ring_len = ring_len;
+
+    let mut link_addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+    link_addr.sll_family = libc::AF_PACKET as libc::c_ushort;
+    link_addr.sll_protocol = protocol as u16;
+    link_addr.sll_ifindex = interface.index as libc::c_int;
+
+    let bind_result = unsafe {
+        libc::bind(
+            fd,
+            &link_addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        )
+    };
+
+    if bind_result < 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Unable to bind the packet socket to '{}'.", interface.name));
+    }
+
+    let poller = Poller::new(&[ring.fd]).context("Unable to set up polling on the packet socket.")?;
+
+    let ring = Arc::new(ring);
+
+    let sender = RingSender {
+        ring: ring.clone(),
+        link,
+        tx_frame_index: 0,
+    };
+
+    let receiver = RingReceiver {
+        ring,
+        rx_block_index: 0,
+        rx_block_held: false,
+        rx_packets_left: 0,
+        rx_next_packet_offset: 0,
+        dropped_packets: 0,
+        malformed_packets: 0,
+        poller,
+    };
+
+    Ok(Transport {
+        sender: Box::new(sender),
+        receiver: Box::new(receiver),
+    })
+}
+
+impl PacketRing {
+    fn set_option<T>(&self, option: libc::c_int, value: &T) -> std::io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(
+                self.fd,
+                libc::SOL_PACKET,
+                option,
+                value as *const T as *const libc::c_void,
+                std::mem::size_of::<T>() as libc::socklen_t,
+            )
+        };
+
+        match result {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    fn get_option<T>(&self, option: libc::c_int, value: &mut T) -> std::io::Result<()> {
+        let mut value_len = std::mem::size_of::<T>() as libc::socklen_t;
+
+        let result = unsafe {
+            libc::getsockopt(
+                self.fd,
+                libc::SOL_PACKET,
+                option,
+                value as *mut T as *mut libc::c_void,
+                &mut value_len,
+            )
+        };
+
+        match result {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    fn rx_block(&self, index: usize) -> *mut libc::tpacket_block_desc {
+        unsafe { self.ring.add(index * RX_BLOCK_SIZE) as *mut libc::tpacket_block_desc }
+    }
+
+    fn tx_frame(&self, index: usize) -> *mut u8 {
+        unsafe { self.ring.add(RX_BLOCK_SIZE * RX_BLOCK_COUNT + index * TX_FRAME_SIZE) }
+    }
+}
+
+impl RingSender {
+
+    /// Writes the frame for a segment into the next transmit slot and marks it for sending.
+    /// Returns `Ok(false)` if the ring is full.
+    fn queue_frame(
+        &mut self,
+        segment: &[u8],
+        protocol: IpNextHeaderProtocol,
+        source: IpAddr,
+        destination: SocketAddr,
+    ) -> std::io::Result<bool> {
+        let frame = self.ring.tx_frame(self.tx_frame_index);
+        let header = frame as *mut libc::tpacket3_hdr;
+        let status = unsafe { &*(addr_of!((*header).tp_status) as *const AtomicU32) };
+
+        // the kernel still hasn't sent whatever was in this slot the last time around
+        if status.load(Ordering::Acquire) != libc::TP_STATUS_AVAILABLE {
+            return Ok(false);
+        }
+
+        let frame_buffer = unsafe {
+            std::slice::from_raw_parts_mut(frame.add(TPACKET3_HDR_ALIGNED_LEN), TX_FRAME_SIZE - TPACKET3_HDR_ALIGNED_LEN)
+        };
+
+        let frame_len = self.link.write_frame(frame_buffer, segment, protocol, source, destination.ip())?;
+
+        unsafe {
+            (*header).tp_len = frame_len as u32;
+            (*header).tp_snaplen = frame_len as u32;
+        }
+
+        // hand the slot over to the kernel only once it's completely written
+        status.store(libc::TP_STATUS_SEND_REQUEST, Ordering::Release);
+
+        self.tx_frame_index = (self.tx_frame_index + 1) % (TX_BLOCK_SIZE / TX_FRAME_SIZE * TX_BLOCK_COUNT);
+
+        Ok(true)
+    }
+
+    /// Asks the kernel to send every frame queued in the transmit ring
+    fn flush(&self) {
+        // nonblocking. If the kernel can't take everything now, the rest goes out with the next flush
+        unsafe {
+            libc::sendto(self.ring.fd, std::ptr::null(), 0, libc::MSG_DONTWAIT, std::ptr::null(), 0);
+        }
+    }
+}
+
+impl ProbeSender for RingSender {
+    fn set_ip_header(&mut self, ip_header: IpHeader) -> anyhow::Result<()> {
+        self.link.set_ip_header(ip_header);
+
+        Ok(())
+    }
+
+    fn try_send_to(
+        &mut self,
+        segment: &[u8],
+        protocol: IpNextHeaderProtocol,
+        source: IpAddr,
+        destination: SocketAddr,
+    ) -> std::io::Result<Option<usize>> {
+        let queued = self.queue_frame(segment, protocol, source, destination)?;
+
+        self.flush();
+
+        match queued {
+            true => Ok(Some(segment.len())),
+            false => Ok(None),
+        }
+    }
+
+    fn try_send_batch(
+        &mut self,
+        segments: &[(&[u8], IpAddr, SocketAddr)],
+        protocol: IpNextHeaderProtocol,
+    ) -> std::io::Result<usize> {
+        let mut queued_count = 0;
+
+        for (segment, source, destination) in segments {
+            match self.queue_frame(segment, protocol, *source, *destination) {
+                Ok(true) => queued_count += 1,
+                // the ring is full
+                Ok(false) => break,
+                Err(e) if queued_count == 0 => return Err(e),
+                // report the ones we did queue, the failing segment will be at the front of the next attempt
+                Err(_) => break,
+            }
+        }
+
+        // one syscall for the whole batch
+        self.flush();
+
+        Ok(queued_count)
+    }
+}
+
+impl ReplyReceiver for RingReceiver {
+    fn try_next(&mut self, protocol: IpNextHeaderProtocol) -> std::io::Result<Option<(&[u8], SocketAddr)>> {
+        loop {
+            let block = self.ring.rx_block(self.rx_block_index);
+            let block_status = unsafe { &*(addr_of!((*block).hdr.bh1.block_status) as *const AtomicU32) };
+
+            if self.rx_packets_left == 0 {
+                // we're done with this block, so give it back to the kernel and move on to the next
+                if self.rx_block_held {
+                    block_status.store(libc::TP_STATUS_KERNEL, Ordering::Release);
+
+                    self.rx_block_held = false;
+                    self.rx_block_index = (self.rx_block_index + 1) % RX_BLOCK_COUNT;
+                    continue;
+                }
+
+                // the kernel hasn't retired the next block yet, so there's nothing to read
+                if block_status.load(Ordering::Acquire) & libc::TP_STATUS_USER == 0 {
+                    return Ok(None);
+                }
+
+                unsafe {
+                    self.rx_block_held = true;
+                    self.rx_packets_left = (*block).hdr.bh1.num_pkts;
+                    self.rx_next_packet_offset = (*block).hdr.bh1.offset_to_first_pkt as usize;
+                }
+
+                continue;
+            }
+
+            let packet = unsafe { (block as *const u8).add(self.rx_next_packet_offset) };
+            let header = unsafe { &*(packet as *const libc::tpacket3_hdr) };
+
+            self.rx_packets_left -= 1;
+            self.rx_next_packet_offset += header.tp_next_offset as usize;
+
+            // in case the kernel can't filter out our own probes for us
+            let link_addr = unsafe { &*(packet.add(TPACKET3_HDR_ALIGNED_LEN) as *const libc::sockaddr_ll) };
+
+            if link_addr.sll_pkttype == libc::PACKET_OUTGOING {
+                continue;
+            }
+
+            // this stays valid until we hand the block back, which is never before our caller is done with it
+            let frame = unsafe { std::slice::from_raw_parts(packet.add(header.tp_mac as usize), header.tp_snaplen as usize) };
+
+            // skip anything that isn't a reply over the protocol we're scanning
+            match parse_frame(frame, protocol, header.tp_snaplen < header.tp_len) {
+                Ok(Some(parsed)) => return Ok(Some(parsed)),
+                Ok(None) => {}
+                Err(MalformedPacket) => self.malformed_packets += 1,
+            }
+        }
+    }
+
+    fn wait(&mut self, timeout: Duration) -> std::io::Result<()> { self.poller.wait(timeout) }
+
+    // replies only become visible once their block is retired, so leave room for half the ring to fill in the meantime
+    fn reply_capacity(&self) -> u64 { (RX_BLOCK_SIZE / RX_FRAME_SIZE * RX_BLOCK_COUNT / 2) as u64 }
+
+    fn dropped_packets(&mut self) -> u64 {
+        let mut statistics: libc::tpacket_stats_v3 = unsafe { std::mem::zeroed() };
+
+        if self.ring.get_option(libc::PACKET_STATISTICS, &mut statistics).is_ok() {
+            self.dropped_packets += statistics.tp_drops as u64;
+        }
+
+        self.dropped_packets
+    }
+
+    fn malformed_packets(&self) -> u64 { self.malformed_packets }
+}
+
+impl Drop for PacketRing {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.ring.is_null() {
+                libc::munmap(self.ring as *mut libc::c_void, self.ring_len);
+            }
+
+            libc::close(self.fd);
+        }
+    }
+}
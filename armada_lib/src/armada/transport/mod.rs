@@ -0,0 +1,88 @@
+pub(crate) mod datalink;
+#[cfg(target_os = "linux")]
+pub(crate) mod mmap;
+#[cfg(target_os = "linux")]
+mod packet_socket;
+mod poll;
+pub(crate) mod raw;
+
+use crate::armada::config::ip_header::IpHeader;
+use pnet::packet::ip::IpNextHeaderProtocol;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// The two halves of a network backend. The worker sends probes through one and receives the replies to them through
+/// the other, each from its own thread.
+pub(crate) struct Transport {
+    pub(crate) sender: Box<dyn ProbeSender>,
+    pub(crate) receiver: Box<dyn ReplyReceiver>,
+}
+
+/// Sends probes. The worker only talks to the network through this and `ReplyReceiver`.
+pub(crate) trait ProbeSender: Send {
+    /// Has the probes of the next scan carry the given IP header fields. Transports that leave the IP header to the
+    /// kernel can't, and fail if any are set.
+    fn set_ip_header(&mut self, ip_header: IpHeader) -> anyhow::Result<()> {
+        if ip_header.is_set() {
+            anyhow::bail!("The kernel builds the IP headers of this scanner's probes, so their fields can't be set.");
+        }
+
+        Ok(())
+    }
+
+    /// Sends a transport layer segment (TCP or SCTP) from `source` to `destination`. The segment already holds the
+    /// destination port, so only the address is used, along with the scope id of link-local IPv6 destinations.
+    /// Returns `Ok(None)` if the segment couldn't be sent right now and should be tried again later.
+    fn try_send_to(
+        &mut self,
+        segment: &[u8],
+        protocol: IpNextHeaderProtocol,
+        source: IpAddr,
+        destination: SocketAddr,
+    ) -> std::io::Result<Option<usize>>;
+
+    /// Sends a batch of `(segment, source, destination)` in order, stopping at the first one that can't be sent right now.
+    /// Returns how many segments, counted from the front of the batch, were sent.
+    /// An error is only returned if the very first segment fails, so the caller knows exactly which one to blame.
+    fn try_send_batch(
+        &mut self,
+        segments: &[(&[u8], IpAddr, SocketAddr)],
+        protocol: IpNextHeaderProtocol,
+    ) -> std::io::Result<usize> {
+        for (idx, (segment, source, destination)) in segments.iter().enumerate() {
+            match self.try_send_to(segment, protocol, *source, *destination) {
+                Ok(Some(_)) => {}
+                Ok(None) => return Ok(idx),
+                Err(e) if idx == 0 => return Err(e),
+                // report the ones we did send, the failing segment will be at the front of the next attempt
+                Err(_) => return Ok(idx),
+            }
+        }
+
+        Ok(segments.len())
+    }
+}
+
+/// Receives the replies to our probes.
+pub(crate) trait ReplyReceiver: Send {
+    /// Receives the next transport layer segment of `protocol`, without interpreting it, along with the address it came from.
+    /// The address's port is always 0, but link-local IPv6 ones carry the scope id of the interface they arrived on.
+    /// Returns `Ok(None)` once there's nothing left to receive.
+    fn try_next(&mut self, protocol: IpNextHeaderProtocol) -> std::io::Result<Option<(&[u8], SocketAddr)>>;
+
+    /// Blocks until there might be something to receive or `timeout` passes, whichever comes first.
+    /// Waking up with nothing to receive is fine, the caller just waits again, but anything that arrives has to wake us
+    /// promptly.
+    fn wait(&mut self, timeout: Duration) -> std::io::Result<()>;
+
+    /// Roughly how many replies can queue up waiting to be received before the kernel has to start dropping them
+    fn reply_capacity(&self) -> u64;
+
+    /// The number of packets the kernel has dropped because we didn't receive them fast enough, since the receiver was
+    /// opened. Backends that can't tell always report zero.
+    fn dropped_packets(&mut self) -> u64 { 0 }
+
+    /// The number of packets received that didn't hold together, e.g. runts or ones with bad header lengths or
+    /// checksums, since the receiver was opened. They're skipped rather than handed out.
+    fn malformed_packets(&self) -> u64 { 0 }
+}
@@ -0,0 +1,156 @@
+use crate::armada::transport::datalink::FRAME_BUFFER_LEN;
+use crate::armada::transport::poll::Poller;
+use pnet::datalink::{DataLinkReceiver, DataLinkSender, NetworkInterface};
+use std::sync::Arc;
+use anyhow::Context;
+
+/// A nonblocking packet socket bound to a single interface, shared by both halves of the datalink transport.
+/// pnet's channels keep their socket to themselves, so this is what lets the receiving half sleep until a frame
+/// arrives.
+struct PacketSocket {
+    fd: libc::c_int,
+}
+
+/// Sends complete Ethernet frames through the packet socket
+pub(super) struct PacketSocketSender {
+    socket: Arc<PacketSocket>,
+}
+
+/// Receives complete Ethernet frames through the packet socket, one syscall each
+pub(super) struct PacketSocketReceiver {
+    socket: Arc<PacketSocket>,
+    frame_buffer: [u8; FRAME_BUFFER_LEN],
+}
+
+/// Opens a packet socket on the interface, along with a poller that wakes once there are frames to receive.
+/// This fails without root or CAP_NET_RAW.
+pub(super) fn open(
+    interface: &NetworkInterface,
+) -> anyhow::Result<(PacketSocketSender, PacketSocketReceiver, Poller)> {
+    let protocol = (libc::ETH_P_ALL as u16).to_be() as libc::c_int;
+    let socket_type = libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+    let fd = unsafe { libc::socket(libc::AF_PACKET, socket_type, protocol) };
+
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context("Error on packet socket initialization");
+    }
+
+    // from here on dropping the socket on error closes it
+    let socket = PacketSocket { fd };
+
+    // only an optimization, older kernels don't know about it and the receiver skips our own frames either way
+    let ignore_outgoing: libc::c_int = 1;
+    unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_PACKET,
+            libc::PACKET_IGNORE_OUTGOING,
+            &ignore_outgoing as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        );
+    }
+
+    let mut link_addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+    link_addr.sll_family = libc::AF_PACKET as libc::c_ushort;
+    link_addr.sll_protocol = protocol as u16;
+    link_addr.sll_ifindex = interface.index as libc::c_int;
+
+    let bind_result = unsafe {
+        libc::bind(
+            fd,
+            &link_addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        )
+    };
+
+    if bind_result < 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Unable to bind the packet socket to '{}'.", interface.name));
+    }
+
+    let poller = Poller::new(&[fd]).context("Unable to set up polling on the packet socket.")?;
+
+    let socket = Arc::new(socket);
+
+    let sender = PacketSocketSender { socket: socket.clone() };
+    let receiver = PacketSocketReceiver {
+        socket,
+        frame_buffer: [0; FRAME_BUFFER_LEN],
+    };
+
+    Ok((sender, receiver, poller))
+}
+
+impl DataLinkSender for PacketSocketSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<std::io::Result<()>> {
+        let mut frame_buffer = [0u8; FRAME_BUFFER_LEN];
+        let frame = frame_buffer.get_mut(.. packet_size)?;
+
+        for _ in 0 .. num_packets {
+            frame.fill(0);
+            func(frame);
+
+            if let Some(Err(e)) = self.send_to(frame, None) {
+                return Some(Err(e));
+            }
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self, packet: &[u8], _: Option<NetworkInterface>) -> Option<std::io::Result<()>> {
+        let sent = unsafe { libc::send(self.socket.fd, packet.as_ptr() as *const libc::c_void, packet.len(), 0) };
+
+        match sent {
+            sent if sent >= 0 => Some(Ok(())),
+            // including the socket not being writable right now
+            _ => Some(Err(std::io::Error::last_os_error())),
+        }
+    }
+}
+
+impl DataLinkReceiver for PacketSocketReceiver {
+    fn next(&mut self) -> std::io::Result<&[u8]> {
+        loop {
+            let mut link_addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+            let mut link_addr_len = std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+
+            // frames longer than the buffer are cut short, same as with pnet's channels
+            let received = unsafe {
+                libc::recvfrom(
+                    self.socket.fd,
+                    self.frame_buffer.as_mut_ptr() as *mut libc::c_void,
+                    self.frame_buffer.len(),
+                    0,
+                    &mut link_addr as *mut libc::sockaddr_ll as *mut libc::sockaddr,
+                    &mut link_addr_len,
+                )
+            };
+
+            // including there being nothing left to receive
+            if received < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            // in case the kernel can't filter out our own probes for us
+            if link_addr.sll_pkttype == libc::PACKET_OUTGOING {
+                continue;
+            }
+
+            return Ok(&self.frame_buffer[.. received as usize]);
+        }
+    }
+}
+
+impl Drop for PacketSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+/// Waits for any of a fixed set of sockets to become readable, so receivers can sleep instead of spinning on empty
+/// sockets. Uses epoll where available and falls back to poll elsewhere.
+pub(crate) struct Poller {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    epoll_fd: libc::c_int,
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    poll_fds: Vec<libc::pollfd>,
+}
+
+impl Poller {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) fn new(fds: &[libc::c_int]) -> std::io::Result<Self> {
+        let epoll_fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+
+        if epoll_fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        // dropping the poller on error closes the epoll instance
+        let poller = Self { epoll_fd };
+
+        for fd in fds {
+            let mut event = libc::epoll_event {
+                events: libc::EPOLLIN as u32,
+                u64: *fd as u64,
+            };
+
+            if unsafe { libc::epoll_ctl(epoll_fd, libc::EPOLL_CTL_ADD, *fd, &mut event) } < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        Ok(poller)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub(crate) fn new(fds: &[libc::c_int]) -> std::io::Result<Self> {
+        let poll_fds = fds
+            .iter()
+            .map(|fd| libc::pollfd { fd: *fd, events: libc::POLLIN, revents: 0 })
+            .collect();
+
+        Ok(Self { poll_fds })
+    }
+
+    /// Blocks until one of the sockets is readable or `timeout` passes. Being interrupted by a signal counts as
+    /// a timeout, callers are expected to check their sockets either way.
+    pub(crate) fn wait(&mut self, timeout: Duration) -> std::io::Result<()> {
+        // round up so we never wake just short of a deadline, only to spin until it arrives
+        let timeout_ms = timeout.as_micros().div_ceil(1_000).min(libc::c_int::MAX as u128) as libc::c_int;
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let result = unsafe {
+            // we don't care which socket woke us, the receiver checks all of them
+            let mut event: libc::epoll_event = std::mem::zeroed();
+
+            libc::epoll_wait(self.epoll_fd, &mut event, 1, timeout_ms)
+        };
+
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let result = unsafe { libc::poll(self.poll_fds.as_mut_ptr(), self.poll_fds.len() as libc::nfds_t, timeout_ms) };
+
+        match result {
+            result if result >= 0 => Ok(()),
+            _ => match std::io::Error::last_os_error() {
+                e if e.kind() == std::io::ErrorKind::Interrupted => Ok(()),
+                e => Err(e),
+            },
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Drop for Poller {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.epoll_fd);
+        }
+    }
+}
@@ -0,0 +1,552 @@
+use crate::armada::config::ip_header::IpHeader;
+use crate::armada::config::source_ports::SourcePorts;
+use crate::armada::packet::{IpPacketWriter, IPV6_HEADER_LEN, MAX_PROBE_PACKET_LEN};
+use crate::armada::tcp_ext::{RecvBatch, TcpReceiverExt, TcpSenderExt};
+use crate::armada::transport::poll::Poller;
+use crate::armada::transport::{ProbeSender, ReplyReceiver, Transport};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::tcp::TcpFlags::{ACK, RST, SYN};
+use pnet::transport::{
+    transport_channel, TransportChannelType,
+    TransportProtocol, TransportReceiver, TransportSender,
+};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use anyhow::Context;
+use tracing::warn;
+
+// the most replies pulled off of a socket with a single syscall
+const RECV_BATCH_SIZE: usize = 32;
+// the buffer pnet reads into. We receive into a RecvBatch instead, so this has no bearing on the socket's buffers
+const PNET_BUFFER_SIZE: usize = 1024 * 16;
+// unless told otherwise, we ask for room for a few thousand replies. Without CAP_NET_ADMIN the kernel caps this at
+// net.core.rmem_max
+const DEFAULT_RECEIVE_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+// the kernel charges each of our small replies around a kilobyte of receive buffer, and we leave half of it as headroom
+const RECEIVE_BUFFER_BYTES_PER_REPLY: usize = 2048;
+// when we build the IP headers, the most packets built ahead of a single send, and the room each of them gets
+const HEADER_INCLUDED_BATCH_SIZE: usize = 32;
+const MAX_PACKET_LEN: usize = IPV6_HEADER_LEN + MAX_PROBE_PACKET_LEN;
+
+/// Sends through the kernel's raw sockets. The kernel routes each probe and, unless we build it ourselves, builds its
+/// IP header.
+pub(crate) struct RawSender {
+    ipv4_tcp_sender: TransportSender,
+    ipv6_tcp_sender: TransportSender,
+    ipv4_sctp_sender: TransportSender,
+    ipv6_sctp_sender: TransportSender,
+    // builds the IP headers in layer-3 mode
+    ip_packet_writer: Option<IpPacketWriter>,
+}
+
+/// Receives through the kernel's layer-4 raw sockets
+pub(crate) struct RawReceiver {
+    ipv4_tcp_receiver: TransportReceiver,
+    ipv6_tcp_receiver: TransportReceiver,
+    ipv4_sctp_receiver: TransportReceiver,
+    ipv6_sctp_receiver: TransportReceiver,
+    // replies received in bulk and not yet handed out, along with the protocol they were received over
+    recv_batch: RecvBatch,
+    recv_batch_protocol: IpNextHeaderProtocol,
+    // alternates which address family is drained first so neither can starve the other
+    prefer_ipv6: bool,
+    // wakes us when any of the receivers has something for us
+    poller: Poller,
+    // how many replies the smallest of the receive buffers holds
+    reply_capacity: u64,
+}
+
+/// Opens every raw socket the worker needs, with the receive sockets only letting through replies to `source_ports`.
+/// With an interface named, every socket is bound to it so probes leave and replies arrive through it alone. With
+/// `header_included`, we build the IP header of every probe instead of the kernel, which is only supported on Linux.
+/// The socket buffer sizes default to what the kernel allows. This fails without root or CAP_NET_RAW.
+pub(crate) fn open(
+    source_ports: SourcePorts,
+    interface_name: Option<&str>,
+    receive_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    header_included: bool,
+) -> anyhow::Result<Transport> {
+    let ipv4_protocol =
+        TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Tcp));
+    let ipv6_protocol =
+        TransportChannelType::Layer4(TransportProtocol::Ipv6(IpNextHeaderProtocols::Tcp));
+
+    let (ipv4_tcp_sender, ipv4_tcp_receiver) =
+        transport_channel(PNET_BUFFER_SIZE, ipv4_protocol)
+            .context("Error on raw socket initialization")?;
+
+    let (ipv6_tcp_sender, ipv6_tcp_receiver) =
+        transport_channel(PNET_BUFFER_SIZE, ipv6_protocol)
+            .context("Error on raw socket initialization")?;
+
+    let ipv4_sctp_protocol =
+        TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Sctp));
+    let ipv6_sctp_protocol =
+        TransportChannelType::Layer4(TransportProtocol::Ipv6(IpNextHeaderProtocols::Sctp));
+
+    let (ipv4_sctp_sender, ipv4_sctp_receiver) =
+        transport_channel(PNET_BUFFER_SIZE, ipv4_sctp_protocol)
+            .context("Error on raw socket initialization")?;
+
+    let (ipv6_sctp_sender, ipv6_sctp_receiver) =
+        transport_channel(PNET_BUFFER_SIZE, ipv6_sctp_protocol)
+            .context("Error on raw socket initialization")?;
+
+    let senders = [&ipv4_tcp_sender, &ipv6_tcp_sender, &ipv4_sctp_sender, &ipv6_sctp_sender];
+
+    if let Some(send_buffer_size) = send_buffer_size {
+        for sender in senders {
+            sender.set_send_buffer_size(send_buffer_size)
+                .context("Unable to set the raw socket send buffer size.")?;
+        }
+    }
+
+    let receivers = [
+        (&ipv4_tcp_receiver, IpNextHeaderProtocols::Tcp, true),
+        (&ipv6_tcp_receiver, IpNextHeaderProtocols::Tcp, false),
+        (&ipv4_sctp_receiver, IpNextHeaderProtocols::Sctp, true),
+        (&ipv6_sctp_receiver, IpNextHeaderProtocols::Sctp, false),
+    ];
+
+    if let Some(interface_name) = interface_name {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            let bind_context = || format!("Unable to bind the raw sockets to interface '{}'.", interface_name);
+
+            for sender in senders {
+                sender.bind_to_interface(interface_name).with_context(bind_context)?;
+            }
+
+            for (receiver, _, _) in receivers {
+                receiver.bind_to_interface(interface_name).with_context(bind_context)?;
+            }
+        }
+
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        anyhow::bail!("Binding the raw sockets to interface '{}' is only supported on Linux.", interface_name);
+    }
+
+    if header_included {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        for (sender, is_ipv4) in senders.into_iter().zip([true, false, true, false]) {
+            sender.include_ip_header(is_ipv4)
+                .context("Unable to have the raw sockets take the IP headers we build. IPv6 ones need Linux 4.5 or later.")?;
+        }
+
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        anyhow::bail!("Building the IP headers of raw socket probes is only supported on Linux.");
+    }
+
+    let mut smallest_receive_buffer_size = usize::MAX;
+
+    for (receiver, _protocol, _is_ipv4) in receivers {
+        receiver.set_receive_buffer_size(receive_buffer_size.unwrap_or(DEFAULT_RECEIVE_BUFFER_SIZE))
+            .context("Unable to set the raw socket receive buffer size.")?;
+
+        // everything the kernel lets through still gets checked by the listener, so this only saves us work
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Err(e) = receiver.attach_filter(&reply_filter(_protocol, _is_ipv4, &source_ports)) {
+            warn!(err = ?e, "Unable to attach a reply filter to a raw socket. Unrelated traffic will reach the listener.");
+        }
+
+        let granted_receive_buffer_size = receiver.receive_buffer_size()
+            .context("Unable to get the raw socket receive buffer size.")?;
+
+        smallest_receive_buffer_size = smallest_receive_buffer_size.min(granted_receive_buffer_size);
+    }
+
+    // the kernel reports twice what it was asked for to account for its own overhead, so we fell short only if the
+    // report is smaller than the request
+    if let Some(receive_buffer_size) = receive_buffer_size {
+        if smallest_receive_buffer_size < receive_buffer_size {
+            warn!(
+                "The raw socket receive buffers were capped at {} bytes. Raising net.core.rmem_max allows for bigger ones.",
+                smallest_receive_buffer_size
+            );
+        }
+    }
+
+    let sender = RawSender {
+        ipv4_tcp_sender,
+        ipv6_tcp_sender,
+        ipv4_sctp_sender,
+        ipv6_sctp_sender,
+        ip_packet_writer: header_included.then(IpPacketWriter::new),
+    };
+
+    let poller = Poller::new(&[
+        ipv4_tcp_receiver.socket.fd,
+        ipv6_tcp_receiver.socket.fd,
+        ipv4_sctp_receiver.socket.fd,
+        ipv6_sctp_receiver.socket.fd,
+    ]).context("Unable to set up polling on the raw sockets.")?;
+
+    let receiver = RawReceiver {
+        ipv4_tcp_receiver,
+        ipv6_tcp_receiver,
+        ipv4_sctp_receiver,
+        ipv6_sctp_receiver,
+        recv_batch: RecvBatch::new(RECV_BATCH_SIZE),
+        recv_batch_protocol: IpNextHeaderProtocols::Tcp,
+        prefer_ipv6: false,
+        poller,
+        reply_capacity: (smallest_receive_buffer_size / RECEIVE_BUFFER_BYTES_PER_REPLY).max(1) as u64,
+    };
+
+    Ok(Transport {
+        sender: Box::new(sender),
+        receiver: Box::new(receiver),
+    })
+}
+
+/// A classic BPF program accepting only what could be a reply to our probes: segments to one of `source_ports` which,
+/// for TCP, have RST or both SYN and ACK set. IPv4 raw sockets hand us the IP header while IPv6 ones start at the
+/// segment.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn reply_filter(protocol: IpNextHeaderProtocol, is_ipv4: bool, source_ports: &SourcePorts) -> Vec<libc::sock_filter> {
+    use libc::{
+        BPF_ALU, BPF_AND, BPF_B, BPF_H, BPF_IMM, BPF_IND, BPF_JEQ, BPF_JGE, BPF_JGT, BPF_JMP, BPF_JSET, BPF_K, BPF_LD,
+        BPF_LDX, BPF_MSH, BPF_RET, BPF_W,
+    };
+
+    // jumps are counted in instructions skipped
+    let statement = |code: u32, k: u32| libc::sock_filter { code: code as u16, jt: 0, jf: 0, k };
+    let jump = |code: u32, k: u32, jt: u8, jf: u8| libc::sock_filter { code: code as u16, jt, jf, k };
+
+    let mut program = vec![
+        // point the index register at the segment
+        match is_ipv4 {
+            true => statement(BPF_LDX | BPF_B | BPF_MSH, 0),
+            false => statement(BPF_LDX | BPF_W | BPF_IMM, 0),
+        },
+        // TCP and SCTP both keep the destination port right after the source port
+        statement(BPF_LD | BPF_H | BPF_IND, 2),
+    ];
+
+    let first_port = source_ports.first() as u32;
+    let last_port = source_ports.last() as u32;
+
+    match protocol {
+        IpNextHeaderProtocols::Tcp => program.extend([
+            jump(BPF_JMP | BPF_JGE | BPF_K, first_port, 0, 6),
+            jump(BPF_JMP | BPF_JGT | BPF_K, last_port, 5, 0),
+            statement(BPF_LD | BPF_B | BPF_IND, 13),
+            jump(BPF_JMP | BPF_JSET | BPF_K, RST as u32, 2, 0),
+            statement(BPF_ALU | BPF_AND | BPF_K, (SYN | ACK) as u32),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, (SYN | ACK) as u32, 0, 1),
+        ]),
+        _ => program.extend([
+            jump(BPF_JMP | BPF_JGE | BPF_K, first_port, 0, 2),
+            jump(BPF_JMP | BPF_JGT | BPF_K, last_port, 1, 0),
+        ]),
+    }
+
+    // accept the whole packet, or none of it
+    program.extend([statement(BPF_RET | BPF_K, u32::MAX), statement(BPF_RET | BPF_K, 0)]);
+
+    program
+}
+
+impl RawSender {
+    /// The socket for probes of `protocol` to one address family, along with what builds their IP headers in layer-3 mode
+    fn sender_for(
+        &mut self,
+        protocol: IpNextHeaderProtocol,
+        is_ipv4: bool,
+    ) -> (&mut TransportSender, Option<&mut IpPacketWriter>) {
+        let sender = match (protocol, is_ipv4) {
+            (IpNextHeaderProtocols::Sctp, true) => &mut self.ipv4_sctp_sender,
+            (IpNextHeaderProtocols::Sctp, false) => &mut self.ipv6_sctp_sender,
+            (_, true) => &mut self.ipv4_tcp_sender,
+            (_, false) => &mut self.ipv6_tcp_sender,
+        };
+
+        (sender, self.ip_packet_writer.as_mut())
+    }
+}
+
+impl ProbeSender for RawSender {
+    fn set_ip_header(&mut self, ip_header: IpHeader) -> anyhow::Result<()> {
+        match &mut self.ip_packet_writer {
+            Some(ip_packet_writer) => ip_packet_writer.set_ip_header(ip_header),
+            None if ip_header.is_set() => {
+                anyhow::bail!("The kernel builds the IP headers of layer-4 raw socket probes, so their fields can't be set.")
+            }
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    fn try_send_to(
+        &mut self,
+        segment: &[u8],
+        protocol: IpNextHeaderProtocol,
+        source: IpAddr,
+        destination: SocketAddr,
+    ) -> std::io::Result<Option<usize>> {
+        let (sender, ip_packet_writer) = self.sender_for(protocol, destination.is_ipv4());
+
+        let ip_packet_writer = match ip_packet_writer {
+            Some(ip_packet_writer) => ip_packet_writer,
+            None => return sender.try_send_to(segment, destination),
+        };
+
+        let mut packet = [0u8; MAX_PACKET_LEN];
+        let packet_len = ip_packet_writer
+            .write_packet(&mut packet, segment, protocol, source, destination.ip())
+            .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "unable to build an IP header for this segment"))?;
+
+        sender.try_send_to(&packet[.. packet_len], destination)
+    }
+
+    fn try_send_batch(
+        &mut self,
+        segments: &[(&[u8], IpAddr, SocketAddr)],
+        protocol: IpNextHeaderProtocol,
+    ) -> std::io::Result<usize> {
+        let mut total_sent = 0;
+
+        // every socket only speaks one address family, so the batch goes out in runs of consecutive v4 or v6 segments
+        while total_sent < segments.len() {
+            let is_ipv4 = segments[total_sent].2.is_ipv4();
+            let run_len = segments[total_sent ..]
+                .iter()
+                .take_while(|(_, _, destination)| destination.is_ipv4() == is_ipv4)
+                .count();
+            let run = &segments[total_sent .. total_sent + run_len];
+
+            let result = match self.sender_for(protocol, is_ipv4) {
+                (sender, Some(ip_packet_writer)) => try_send_packets(sender, ip_packet_writer, run, protocol),
+                (sender, None) => sender.try_send_batch_to(run),
+            };
+            let sent = match result {
+                Ok(sent) => sent,
+                Err(e) if total_sent == 0 => return Err(e),
+                // the segments before this run did go out, so they're reported and the failure comes up again next time
+                Err(_) => break,
+            };
+            total_sent += sent;
+
+            // the socket is backed up
+            if sent < run.len() {
+                break;
+            }
+        }
+
+        Ok(total_sent)
+    }
+}
+
+/// Sends a run of segments to a single address family over `sender`, building each one's IP header in front of it.
+/// Like `ProbeSender::try_send_batch`, this only fails if the first segment can't be sent.
+fn try_send_packets(
+    sender: &mut TransportSender,
+    ip_packet_writer: &mut IpPacketWriter,
+    segments: &[(&[u8], IpAddr, SocketAddr)],
+    protocol: IpNextHeaderProtocol,
+) -> std::io::Result<usize> {
+    let mut total_sent = 0;
+
+    for chunk in segments.chunks(HEADER_INCLUDED_BATCH_SIZE) {
+        let mut packet_buffers = [[0u8; MAX_PACKET_LEN]; HEADER_INCLUDED_BATCH_SIZE];
+        let mut packets = [(&[][..], chunk[0].1, chunk[0].2); HEADER_INCLUDED_BATCH_SIZE];
+        let mut built_count = 0;
+
+        for ((packet, packet_buffer), (segment, source, destination)) in
+            packets.iter_mut().zip(packet_buffers.iter_mut()).zip(chunk)
+        {
+            let packet_len = match ip_packet_writer.write_packet(packet_buffer, segment, protocol, *source, destination.ip()) {
+                Some(packet_len) => packet_len,
+                None => break,
+            };
+
+            *packet = (&packet_buffer[.. packet_len], *source, *destination);
+            built_count += 1;
+        }
+
+        if built_count == 0 {
+            return match total_sent {
+                0 => Err(std::io::Error::new(ErrorKind::InvalidInput, "unable to build an IP header for this segment")),
+                _ => Ok(total_sent),
+            };
+        }
+
+        let sent = match sender.try_send_batch_to(&packets[.. built_count]) {
+            Ok(sent) => sent,
+            Err(e) if total_sent == 0 => return Err(e),
+            Err(_) => return Ok(total_sent),
+        };
+        total_sent += sent;
+
+        // the socket is backed up, or the segment after these couldn't be built
+        if sent < chunk.len() {
+            break;
+        }
+    }
+
+    Ok(total_sent)
+}
+
+impl ReplyReceiver for RawReceiver {
+    fn try_next(&mut self, protocol: IpNextHeaderProtocol) -> std::io::Result<Option<(&[u8], SocketAddr)>> {
+        // anything left over from a scan over another protocol is of no use to us
+        if self.recv_batch_protocol != protocol {
+            self.recv_batch.clear();
+            self.recv_batch_protocol = protocol;
+        }
+
+        if self.recv_batch.is_drained() {
+            let (ipv4_receiver, ipv6_receiver) = match protocol {
+                IpNextHeaderProtocols::Sctp => (&mut self.ipv4_sctp_receiver, &mut self.ipv6_sctp_receiver),
+                _ => (&mut self.ipv4_tcp_receiver, &mut self.ipv6_tcp_receiver),
+            };
+
+            self.prefer_ipv6 = !self.prefer_ipv6;
+
+            let (first_receiver, second_receiver) = match self.prefer_ipv6 {
+                true => (ipv6_receiver, ipv4_receiver),
+                false => (ipv4_receiver, ipv6_receiver),
+            };
+
+            if first_receiver.try_next_batch(&mut self.recv_batch)? == 0 {
+                second_receiver.try_next_batch(&mut self.recv_batch)?;
+            }
+        }
+
+        Ok(self.recv_batch.next())
+    }
+
+    fn wait(&mut self, timeout: Duration) -> std::io::Result<()> { self.poller.wait(timeout) }
+
+    fn reply_capacity(&self) -> u64 { self.reply_capacity }
+
+    fn dropped_packets(&mut self) -> u64 {
+        [
+            &self.ipv4_tcp_receiver,
+            &self.ipv6_tcp_receiver,
+            &self.ipv4_sctp_receiver,
+            &self.ipv6_sctp_receiver,
+        ]
+            .iter()
+            .map(|receiver| receiver.dropped_packets().unwrap_or(0))
+            .sum()
+    }
+
+    fn malformed_packets(&self) -> u64 { self.recv_batch.malformed_packets() }
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use super::*;
+
+    /// Runs the program in the kernel by attaching it to one end of a datagram socket pair and sending `packet` from
+    /// the other. Returns whether it came through
+    fn passes(program: &[libc::sock_filter], packet: &[u8]) -> bool {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_DGRAM, 0, fds.as_mut_ptr()) }, 0);
+
+        let program = libc::sock_fprog { len: program.len() as libc::c_ushort, filter: program.as_ptr() as *mut _ };
+        let mut buffer = [0u8; 128];
+
+        let received = unsafe {
+            assert_eq!(
+                libc::setsockopt(
+                    fds[1],
+                    libc::SOL_SOCKET,
+                    libc::SO_ATTACH_FILTER,
+                    &program as *const libc::sock_fprog as *const libc::c_void,
+                    std::mem::size_of_val(&program) as libc::socklen_t,
+                ),
+                0
+            );
+            // packets the filter turns down are dropped without the sender hearing about it
+            let sent = libc::send(fds[0], packet.as_ptr() as *const libc::c_void, packet.len(), 0);
+            assert_eq!(sent, packet.len() as isize);
+
+            let received =
+                libc::recv(fds[1], buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(), libc::MSG_DONTWAIT);
+
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+
+            received
+        };
+
+        received == packet.len() as isize
+    }
+
+    fn tcp_segment(destination_port: u16, flags: u16) -> Vec<u8> {
+        let mut segment = vec![0u8; 20];
+        segment[.. 2].copy_from_slice(&443u16.to_be_bytes());
+        segment[2 .. 4].copy_from_slice(&destination_port.to_be_bytes());
+        segment[12] = 5 << 4;
+        segment[13] = flags as u8;
+
+        segment
+    }
+
+    fn sctp_segment(destination_port: u16) -> Vec<u8> {
+        let mut segment = vec![0u8; 16];
+        segment[.. 2].copy_from_slice(&443u16.to_be_bytes());
+        segment[2 .. 4].copy_from_slice(&destination_port.to_be_bytes());
+        segment[12] = 2;
+
+        segment
+    }
+
+    /// An IPv4 header of `header_len` bytes, options included, in front of the segment
+    fn ipv4_packet(header_len: usize, segment: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; header_len];
+        packet[0] = 0x40 | (header_len / 4) as u8;
+        packet.extend_from_slice(segment);
+
+        packet
+    }
+
+    #[test]
+    fn tcp_filters_pass_replies_to_our_ports() {
+        // (destination port, flags, whether it's let through)
+        let cases = [
+            (50000, SYN | ACK, true),
+            (50015, SYN | ACK, true),
+            (50007, RST, true),
+            (50007, RST | ACK, true),
+            (50007, ACK, false),
+            (50007, SYN, false),
+            (49999, SYN | ACK, false),
+            (50016, RST, false),
+            (443, RST, false),
+        ];
+
+        let source_ports = SourcePorts::new(50000, 50015);
+        let ipv4_filter = reply_filter(IpNextHeaderProtocols::Tcp, true, &source_ports);
+        let ipv6_filter = reply_filter(IpNextHeaderProtocols::Tcp, false, &source_ports);
+
+        for (destination_port, flags, expected) in cases {
+            let segment = tcp_segment(destination_port, flags);
+
+            assert_eq!(passes(&ipv4_filter, &ipv4_packet(20, &segment)), expected, "v4 {}", destination_port);
+            // options in the IPv4 header move the segment along
+            assert_eq!(passes(&ipv4_filter, &ipv4_packet(28, &segment)), expected, "v4+options {}", destination_port);
+            // IPv6 raw sockets start at the segment
+            assert_eq!(passes(&ipv6_filter, &segment), expected, "v6 {} {:#x}", destination_port, flags);
+        }
+    }
+
+    #[test]
+    fn sctp_filters_pass_anything_to_our_ports() {
+        let source_ports = SourcePorts::new(50000, 50015);
+        let ipv4_filter = reply_filter(IpNextHeaderProtocols::Sctp, true, &source_ports);
+        let ipv6_filter = reply_filter(IpNextHeaderProtocols::Sctp, false, &source_ports);
+
+        for (destination_port, expected) in [(50000, true), (50015, true), (49999, false), (50016, false)] {
+            let segment = sctp_segment(destination_port);
+
+            assert_eq!(passes(&ipv4_filter, &ipv4_packet(20, &segment)), expected, "v4 {}", destination_port);
+            assert_eq!(passes(&ipv6_filter, &segment), expected, "v6 {}", destination_port);
+        }
+    }
+}
@@ -1,36 +1,76 @@
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::time::Duration;
+use std::fmt;
+use std::net::SocketAddr;
 
 use crate::armada::config::host::HostIterator;
 use crate::armada::config::port::PortIterator;
+use crate::armada::config::scan_options::ScanOptions;
 use itertools::{Itertools, Product};
 
 use tokio::sync::mpsc::UnboundedSender;
 
+/// The state of a port as determined by a scan.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PortState {
+    Open,
+    Closed,
+    Filtered,
+    Unfiltered,
+    OpenFiltered,
+    /// The host couldn't be reached at all, e.g. as reported by a proxy
+    Unreachable,
+}
+
+impl fmt::Display for PortState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            PortState::Open => "open",
+            PortState::Closed => "closed",
+            PortState::Filtered => "filtered",
+            PortState::Unfiltered => "unfiltered",
+            PortState::OpenFiltered => "open|filtered",
+            PortState::Unreachable => "unreachable",
+        };
+
+        f.write_str(state)
+    }
+}
+
 #[derive(Debug)]
 pub enum ArmadaWorkMessage {
-    Results(Vec<SocketAddr>),
+    Results(Vec<(SocketAddr, PortState)>),
     Stats {
         total_processed_ports: u128,
         current_inflight_packets: u128,
-        total_packets_sent: u128
+        total_packets_sent: u128,
+        // replies the kernel dropped before we could receive them
+        total_packets_dropped: u128,
+        // received packets that didn't hold together and were skipped
+        total_packets_malformed: u128,
+        // how fast packets went out since the previous stats update, which adaptive rate limits keep changing
+        current_packets_per_second: u128
     }
 }
 
 impl ArmadaWorkMessage {
-    pub fn results(results: Vec<SocketAddr>) -> ArmadaWorkMessage {
+    pub fn results(results: Vec<(SocketAddr, PortState)>) -> ArmadaWorkMessage {
         ArmadaWorkMessage::Results(results)
     }
 
     pub fn stats(
         total_processed_ports: u128,
         current_inflight_packets: u128,
-        total_packets_sent: u128
+        total_packets_sent: u128,
+        total_packets_dropped: u128,
+        total_packets_malformed: u128,
+        current_packets_per_second: u128
     ) -> ArmadaWorkMessage {
         ArmadaWorkMessage::Stats {
             total_processed_ports,
             current_inflight_packets,
-            total_packets_sent
+            total_packets_sent,
+            total_packets_dropped,
+            total_packets_malformed,
+            current_packets_per_second
         }
     }
 }
@@ -38,12 +78,9 @@ impl ArmadaWorkMessage {
 // todo: change to struct to carry work info
 #[derive(Debug)]
 pub(crate) struct ArmadaWork {
-    pub(crate) remote_addrs: Product<HostIterator, PortIterator>,
-    pub(crate) port_retries: u8,
-    pub(crate) port_timeout: Duration,
-    pub(crate) packets_per_second: Option<usize>,
-    pub(crate) source_ipv4_addrs: Vec<Ipv4Addr>,
-    pub(crate) source_ipv6_addrs: Vec<Ipv6Addr>,
+    // (port, host) pairs. Each port is scanned across every host before moving on to the next, spreading the load
+    pub(crate) remote_addrs: Product<PortIterator, HostIterator>,
+    pub(crate) options: ScanOptions,
     pub(crate) reporting_channel: UnboundedSender<ArmadaWorkMessage>,
 }
 
@@ -51,23 +88,11 @@ impl ArmadaWork {
     pub(crate) fn new(
         remote_hosts: HostIterator,
         ports: PortIterator,
-        port_retries: u8,
-        port_timeout: Duration,
-        packets_per_second: Option<usize>,
-        source_ipv4_addrs: Vec<Ipv4Addr>,
-        source_ipv6_addrs: Vec<Ipv6Addr>,
+        options: ScanOptions,
         reporting_channel: UnboundedSender<ArmadaWorkMessage>,
     ) -> Self {
-        let remote_addrs = remote_hosts.cartesian_product(ports);
+        let remote_addrs = ports.cartesian_product(remote_hosts);
 
-        Self {
-            remote_addrs,
-            port_retries,
-            port_timeout,
-            packets_per_second,
-            source_ipv4_addrs,
-            source_ipv6_addrs,
-            reporting_channel,
-        }
+        Self { remote_addrs, options, reporting_channel }
     }
 }
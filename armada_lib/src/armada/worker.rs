@@ -1,24 +1,38 @@
-use crate::armada::tcp_ext::{TcpReceiverExt, TcpSenderExt};
-use crate::armada::work::{ArmadaWork, ArmadaWorkMessage};
-use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::transport::{
-    transport_channel, TransportChannelType,
-    TransportProtocol, TransportReceiver, TransportSender,
-};
-use std::collections::{HashMap, HashSet};
-use std::hash::BuildHasherDefault;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use crate::armada::config::host::remote_addr;
+use crate::armada::config::rate_limit::RateLimit;
+use crate::armada::config::scan_type::ScanType;
+use crate::armada::config::source_ports::SourcePorts;
+use crate::armada::listener::{ListenerCommand, ListenerStatus, ReplyListener};
+use crate::armada::network_scheduler::NetworkScheduler;
+use crate::armada::packet::{create_sctp_init_packet, TcpProbeTemplate, MAX_PROBE_PACKET_LEN};
+use crate::armada::probes::ProbeSlab;
+use crate::armada::rate_controller::RateController;
+use crate::armada::routing::SourceSelector;
+use crate::armada::rtt_estimator::RttEstimator;
+use crate::armada::timing_wheel::TimingWheel;
+use crate::armada::token_bucket::TokenBucket;
+use crate::armada::transport::{ProbeSender, Transport};
+use crate::armada::work::{ArmadaWork, ArmadaWorkMessage, PortState};
+use pnet::packet::Packet;
+use rand::rngs::ThreadRng;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use anyhow::Context;
 use tokio::sync::mpsc::UnboundedReceiver;
 use tracing::{error, warn};
-use twox_hash::XxHash64;
 
 const BATCH_SEND_SIZE: usize = 32;
-const BATCH_RECV_SIZE: usize = 32;
+// the most replies handled between sends
+const BATCH_RECV_SIZE: usize = 128;
+// replies the receiver thread can hand over before it has to wait on us. The queue is allocated up front
+const REPLY_QUEUE_CAPACITY: usize = 4096;
 //const OPEN_PORT_REPORTING_SIZE: usize = 16;
-const RATE_LIMIT_RESOLUTION: u64 = 100;
-const MS_IN_SECOND: usize = 1_000;
+// how long to hold off sending while the receiver thread catches up
+const RECEIVER_BACKLOG_WAIT: Duration = Duration::from_micros(100);
+// how often progress is reported while scanning
+pub(crate) const STATS_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
 
 pub(crate) struct ArmadaWorker {
     work_queue: UnboundedReceiver<ArmadaWork>,
@@ -29,36 +43,38 @@ impl ArmadaWorker {
         Self { work_queue }
     }
 
-    /// Runs the Armada worker, only processing (and sending) packets with the specified port
-    pub(crate) fn run(mut self, port: u16) -> anyhow::Result<()> {
-        // todo: increase buffer size
-        let ipv4_protocol =
-            TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Tcp));
-        let ipv6_protocol =
-            TransportChannelType::Layer4(TransportProtocol::Ipv6(IpNextHeaderProtocols::Tcp));
+    /// Runs the Armada worker, only processing (and sending) packets with the specified source ports.
+    /// Replies are received on a separate thread and handed back to this one.
+    pub(crate) fn run(mut self, source_ports: SourcePorts, transport: Transport) -> anyhow::Result<()> {
+        let Transport { mut sender, receiver } = transport;
 
-        let (mut ipv4_tcp_sender, mut ipv4_tcp_receiver) =
-            transport_channel(1024 * 16, ipv4_protocol)
-                .context("Error on raw socket initialization")?;
+        let (listener_commands, commands) = std::sync::mpsc::channel();
+        let listener_status = Arc::new(ListenerStatus::new(receiver.reply_capacity()));
 
-        let (mut ipv6_tcp_sender, mut ipv6_tcp_receiver) =
-            transport_channel(1024 * 16, ipv6_protocol)
-                .context("Error on raw socket initialization")?;
+        let listener = ReplyListener::new(receiver, commands, listener_status.clone());
+
+        // the listener stops once `listener_commands` is dropped, i.e. when we return
+        std::thread::Builder::new()
+            .name("armada_receiver".to_string())
+            .spawn(move || listener.run(source_ports))
+            .context("Failed to create armada receiver thread.")?;
 
         let mut tcp_seq = rand::random::<u32>();
 
         while let Some(work) = self.work_queue.blocking_recv() {
-            if let Err(e) = self.process_work(
-                work,
-                &mut ipv4_tcp_sender,
-                &mut ipv4_tcp_receiver,
-                &mut ipv6_tcp_sender,
-                &mut ipv6_tcp_receiver,
-                port,
-                &mut tcp_seq,
-            ) {
+            let (reply_sender, replies) = std::sync::mpsc::sync_channel(REPLY_QUEUE_CAPACITY);
+
+            listener_commands
+                .send(ListenerCommand::Listen { scan_type: work.options.scan_type(), replies: reply_sender })
+                .context("The armada receiver thread has stopped.")?;
+
+            if let Err(e) = self.process_work(work, sender.as_mut(), &replies, &listener_status, source_ports, &mut tcp_seq) {
                 error!(err = ?e, "scan failed");
             }
+
+            listener_commands
+                .send(ListenerCommand::Idle)
+                .context("The armada receiver thread has stopped.")?;
         }
 
         Ok(())
@@ -67,375 +83,637 @@ impl ArmadaWorker {
     fn process_work(
         &self,
         work_unit: ArmadaWork,
-        ipv4_tcp_sender: &mut TransportSender,
-        ipv4_tcp_receiver: &mut TransportReceiver,
-        ipv6_tcp_sender: &mut TransportSender,
-        ipv6_tcp_receiver: &mut TransportReceiver,
-        listening_port: u16,
+        sender: &mut dyn ProbeSender,
+        replies: &Receiver<(SocketAddr, PortState)>,
+        listener_status: &ListenerStatus,
+        source_ports: SourcePorts,
         tcp_seq: &mut u32,
     ) -> anyhow::Result<()> {
-        let ArmadaWork {
-            mut remote_addrs,
-            port_retries,
-            port_timeout,
-            packets_per_second,
-            source_ipv4_addrs,
-            source_ipv6_addrs,
-            reporting_channel,
-        } = work_unit;
-
-        let mut requeued_addrs = Vec::with_capacity(1024 * 8);
+        let ArmadaWork { mut remote_addrs, options, reporting_channel } = work_unit;
+
+        let scan_type = options.scan_type();
+        let port_retries = options.retries();
+        let port_timeout = options.timeout();
+        let rate_limit = options.rate_limit();
+        let network_limits = options.network_limits();
+
+        sender.set_ip_header(options.ip_header())?;
+
+        // every remote being scanned, from when it's queued until it's answered or out of retries. Everything else
+        // refers to probes by their index in here, and all of it is allocated up front so that once the scan is in
+        // full swing, sending and receiving make no allocations
+        let mut probes = ProbeSlab::with_capacity(1024 * 16);
+        // probes waiting to be sent, retries included
+        let mut pending_probes = Vec::with_capacity(1024 * 8);
+        // the probes that went out with the last batch
+        let mut sent_probes = Vec::with_capacity(BATCH_SEND_SIZE);
+        // how long to wait on replies from each network, starting out at the port timeout
+        let mut rtt_estimator = RttEstimator::new(port_timeout, network_limits);
+        // in-flight probes by when they time out. Answered probes are taken off it, so it never holds more than the slab
+        let mut expiry_wheel = TimingWheel::with_capacity(rtt_estimator.max_timeout(), 1024 * 16);
+        let mut expired_probes = Vec::with_capacity(1024 * 8);
+        // holds back probes to hosts and networks that are at their limits
+        let mut network_scheduler = (!network_limits.is_unlimited()).then(|| NetworkScheduler::new(network_limits));
 
         // results list
-        let mut open_ports = Vec::new();
-        let mut expiry_list = Vec::with_capacity(1024 * 8);
-        let mut packet_retry_tracker =
-            HashMap::<SocketAddr, u8, BuildHasherDefault<XxHash64>>::with_capacity_and_hasher(
-                1024 * 16,
-                Default::default(),
-            );
-        let mut inflight_addrs =
-            HashSet::<SocketAddr, BuildHasherDefault<XxHash64>>::with_capacity_and_hasher(
-                1024 * 16,
-                Default::default(),
-            );
-
-        let mut source_ipv4_cycle = source_ipv4_addrs.iter().cycle();
-        let mut source_ipv6_cycle = source_ipv6_addrs.iter().cycle();
-
-        let mut current_packets_sent_for_bucket = 0usize;
-        let mut next_packet_bucket_expiry = Instant::now()
-            .checked_add(Duration::from_millis(RATE_LIMIT_RESOLUTION))
-            .unwrap();
+        let mut port_results = Vec::new();
+
+        let mut probe_builder = ProbeBuilder {
+            scan_type,
+            // every tcp probe of the scan is a copy of this with the ports and remote patched in
+            tcp_template: TcpProbeTemplate::new(scan_type.probe_flags(), options.syn_profile()),
+            // picks the address each remote is scanned from
+            source_selector: SourceSelector::new(
+                options.source_ipv4_addrs().to_vec(),
+                options.source_ipv6_addrs().to_vec(),
+            ),
+            source_ports,
+            tcp_seq,
+            rng: rand::thread_rng(),
+        };
+
+        // adaptive rate limits are only a ceiling, below which the controller decides how fast we send
+        let mut rate_controller = rate_limit
+            .filter(RateLimit::is_adaptive)
+            .map(|rate_limit| RateController::new(&rate_limit, port_timeout));
+        let mut rate_limiter = rate_limit.map(|rate_limit| {
+            let packets_per_second = rate_controller
+                .as_ref()
+                .map_or(rate_limit.packets_per_second(), RateController::rate);
+
+            TokenBucket::new(packets_per_second, rate_limit.burst_at(packets_per_second))
+        });
+
+        let mut last_stats_update = Instant::now();
+        let mut next_stats_update = last_stats_update.checked_add(STATS_UPDATE_INTERVAL).unwrap();
 
         let mut total_processed_ports = 0u128;
         let mut total_packets_sent = 0u128;
+        let mut total_packets_sent_at_last_update = 0u128;
+        let mut current_packets_per_second = 0u128;
+        let mut remote_addrs_exhausted = false;
 
         'driver: loop {
             /*
-               1. Send up-to some limit of pending addrs a syn packet
-                   1. craft a tcp SYN packet
-                   2. clone + swap remote with addr
-                   3. send
-                   4. add to inflight_addr
-                   5. after batch, add batch to expiry_list
-               2. Receive up-to some limit of packets
-                   1. recv up-to some number of filtered (destination-bound) packets or when first terminated
-                   2. check if indicative of response (syn-ack)
-                   3. convert to socket-addr
-                   4. remove from hashset and, if present, add to open_ports
-               3. process expired addrs
-                   1. peek expiry_list
-                   2. if instant older than 'now', pop set off of expiry_list
-                   3. for each addr, remove from hashset
-                   4. if present in hashset, add to pending, otherwise discard
-               4. if inflight is empty and pending is empty, return happily else loop
+               1. Send up-to some limit of pending probes
+                   1. craft the probe packet for the probe's remote
+                   2. send
+                   3. mark the probe as in flight
+                   4. schedule its expiry on the expiry_wheel, a timeout for its network later
+               2. Handle up-to some limit of replies from the receiver thread
+                   1. if there was nothing to send, wait for a reply until the next token, stats update or expiry
+                   2. if the replying remote's probe is in flight, stop tracking it and take its expiry off the expiry_wheel
+                      and, if reportable, add to port_results
+                   3. if it was only sent once, feed how long the reply took into its network's timeout
+               3. process expired probes
+                   1. take every probe whose timeout has passed off of the expiry_wheel
+                   2. skip any that were answered or resent since
+                   3. add to pending unless out of retries, otherwise stop tracking it. Resent probes are scheduled anew,
+                      a full timeout for their network later
+               4. if no probes are left to track, return happily else loop
             */
 
-            while requeued_addrs.len() < BATCH_SEND_SIZE * 16 {
+            // with enough probes held back by the network limits, there's no use in queueing up more. Once the remotes
+            // run out they're left alone, as asking for more would have the iterator sweep the hosts again for nothing
+            while !remote_addrs_exhausted
+                && pending_probes.len() < BATCH_SEND_SIZE * 16
+                && !network_scheduler.as_ref().is_some_and(NetworkScheduler::is_full)
+            {
                 match remote_addrs.next() {
-                    Some((addr, port)) => requeued_addrs.push(SocketAddr::new(addr, port)),
-                    // if the iterator is empty AND we have no more addrs to process we can kill the whole driver loop
-                    None if inflight_addrs.is_empty() && requeued_addrs.is_empty() => break 'driver,
-                    None => break,
+                    // a remote listed twice is only scanned once at a time
+                    Some((port, (addr, scope_id))) => pending_probes.extend(probes.insert(remote_addr(addr, scope_id, port))),
+                    None => remote_addrs_exhausted = true,
                 }
             }
 
-            // pick the next ipv4 and ipv6 addresses that we should send packets from
-            let source_ipv4_addr = source_ipv4_cycle.next();
-            let source_ipv6_addr = source_ipv6_cycle.next();
+            // if the iterator is empty AND we have no more probes to track we can kill the whole driver loop
+            if remote_addrs_exhausted && probes.is_empty() {
+                break 'driver;
+            }
+
+            let now = Instant::now();
 
-            // unless we send too many packets, we're clear to send
-            let mut rate_limit_unviolated = true;
+            if next_stats_update.le(&now) {
+                let packets_sent_since_update = total_packets_sent - total_packets_sent_at_last_update;
+                current_packets_per_second =
+                    (packets_sent_since_update as f64 / now.duration_since(last_stats_update).as_secs_f64()) as u128;
 
-            if let Some(packet_per_second_limit) = &packets_per_second {
-                if next_packet_bucket_expiry.le(&Instant::now()) {
-                    current_packets_sent_for_bucket = 0;
-                    next_packet_bucket_expiry = Instant::now()
-                        .checked_add(Duration::from_millis(RATE_LIMIT_RESOLUTION))
-                        .unwrap();
+                last_stats_update = now;
+                next_stats_update = now.checked_add(STATS_UPDATE_INTERVAL).unwrap();
+                total_packets_sent_at_last_update = total_packets_sent;
 
-                    // might as well send a stats update
-                    reporting_channel.send(ArmadaWorkMessage::stats(
+                reporting_channel
+                    .send(ArmadaWorkMessage::stats(
                         total_processed_ports,
-                        inflight_addrs.len() as u128,
-                        total_packets_sent
-                    )).context("Failed to send a stats update")?;
-                }
+                        probes.inflight_len() as u128,
+                        total_packets_sent,
+                        listener_status.dropped_packets() as u128,
+                        listener_status.malformed_packets() as u128,
+                        current_packets_per_second
+                    ))
+                    .context("Failed to send stats update over reporting channel.")?;
+            }
 
-                // if the number of packets we've sent so far is below the packet limit for our resolution, mark as "clear to send" otherwise don't
-                rate_limit_unviolated = (*packet_per_second_limit / (MS_IN_SECOND / RATE_LIMIT_RESOLUTION as usize)) > current_packets_sent_for_bucket;
-            } else {
-                // even though we don't need to enforce a rate limit, the bucket timeout presents a good opportunity to push a stats update
-
-                if next_packet_bucket_expiry.le(&Instant::now()) {
-                    next_packet_bucket_expiry = Instant::now()
-                        .checked_add(Duration::from_millis(RATE_LIMIT_RESOLUTION))
-                        .unwrap();
-
-                    // send our stats update
-                    reporting_channel
-                        .send(ArmadaWorkMessage::stats(
-                            total_processed_ports,
-                            inflight_addrs.len() as u128,
-                            total_packets_sent
-                        ))
-                        .context("Failed to send stats update over reporting channel.")?;
+            if let (Some(rate_controller), Some(rate_limiter), Some(rate_limit)) =
+                (&mut rate_controller, &mut rate_limiter, &rate_limit)
+            {
+                if let Some(packets_per_second) = rate_controller.adjust(now, listener_status.dropped_packets()) {
+                    rate_limiter.set_rate(now, packets_per_second, rate_limit.burst_at(packets_per_second));
                 }
             }
 
+            // probes held back by the network limits rejoin the queue as tokens come in
+            if let Some(network_scheduler) = &mut network_scheduler {
+                network_scheduler.wake(now, &mut pending_probes);
+            }
+
+            // how many probes the rate limit lets us send right now
+            let send_allowance = rate_limiter
+                .as_mut()
+                .map_or(usize::MAX, |rate_limiter| rate_limiter.available(now));
+
+            // give way to the receiver thread when it falls behind, otherwise (especially when both share a core) we can
+            // send probes faster than their replies are received and the kernel starts dropping them
+            let receiver_caught_up = !listener_status.is_behind();
+
             // if we're not pushing any rate limits, we should do some sending
-            if rate_limit_unviolated {
+            if send_allowance > 0 && receiver_caught_up {
                 // Send packets
-                let addresses_sent_packets = self.send_packets(
-                    ipv4_tcp_sender,
-                    ipv6_tcp_sender,
-                    &mut requeued_addrs,
-                    source_ipv4_addr,
-                    source_ipv6_addr,
-                    listening_port,
-                    tcp_seq,
+                Self::send_packets(
+                    sender,
+                    &mut probes,
+                    &mut pending_probes,
+                    &mut sent_probes,
+                    network_scheduler.as_mut(),
+                    send_allowance,
+                    &mut probe_builder,
                 );
 
-                total_packets_sent += addresses_sent_packets.len() as u128;
+                total_packets_sent += sent_probes.len() as u128;
+                listener_status.record_probes_sent(sent_probes.len());
 
-                if !addresses_sent_packets.is_empty() {
-                    current_packets_sent_for_bucket += addresses_sent_packets.len();
+                if !sent_probes.is_empty() {
+                    if let Some(rate_limiter) = &mut rate_limiter {
+                        rate_limiter.consume(sent_probes.len());
+                    }
 
-                    inflight_addrs.extend(addresses_sent_packets.clone());
+                    if let Some(rate_controller) = &mut rate_controller {
+                        rate_controller.record_sent(sent_probes.len());
+                    }
 
                     // mark for expiration
-                    let expiration = Instant::now().checked_add(port_timeout.clone()).unwrap();
-                    expiry_list.push((expiration, addresses_sent_packets));
+                    let sent_at = Instant::now();
+                    sent_probes.iter().for_each(|index| {
+                        let expiration = sent_at + rtt_estimator.timeout(probes.remote(*index).ip());
+                        let expiry = expiry_wheel.insert(expiration, probes.mark_sent(*index, sent_at));
+                        probes.set_expiry(*index, expiry);
+                    });
                 }
-            } else {
-                std::thread::sleep(next_packet_bucket_expiry.duration_since(Instant::now()));
             }
 
-            // receive remotes that syn-ack'd
-            let received_remotes_v4 =
-                self.record_open_sockets_from_response(ipv4_tcp_receiver, listening_port);
-            let received_remotes_v6 =
-                self.record_open_sockets_from_response(ipv6_tcp_receiver, listening_port);
+            // with nothing to send right now, rather than spin we wait for a reply until there's something else to do
+            let reply_wait = match (pending_probes.is_empty(), send_allowance > 0) {
+                (false, true) if !receiver_caught_up => RECEIVER_BACKLOG_WAIT,
+                (false, true) => Duration::ZERO,
+                (no_pending_probes, _) => {
+                    // we're only held back by the rate limit if there's something left to send
+                    let next_token = rate_limiter
+                        .as_ref()
+                        .filter(|_| !no_pending_probes)
+                        .map(TokenBucket::next_available);
+
+                    let next_network_wake_up = network_scheduler.as_ref().and_then(NetworkScheduler::next_wake_up);
+
+                    [expiry_wheel.next_expiry(), next_token, next_network_wake_up]
+                        .into_iter()
+                        .flatten()
+                        .fold(next_stats_update, Instant::min)
+                        .saturating_duration_since(Instant::now())
+                }
+            };
+
+            // while the rate limit is pacing our sends, replies can wait for the next send rather than each waking us up
+            let reply_wait = match send_allowance == 0 && !pending_probes.is_empty() {
+                true => {
+                    std::thread::sleep(reply_wait);
+                    Duration::ZERO
+                }
+                false => reply_wait,
+            };
+
+            let first_reply = match replies.recv_timeout(reply_wait) {
+                Ok(reply) => Some(reply),
+                Err(RecvTimeoutError::Timeout) if !receiver_caught_up => {
+                    listener_status.catch_up_if_waiting();
+                    None
+                }
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => anyhow::bail!("The armada receiver thread has stopped."),
+            };
+
+            // replies are timed from their send until now, which is as close as we get to their arrival
+            let received_at = Instant::now();
 
             // save the remotes that were actually in-flight
-            received_remotes_v4
+            first_reply
                 .into_iter()
-                .chain(received_remotes_v6)
-                .filter(|remote_addr| inflight_addrs.remove(&remote_addr))
-                .for_each(|remote_addr| {
-                    // if a port was deemed open, we can update this statistic
+                .chain(replies.try_iter().take(BATCH_RECV_SIZE))
+                .filter_map(|(remote_addr, port_state)| Some((remote_addr, port_state, probes.answer(&remote_addr)?)))
+                .for_each(|(remote_addr, port_state, (retries, sent_at, expiry))| {
+                    expiry_wheel.remove(expiry);
+
+                    // the port answered so it's been processed, regardless of whether we report it
                     total_processed_ports += 1;
-                    packet_retry_tracker.remove(&remote_addr);
-                    open_ports.push(remote_addr);
+
+                    // a reply to a retried probe may answer any of its sends, so it can't be timed
+                    if retries == 0 {
+                        rtt_estimator.record(remote_addr.ip(), received_at.saturating_duration_since(sent_at));
+                    }
+
+                    if let Some(network_scheduler) = &mut network_scheduler {
+                        network_scheduler.release(remote_addr.ip(), now, &mut pending_probes);
+                    }
+
+                    if let Some(rate_controller) = &mut rate_controller {
+                        rate_controller.record_answer(retries);
+                    }
+
+                    if scan_type.reports(port_state) {
+                        port_results.push((remote_addr, port_state));
+                    }
                 });
 
-            if !open_ports.is_empty() {
+            if !port_results.is_empty() {
                 // send our stats update
                 reporting_channel.send(ArmadaWorkMessage::stats(
                     total_processed_ports,
-                    inflight_addrs.len() as u128,
-                    total_packets_sent
+                    probes.inflight_len() as u128,
+                    total_packets_sent,
+                    listener_status.dropped_packets() as u128,
+                    listener_status.malformed_packets() as u128,
+                    current_packets_per_second
                 )).context("Failed to send stats message to reporting channel.")?;
-                // we'll empty the open ports vec into our update here
+                // we'll empty the port results vec into our update here
                 reporting_channel.send(
-                    ArmadaWorkMessage::results(open_ports.drain(.. open_ports.len()).collect())
+                    ArmadaWorkMessage::results(std::mem::take(&mut port_results))
                 ).context("Failed to send results message to reporting channel.")?;
             }
 
-            self.process_expiration(&mut expiry_list)
-                .into_iter()
-                .filter(|expired_remote| inflight_addrs.remove(&expired_remote))
-                .filter(|expired_remote| {
-                    let retry_counter = packet_retry_tracker
-                        .entry(expired_remote.clone())
-                        .or_insert(0);
-
-                    if *retry_counter == port_retries {
-                        // this port has been deemed closed and therefore has been "processed"
-                        total_processed_ports += 1;
-                        packet_retry_tracker.remove(&expired_remote);
-                        false
-                    } else {
-                        *retry_counter += 1;
-                        true
+            expiry_wheel.expire(Instant::now(), &mut expired_probes);
+
+            for ticket in expired_probes.drain(..) {
+                // answered or resent since this expiry was scheduled
+                let (index, retries) = match probes.expire(ticket) {
+                    Some(expired) => expired,
+                    None => continue,
+                };
+
+                if let Some(rate_controller) = &mut rate_controller {
+                    rate_controller.record_timeout(retries);
+                }
+
+                if let Some(network_scheduler) = &mut network_scheduler {
+                    network_scheduler.release(probes.remote(index).ip(), now, &mut pending_probes);
+                }
+
+                if retries == port_retries {
+                    // this port never answered and therefore has been "processed"
+                    total_processed_ports += 1;
+
+                    // some scan types care about silence (e.g. open|filtered for FIN scans)
+                    let port_state = scan_type.timeout_state();
+
+                    if scan_type.reports(port_state) {
+                        port_results.push((probes.remote(index), port_state));
                     }
-                })
-                .for_each(|expired_remote| {
-                    requeued_addrs.push(expired_remote);
-                });
+
+                    probes.remove(index);
+                } else {
+                    probes.retry(index);
+                    pending_probes.push(index);
+                }
+            }
         }
 
         // send the final stats and results before closing up shop
         reporting_channel
-            .send(ArmadaWorkMessage::stats(total_processed_ports, inflight_addrs.len() as u128, total_packets_sent))
+            .send(ArmadaWorkMessage::stats(
+                total_processed_ports,
+                probes.inflight_len() as u128,
+                total_packets_sent,
+                listener_status.dropped_packets() as u128,
+                listener_status.malformed_packets() as u128,
+                current_packets_per_second
+            ))
             .context("Failed to send final stats message over reporting channel.")?;
 
         reporting_channel
-            .send(ArmadaWorkMessage::results(open_ports))
+            .send(ArmadaWorkMessage::results(port_results))
             .context("Failed to send final results message over reporting channel.")?;
 
         Ok(())
     }
 
-    /// Pulls socket addresses off the queued address list and sends them SYN TCP packets via IPv4 or IPv6
+    /// Takes up to `max_probes` probes off of the pending list, builds them shaped by the scan type, and hands the whole
+    /// batch to the transport at once. The probes that went out are left in `sent_probes`, while any that couldn't be
+    /// built or sent are skipped and no longer tracked. Probes the network scheduler won't admit yet are left with it.
     fn send_packets(
-        &self,
-        ipv4_tcp_sender: &mut TransportSender,
-        ipv6_tcp_sender: &mut TransportSender,
-        requeued_addrs: &mut Vec<SocketAddr>,
-        source_ipv4: Option<&Ipv4Addr>,
-        source_ipv6: Option<&Ipv6Addr>,
-        listening_port: u16,
-        tcp_seq: &mut u32,
-    ) -> Vec<SocketAddr> {
-        use crate::armada::packet::{create_syn_tcp_packet_v4, create_syn_tcp_packet_v6};
+        sender: &mut dyn ProbeSender,
+        probes: &mut ProbeSlab,
+        pending_probes: &mut Vec<u32>,
+        sent_probes: &mut Vec<u32>,
+        mut network_scheduler: Option<&mut NetworkScheduler<u32>>,
+        max_probes: usize,
+        probe_builder: &mut ProbeBuilder,
+    ) {
+        const UNSPECIFIED: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+        let mut probe_buffers = [[0u8; MAX_PROBE_PACKET_LEN]; BATCH_SEND_SIZE];
+        // (probe, source ip, probe length) for each probe built into the buffer of the same index
+        let mut batch = [(0u32, UNSPECIFIED, 0usize); BATCH_SEND_SIZE];
+        let mut batch_len = 0;
+        let protocol = probe_builder.scan_type.transport_protocol();
+        let now = Instant::now();
 
-        let mut sent_addrs = Vec::with_capacity(BATCH_SEND_SIZE);
-        let mut syn_tcp_buffer = [0; 32];
+        sent_probes.clear();
 
-        for _ in 0 .. BATCH_SEND_SIZE {
-            let remote = match requeued_addrs.pop() {
-                Some(remote) => remote,
+        while batch_len < BATCH_SEND_SIZE.min(max_probes) {
+            let index = match pending_probes.pop() {
+                Some(index) => index,
                 None => break,
             };
 
-            let remote_port = remote.port();
+            let remote = probes.remote(index);
+            let probe = probe_builder.build(remote, &mut probe_buffers[batch_len]);
 
-            let (tcp_sender, packet) = match (&remote.ip(), source_ipv4, source_ipv6) {
-                (IpAddr::V4(remote_ipv4), Some(source_ipv4_addr), _) => {
-                    let packet = create_syn_tcp_packet_v4(
-                        source_ipv4_addr,
-                        remote_ipv4,
-                        listening_port,
-                        remote_port,
-                        &mut syn_tcp_buffer,
-                        tcp_seq,
-                    );
+            match probe {
+                Some((source_ip, probe_len)) => {
+                    if let Some(network_scheduler) = network_scheduler.as_deref_mut() {
+                        if !network_scheduler.admit(index, remote.ip(), now) {
+                            continue;
+                        }
+                    }
 
-                    (&mut *ipv4_tcp_sender, packet)
-                }
-                (IpAddr::V6(remote_ipv6), _, Some(source_ipv6_addr)) => {
-                    let packet = create_syn_tcp_packet_v6(
-                        source_ipv6_addr,
-                        remote_ipv6,
-                        listening_port,
-                        remote_port,
-                        &mut syn_tcp_buffer,
-                        tcp_seq,
-                    );
-
-                    (&mut *ipv6_tcp_sender, packet)
-                }
-                (IpAddr::V4(_), None, _) => {
-                    error!("Attempted to port scan an IPv4 address without any provided IPv4 source addresses. Port will be skipped.");
-                    continue;
+                    batch[batch_len] = (index, source_ip, probe_len);
+                    batch_len += 1;
                 }
-                (IpAddr::V6(_), _, None) => {
-                    error!("Attempted to port scan an IPv6 address without any provided IPv6 source addresses. Port will be skipped.");
-                    continue;
-                }
-            };
+                // the builder already said why
+                None => probes.remove(index),
+            }
+        }
 
-            let packet = match packet {
-                Some(packet) => packet,
-                None => {
-                    warn!(
-                        "Unable to create SYN packet for {}. Port will be skipped.",
-                        remote
-                    );
-                    continue;
-                }
-            };
+        let mut segments: [(&[u8], IpAddr, SocketAddr); BATCH_SEND_SIZE] =
+            [(&[], UNSPECIFIED, SocketAddr::new(UNSPECIFIED, 0)); BATCH_SEND_SIZE];
 
-            match tcp_sender.try_send_to(packet, remote.ip()) {
-                Ok(Some(_)) => sent_addrs.push(remote),
-                _ => {
-                    //eprintln!("ERR: {:?}", e);
-                    requeued_addrs.push(remote);
-                    break;
-                }
-            }
+        for ((segment, (index, source_ip, probe_len)), probe_buffer) in
+            segments.iter_mut().zip(&batch[.. batch_len]).zip(probe_buffers.iter())
+        {
+            *segment = (&probe_buffer[.. *probe_len], *source_ip, probes.remote(*index));
         }
 
-        sent_addrs
-    }
+        let (sent_count, skipped_count) = match sender.try_send_batch(&segments[.. batch_len], protocol) {
+            Ok(sent_count) => (sent_count, 0),
+            Err(e) => {
+                // only the first probe failed, so skip it and give the rest another go next time
+                warn!(err = ?e, "Unable to send probe packet to {}. Port will be skipped.", probes.remote(batch[0].0));
+                probes.remove(batch[0].0);
+                (0, 1)
+            }
+        };
 
-    /// Receives some number of responses from the socket and determines which sockets indicate an open status
-    fn record_open_sockets_from_response(
-        &self,
-        tcp_receiver: &mut TransportReceiver,
-        listening_port: u16,
-    ) -> Vec<SocketAddr> {
-        use pnet::packet::tcp::TcpFlags::{NS, CWR, ECE, URG, ACK, RST, SYN, PSH, FIN};
-
-        let mut results = Vec::with_capacity(BATCH_RECV_SIZE);
-
-        while let Ok(Some((packet, remote))) = tcp_receiver.try_next() {
-            let flag = packet.get_flags();
-
-            let seq = packet.get_sequence();
-            let ns_flag = flag & NS != 0;
-            let cwr_flag = flag & CWR != 0;
-            let ece_flag = flag & ECE != 0;
-            let urg_flag = flag & URG != 0;
-            let ack_flag = flag & ACK != 0;
-            let psh_flag = flag & PSH != 0;
-            let rst_flag = flag & RST != 0;
-            let syn_flag = flag & SYN != 0;
-            let fin_flag = flag & FIN != 0;
-
-            if remote == IpAddr::from([103,97,3,19]) {
-                println!("{:#?}:", packet.get_source());
-                println!("\tRemote: {remote:#?}");
-                println!("\tSEQ: {seq}");
-                println!("\tNS: {ns_flag}");
-                println!("\tCWR: {cwr_flag}");
-                println!("\tECE: {ece_flag}");
-                println!("\tURG: {urg_flag}");
-                println!("\tACK: {ack_flag}");
-                println!("\tPSH: {psh_flag}");
-                println!("\tRST: {rst_flag}");
-                println!("\tSYN: {syn_flag}");
-                println!("\tFIN: {fin_flag}");
+        sent_probes.extend(batch[.. sent_count].iter().map(|(index, _, _)| *index));
+
+        // whatever didn't go out is admitted anew when it's next taken out
+        if let Some(network_scheduler) = network_scheduler {
+            for (index, _, _) in &batch[sent_count .. batch_len] {
+                network_scheduler.release(probes.remote(*index).ip(), now, pending_probes);
             }
+        }
 
-            // todo: if reset packet back, return "definitely closed"
+        // the transport is backed up, so put the unsent probes back in the order we took them out
+        pending_probes.extend(batch[sent_count + skipped_count .. batch_len].iter().rev().map(|(index, _, _)| *index));
+    }
+}
 
-            if packet.get_destination() == listening_port && !rst_flag && ack_flag {
-                results.push(SocketAddr::new(remote, packet.get_source()));
+/// Builds the probes of a scan, each sent from the address picked for its remote and a port out of the pool
+struct ProbeBuilder<'a> {
+    scan_type: ScanType,
+    tcp_template: TcpProbeTemplate,
+    source_selector: SourceSelector,
+    source_ports: SourcePorts,
+    // carries on from one scan to the next
+    tcp_seq: &'a mut u32,
+    rng: ThreadRng,
+}
 
-                // if we've reached the capacity for this vec, we've processed enough and can return
-                if results.len() == results.capacity() {
-                    break;
-                }
+impl ProbeBuilder<'_> {
+    /// Builds the probe for `remote` into `buffer`, returning the address it's to be sent from and its length. Probes
+    /// that can't be built are logged and None is returned.
+    fn build(&mut self, remote: SocketAddr, buffer: &mut [u8]) -> Option<(IpAddr, usize)> {
+        let remote_port = remote.port();
+        // each probe leaves from a different port, so none of them can be singled out by it
+        let source_port = self.source_ports.pick(&mut self.rng);
+
+        // build the probe for the remote's address family
+        let probe = match (&remote.ip(), self.source_selector.source_for(remote)) {
+            (IpAddr::V4(remote_ipv4), Some(IpAddr::V4(source_ipv4_addr))) => match self.scan_type {
+                ScanType::SctpInit => create_sctp_init_packet(
+                    source_port,
+                    remote_port,
+                    buffer,
+                    self.tcp_seq,
+                ).map(|packet| packet.packet().len()),
+                _ => self.tcp_template.write_v4(
+                    &source_ipv4_addr,
+                    source_port,
+                    remote_ipv4,
+                    remote_port,
+                    buffer,
+                    self.tcp_seq,
+                ),
+            }.map(|probe_len| (IpAddr::V4(source_ipv4_addr), probe_len)),
+            (IpAddr::V6(remote_ipv6), Some(IpAddr::V6(source_ipv6_addr))) => match self.scan_type {
+                ScanType::SctpInit => create_sctp_init_packet(
+                    source_port,
+                    remote_port,
+                    buffer,
+                    self.tcp_seq,
+                ).map(|packet| packet.packet().len()),
+                _ => self.tcp_template.write_v6(
+                    &source_ipv6_addr,
+                    source_port,
+                    remote_ipv6,
+                    remote_port,
+                    buffer,
+                    self.tcp_seq,
+                ),
+            }.map(|probe_len| (IpAddr::V6(source_ipv6_addr), probe_len)),
+            (IpAddr::V4(_), _) => {
+                error!("Attempted to port scan {} without an IPv4 source address to scan it from. Port will be skipped.", remote);
+                return None;
+            }
+            (IpAddr::V6(_), _) => {
+                error!("Attempted to port scan {} without an IPv6 source address to scan it from. Port will be skipped.", remote);
+                return None;
             }
+        };
+
+        if probe.is_none() {
+            warn!("Unable to create probe packet for {}. Port will be skipped.", remote);
         }
 
-        results
+        probe
     }
+}
 
-    /// Process all currently expired packets
-    fn process_expiration(
-        &self,
-        expiry_list: &mut Vec<(Instant, Vec<SocketAddr>)>,
-    ) -> Vec<SocketAddr> {
-        // assume send size for efficient writing
-        let mut all_expired_remotes = Vec::with_capacity(BATCH_SEND_SIZE);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::armada::config::host::HostIterator;
+    use crate::armada::config::port::PortIterator;
+    use crate::armada::config::scan_options::ScanOptions;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::sync::mpsc::SyncSender;
+
+    // counts the allocations made by each thread, so tests running alongside don't get in the way
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+    }
 
-        let now = Instant::now();
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+            System.alloc(layout)
+        }
 
-        loop {
-            match expiry_list.first() {
-                Some((expiry, _)) if expiry.le(&now) => {
-                    let (_, expired_remotes) = expiry_list.pop().expect("This should not be possible as we just confirmed an item exists.");
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { System.dealloc(ptr, layout) }
 
-                    all_expired_remotes.extend(expired_remotes);
-                }
-                _ => break,
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    // answers the probes `answered` picks as soon as they're sent, as if those ports were closed and right next door,
+    // while keeping track of the allocations made from the first send after the warm-up to the last send
+    struct CountingSender {
+        replies: SyncSender<(SocketAddr, PortState)>,
+        answered: fn(SocketAddr) -> bool,
+        warm_up_sends: u64,
+        sends: u64,
+        allocations_after_warm_up: Option<u64>,
+        allocations_at_last_send: u64,
+    }
+
+    impl ProbeSender for CountingSender {
+        fn try_send_to(
+            &mut self,
+            segment: &[u8],
+            _: pnet::packet::ip::IpNextHeaderProtocol,
+            _: IpAddr,
+            destination: SocketAddr,
+        ) -> std::io::Result<Option<usize>> {
+            let allocations = ALLOCATIONS.with(Cell::get);
+
+            self.sends += 1;
+            self.allocations_at_last_send = allocations;
+
+            if self.sends == self.warm_up_sends + 1 {
+                self.allocations_after_warm_up = Some(allocations);
+            }
+
+            if (self.answered)(destination) {
+                // the worker drains more replies per turn than it sends probes, so this never fills up
+                self.replies.try_send((destination, PortState::Closed)).unwrap();
             }
+
+            Ok(Some(segment.len()))
         }
+    }
 
-        all_expired_remotes
+    // the allocations the worker's thread made between the first send after `warm_up_sends` and the last send of a SYN
+    // scan of port 80 across `target`
+    fn allocations_after_warm_up(
+        target: &str,
+        options: ScanOptions,
+        answered: fn(SocketAddr) -> bool,
+        warm_up_sends: u64,
+    ) -> u64 {
+        let (_work_sender, work_queue) = tokio::sync::mpsc::unbounded_channel();
+        let worker = ArmadaWorker::new(work_queue);
+
+        // closed ports and silence are both left out of a SYN scan's results, so all that's reported are stats updates,
+        // once a second, which fit in the block the channel starts out with
+        let (reporting_channel, _reports) = tokio::sync::mpsc::unbounded_channel();
+        let work = ArmadaWork::new(
+            HostIterator::new().add_target(target).unwrap(),
+            PortIterator::new().add_port(80),
+            options.with_source_ipv6_addrs(vec!["2001:db8:ffff::1".parse().unwrap()]),
+            reporting_channel,
+        );
+
+        let (reply_sender, replies) = std::sync::mpsc::sync_channel(REPLY_QUEUE_CAPACITY);
+        let mut sender = CountingSender {
+            replies: reply_sender,
+            answered,
+            warm_up_sends,
+            sends: 0,
+            allocations_after_warm_up: None,
+            allocations_at_last_send: 0,
+        };
+        // a listener that's never behind, there being none
+        let listener_status = ListenerStatus::new(u64::MAX);
+        let mut tcp_seq = 0;
+
+        // the first wait on a channel sets up what it needs to wake this thread, once for the thread's and the
+        // channel's life, and a worker's thread and reply channel last for all of its scans
+        let _ = replies.recv_timeout(Duration::from_millis(1));
+
+        worker
+            .process_work(work, &mut sender, &replies, &listener_status, SourcePorts::single(50000), &mut tcp_seq)
+            .unwrap();
+
+        let allocations_after_warm_up = sender.allocations_after_warm_up.expect("the scan never got past its warm-up");
+
+        sender.allocations_at_last_send - allocations_after_warm_up
+    }
+
+    // every target is in the same network, so there's only the one round trip time to keep track of. Probes are only
+    // allocated for as they first go in flight, so once as many are in flight as ever will be at once, nothing more is
+    #[test]
+    fn answered_probes_make_no_allocations() {
+        // the probe slab holds up to 16384 probes, after which the in-flight probes and their expiries don't grow
+        let allocations = allocations_after_warm_up("2001:db8::/112", ScanOptions::new(), |_| true, 16384);
+
+        assert_eq!(allocations, 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn expired_and_retried_probes_make_no_allocations() {
+        // every probe times out, twice over, so the scan is all expiring, retrying and rescheduling probes after the
+        // first round of sends has put every one of them in flight
+        let options = ScanOptions::new().with_timeout(Duration::from_millis(1)).with_retries(2);
+        let allocations = allocations_after_warm_up("2001:db8::/116", options, |_| false, 4096);
+
+        assert_eq!(allocations, 0);
+    }
+
+    #[test]
+    fn partly_answered_probes_make_no_allocations() {
+        // half the ports answer, the other half time out and are sent again
+        let options = ScanOptions::new().with_timeout(Duration::from_millis(1)).with_retries(1);
+        let answered = |remote: SocketAddr| match remote.ip() {
+            IpAddr::V6(ip) => ip.segments()[7] % 2 == 0,
+            IpAddr::V4(_) => unreachable!(),
+        };
+        let allocations = allocations_after_warm_up("2001:db8::/116", options, answered, 4096);
+
+        assert_eq!(allocations, 0);
+    }
+}
@@ -2,6 +2,10 @@ extern crate cidr_utils;
 mod armada;
 pub mod utils;
 
-pub use crate::armada::config::{host::HostIterator, port::PortIterator};
-pub use crate::armada::work::ArmadaWorkMessage;
+pub use crate::armada::config::{
+    host::HostIterator, ip_header::IpHeader, network_limits::NetworkLimits, port::PortIterator, rate_limit::RateLimit,
+    scan_options::ScanOptions, scan_type::ScanType, source_ports::SourcePorts, syn_profile::{SynOption, SynProfile},
+};
+pub use crate::armada::socks5::Socks5Proxy;
+pub use crate::armada::work::{ArmadaWorkMessage, PortState};
 pub use crate::armada::Armada;
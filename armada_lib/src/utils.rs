@@ -1,41 +1,131 @@
+use pnet::datalink::NetworkInterface;
+use pnet::ipnetwork::IpNetwork;
+use pnet::util::MacAddr;
 use std::net::IpAddr;
 
+/// An interface holding a default route, as the kernel's routing tables describe it
+#[derive(Clone, Debug)]
+pub struct DefaultRouteInterface {
+    pub name: String,
+    pub index: u32,
+    pub mac: Option<MacAddr>,
+    /// Where the default route leads. Routes over point-to-point links (e.g. VPN tunnels) don't name a gateway.
+    pub gateway: Option<IpAddr>,
+    /// Every address of the interface, of both families
+    pub addresses: Vec<IpNetwork>,
+}
+
 /// Attempts to figure out what IP addresses you *probably* want to set as "source" ips for armada
-/// It will check for the default route to the internet and grab the IPs configured for that interface.
+/// It will check for the default routes to the internet and grab the IPs configured for their interfaces, IPv4 ones from
+/// the IPv4 default route's and IPv6 ones (link-local aside) from the IPv6 default route's.
 pub async fn get_default_ips() -> Option<Vec<IpAddr>> {
-    get_interface_holding_default_route().await.map(|interface_name| {
-        pnet::datalink::interfaces().into_iter()
-            .find(|interface| interface.name.eq(&interface_name))
-            .map(|interface| {
-                interface.ips.into_iter()
-                    .map(|networks| networks.ip())
-                    .collect::<Vec<_>>()
-            })
-    }).flatten()
-}
-
-/// Fetches the interface that is cited in the default route
-/// It's embarrassing for Rust that we don't have an easier to use library for this
-async fn get_interface_holding_default_route() -> Option<String> {
-    use regex::Regex;
-    use tokio::process::Command;
-
-    let ip_output = Command::new("ip")
-        .arg("route")
-        .arg("show")
-        .arg("default")
-        .arg("0.0.0.0/0")
-        .output()
-        .await
-        .ok()
-        .map(|stdout_bytes| String::from_utf8(stdout_bytes.stdout).ok())
-        .flatten()?;
-
-    // parse out device name from `... dev eth0 ...`
-    let interface_regex = Regex::new(r"\bdev ([^\s]+)").ok()?;
-
-    interface_regex.captures(&ip_output)
-        .map(|captures| captures.get(1))
-        .flatten()
-        .map(|interface_name| interface_name.as_str().to_string())
-}
\ No newline at end of file
+    let ipv4_interface = get_default_ipv4_interface().ok().flatten();
+    let ipv6_interface = get_default_ipv6_interface().ok().flatten();
+
+    let ipv4_addrs = ipv4_interface.iter()
+        .flat_map(|interface| interface.addresses.iter())
+        .map(|network| network.ip())
+        .filter(IpAddr::is_ipv4);
+    let ipv6_addrs = ipv6_interface.iter()
+        .flat_map(|interface| interface.addresses.iter())
+        .map(|network| network.ip())
+        .filter(|ip| ip.is_ipv6() && !is_ipv6_link_local(ip));
+
+    let source_ips: Vec<_> = ipv4_addrs.chain(ipv6_addrs).collect();
+
+    match source_ips.is_empty() {
+        true => None,
+        false => Some(source_ips),
+    }
+}
+
+/// Fetches every network interface pnet can see
+pub fn get_interfaces() -> Vec<NetworkInterface> { pnet::datalink::interfaces() }
+
+/// Fetches the IP addresses of the named interface to use as "source" ips, i.e. all but its IPv6 link-local ones
+pub fn get_interface_ips(interface_name: &str) -> anyhow::Result<Vec<IpAddr>> {
+    let interface = get_interfaces()
+        .into_iter()
+        .find(|interface| interface.name == interface_name)
+        .ok_or_else(|| anyhow::anyhow!("Unable to find interface '{}'.", interface_name))?;
+
+    Ok(interface.ips.iter()
+        .map(|network| network.ip())
+        .filter(|ip| !is_ipv6_link_local(ip))
+        .collect())
+}
+
+/// Fetches the MTU of the interface with the given index
+#[cfg(target_os = "linux")]
+pub fn get_interface_mtu(interface_index: u32) -> Option<u32> {
+    crate::armada::netlink::interfaces().ok()?
+        .into_iter()
+        .find(|interface| interface.index == interface_index)
+        .and_then(|interface| interface.mtu)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get_interface_mtu(_interface_index: u32) -> Option<u32> { None }
+
+/// Fetches the interface holding the IPv4 default route, if there is one
+pub fn get_default_ipv4_interface() -> anyhow::Result<Option<DefaultRouteInterface>> {
+    get_default_route_interface(false)
+}
+
+/// Fetches the interface holding the IPv6 default route, if there is one
+pub fn get_default_ipv6_interface() -> anyhow::Result<Option<DefaultRouteInterface>> {
+    get_default_route_interface(true)
+}
+
+/// Reads the default route of one address family out of the main routing table over netlink, along with the interface
+/// it goes out of. Of several default routes, the one with the lowest metric wins, as it does for the kernel.
+#[cfg(target_os = "linux")]
+fn get_default_route_interface(ipv6: bool) -> anyhow::Result<Option<DefaultRouteInterface>> {
+    use crate::armada::netlink;
+    use anyhow::Context;
+
+    // from linux/rtnetlink.h
+    const RT_TABLE_MAIN: u32 = 254;
+    const RTN_UNICAST: u8 = 1;
+
+    let default_route = netlink::routes()
+        .context("Unable to read the routing table.")?
+        .into_iter()
+        .filter(|route| route.destination.is_ipv6() == ipv6 && route.prefix_len == 0)
+        .filter(|route| route.table == RT_TABLE_MAIN && route.route_type == RTN_UNICAST)
+        .filter_map(|route| Some((route.interface_index?, route)))
+        .min_by_key(|(_, route)| route.priority);
+
+    let (interface_index, default_route) = match default_route {
+        Some(default_route) => default_route,
+        None => return Ok(None),
+    };
+
+    let interface = netlink::interfaces()
+        .context("Unable to read the network interfaces.")?
+        .into_iter()
+        .find(|interface| interface.index == interface_index)
+        .with_context(|| format!("The default route's interface (index {}) doesn't exist.", interface_index))?;
+
+    let addresses = netlink::addresses()
+        .context("Unable to read the interface addresses.")?
+        .into_iter()
+        .filter(|address| address.interface_index == interface_index)
+        .filter_map(|address| IpNetwork::new(address.address, address.prefix_len).ok())
+        .collect();
+
+    Ok(Some(DefaultRouteInterface {
+        name: interface.name,
+        index: interface.index,
+        mac: interface.mac.map(|[a, b, c, d, e, f]| MacAddr::new(a, b, c, d, e, f)),
+        gateway: default_route.gateway,
+        addresses,
+    }))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn get_default_route_interface(_ipv6: bool) -> anyhow::Result<Option<DefaultRouteInterface>> {
+    anyhow::bail!("Finding the default route is only supported on Linux.")
+}
+
+pub(crate) fn is_ipv6_link_local(ip: &IpAddr) -> bool { matches!(ip, IpAddr::V6(ipv6) if ipv6.segments()[0] & 0xffc0 == 0xfe80) }